@@ -0,0 +1,4 @@
+CREATE TABLE IF NOT EXISTS clients (
+    id UUID PRIMARY KEY DEFAULT gen_random_uuid(),
+    private_key_path TEXT NOT NULL
+);
@@ -0,0 +1,13 @@
+CREATE TABLE IF NOT EXISTS products (
+    id UUID PRIMARY KEY DEFAULT gen_random_uuid(),
+    client_id UUID NOT NULL REFERENCES clients (id) ON DELETE CASCADE,
+    product_name VARCHAR(255) NOT NULL,
+    total_quantity INT4 NOT NULL DEFAULT 0,
+    total_shipper_boxes INT4 NOT NULL DEFAULT 0,
+    created_at TIMESTAMPTZ NOT NULL DEFAULT NOW(),
+    updated_at TIMESTAMPTZ NOT NULL DEFAULT NOW()
+);
+
+CREATE INDEX IF NOT EXISTS products_client_id_idx ON products (client_id);
+
+SELECT diesel_manage_updated_at('products');
@@ -0,0 +1,18 @@
+CREATE TABLE IF NOT EXISTS batch_details (
+    id UUID PRIMARY KEY DEFAULT gen_random_uuid(),
+    product_id UUID NOT NULL REFERENCES products (id) ON DELETE CASCADE,
+    batch_no VARCHAR(50) NOT NULL,
+    mfg_date DATE NOT NULL,
+    exp_date DATE NOT NULL,
+    boxes INT4 NOT NULL,
+    units_per_box INT4 NOT NULL,
+    units_per_pack INT4 NOT NULL,
+    packs_per_box INT4 NOT NULL,
+    packages_configuration VARCHAR(255) NOT NULL,
+    total_packs INT4 NOT NULL,
+    created_at TIMESTAMPTZ NOT NULL DEFAULT NOW(),
+    updated_at TIMESTAMPTZ NOT NULL DEFAULT NOW(),
+    UNIQUE (product_id, batch_no)
+);
+
+SELECT diesel_manage_updated_at('batch_details');
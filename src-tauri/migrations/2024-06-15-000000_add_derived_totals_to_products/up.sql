@@ -0,0 +1,2 @@
+ALTER TABLE products
+    ADD COLUMN derived_totals BOOLEAN NOT NULL DEFAULT FALSE;
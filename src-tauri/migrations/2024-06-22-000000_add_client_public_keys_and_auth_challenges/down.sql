@@ -0,0 +1,3 @@
+DROP TABLE IF EXISTS auth_challenges;
+
+ALTER TABLE clients DROP COLUMN public_key;
@@ -0,0 +1,12 @@
+ALTER TABLE clients ADD COLUMN public_key TEXT;
+
+CREATE TABLE IF NOT EXISTS auth_challenges (
+    id UUID PRIMARY KEY DEFAULT gen_random_uuid(),
+    client_id UUID NOT NULL REFERENCES clients (id) ON DELETE CASCADE,
+    nonce VARCHAR(64) NOT NULL UNIQUE,
+    created_at TIMESTAMPTZ NOT NULL DEFAULT NOW(),
+    expires_at TIMESTAMPTZ NOT NULL,
+    used_at TIMESTAMPTZ
+);
+
+CREATE INDEX IF NOT EXISTS auth_challenges_client_id_idx ON auth_challenges (client_id);
@@ -0,0 +1,3 @@
+ALTER TABLE clients
+    DROP COLUMN created_at,
+    DROP COLUMN name;
@@ -0,0 +1,3 @@
+ALTER TABLE clients
+    ADD COLUMN name VARCHAR(100) NOT NULL DEFAULT 'Unnamed client',
+    ADD COLUMN created_at TIMESTAMPTZ NOT NULL DEFAULT NOW();
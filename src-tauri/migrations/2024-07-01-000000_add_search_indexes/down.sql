@@ -0,0 +1,5 @@
+DROP INDEX IF EXISTS batch_details_packages_configuration_trgm_idx;
+DROP INDEX IF EXISTS batch_details_batch_no_trgm_idx;
+DROP INDEX IF EXISTS products_product_name_trgm_idx;
+
+DROP EXTENSION IF EXISTS pg_trgm;
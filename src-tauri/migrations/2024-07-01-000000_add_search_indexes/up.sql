@@ -0,0 +1,8 @@
+CREATE EXTENSION IF NOT EXISTS pg_trgm;
+
+CREATE INDEX IF NOT EXISTS products_product_name_trgm_idx
+    ON products USING GIN (product_name gin_trgm_ops);
+CREATE INDEX IF NOT EXISTS batch_details_batch_no_trgm_idx
+    ON batch_details USING GIN (batch_no gin_trgm_ops);
+CREATE INDEX IF NOT EXISTS batch_details_packages_configuration_trgm_idx
+    ON batch_details USING GIN (packages_configuration gin_trgm_ops);
@@ -0,0 +1,7 @@
+CREATE TABLE IF NOT EXISTS expiry_alerts (
+    batch_id UUID NOT NULL REFERENCES batch_details (id) ON DELETE CASCADE,
+    threshold_days INTEGER NOT NULL,
+    exp_date DATE NOT NULL,
+    alerted_at TIMESTAMPTZ NOT NULL DEFAULT NOW(),
+    PRIMARY KEY (batch_id, threshold_days, exp_date)
+);
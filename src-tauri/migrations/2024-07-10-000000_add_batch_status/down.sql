@@ -0,0 +1,7 @@
+DROP TABLE IF EXISTS batch_status_history;
+
+DROP INDEX IF EXISTS batch_details_status_idx;
+
+ALTER TABLE batch_details
+    DROP CONSTRAINT batch_details_status_check,
+    DROP COLUMN status;
@@ -0,0 +1,18 @@
+ALTER TABLE batch_details
+    ADD COLUMN status VARCHAR(20) NOT NULL DEFAULT 'released',
+    ADD CONSTRAINT batch_details_status_check
+        CHECK (status IN ('quarantine', 'released', 'on_hold', 'recalled', 'expired'));
+
+CREATE INDEX IF NOT EXISTS batch_details_status_idx ON batch_details (status);
+
+CREATE TABLE IF NOT EXISTS batch_status_history (
+    id UUID PRIMARY KEY DEFAULT gen_random_uuid(),
+    batch_id UUID NOT NULL REFERENCES batch_details (id) ON DELETE CASCADE,
+    from_status VARCHAR(20) NOT NULL,
+    to_status VARCHAR(20) NOT NULL,
+    reason TEXT NOT NULL,
+    changed_by UUID NOT NULL REFERENCES clients (id) ON DELETE CASCADE,
+    changed_at TIMESTAMPTZ NOT NULL DEFAULT NOW()
+);
+
+CREATE INDEX IF NOT EXISTS batch_status_history_batch_id_idx ON batch_status_history (batch_id);
@@ -0,0 +1,43 @@
+CREATE TABLE IF NOT EXISTS stock_movements (
+    id UUID PRIMARY KEY DEFAULT gen_random_uuid(),
+    batch_id UUID NOT NULL REFERENCES batch_details (id) ON DELETE CASCADE,
+    movement_type VARCHAR(20) NOT NULL,
+    quantity INTEGER NOT NULL,
+    unit VARCHAR(10) NOT NULL,
+    units_delta BIGINT NOT NULL,
+    reference VARCHAR(100),
+    created_by UUID NOT NULL REFERENCES clients (id) ON DELETE CASCADE,
+    created_at TIMESTAMPTZ NOT NULL DEFAULT NOW(),
+    CONSTRAINT stock_movements_unit_check CHECK (unit IN ('boxes', 'packs', 'units')),
+    CONSTRAINT stock_movements_direction_check CHECK (
+        (movement_type = 'receipt' AND units_delta > 0)
+        OR (movement_type IN ('dispatch', 'write_off') AND units_delta < 0)
+        OR (movement_type = 'adjustment' AND units_delta <> 0)
+    )
+);
+
+CREATE INDEX IF NOT EXISTS stock_movements_batch_id_idx ON stock_movements (batch_id);
+
+-- Movements are never edited or removed; corrections are posted as new
+-- movements. Deletes cascading from a removed batch run inside the foreign
+-- key's own trigger and are let through.
+CREATE OR REPLACE FUNCTION stock_movements_append_only() RETURNS TRIGGER AS $$
+BEGIN
+    IF TG_OP = 'DELETE' AND pg_trigger_depth() > 1 THEN
+        RETURN OLD;
+    END IF;
+    RAISE EXCEPTION 'stock movements are append-only';
+END;
+$$ LANGUAGE plpgsql;
+
+CREATE TRIGGER stock_movements_append_only
+    BEFORE UPDATE OR DELETE ON stock_movements
+    FOR EACH ROW EXECUTE FUNCTION stock_movements_append_only();
+
+-- Existing batches open the ledger with what they currently hold.
+INSERT INTO stock_movements (batch_id, movement_type, quantity, unit, units_delta, reference, created_by)
+SELECT b.id, 'receipt', b.total_packs, 'packs', b.total_packs::BIGINT * b.units_per_pack,
+       'Opening balance', p.client_id
+FROM batch_details b
+JOIN products p ON p.id = b.product_id
+WHERE b.total_packs > 0 AND b.units_per_pack > 0;
@@ -0,0 +1,38 @@
+CREATE TABLE IF NOT EXISTS goods_receipts (
+    id UUID PRIMARY KEY DEFAULT gen_random_uuid(),
+    client_id UUID NOT NULL REFERENCES clients (id) ON DELETE CASCADE,
+    supplier VARCHAR(255) NOT NULL,
+    delivery_note VARCHAR(100) NOT NULL,
+    received_date DATE NOT NULL,
+    status VARCHAR(20) NOT NULL DEFAULT 'draft',
+    created_at TIMESTAMPTZ NOT NULL DEFAULT NOW(),
+    updated_at TIMESTAMPTZ NOT NULL DEFAULT NOW(),
+    confirmed_at TIMESTAMPTZ,
+    cancelled_at TIMESTAMPTZ,
+    CONSTRAINT goods_receipts_client_id_supplier_delivery_note_key
+        UNIQUE (client_id, supplier, delivery_note),
+    CONSTRAINT goods_receipts_status_check CHECK (status IN ('draft', 'confirmed', 'cancelled'))
+);
+
+CREATE INDEX IF NOT EXISTS goods_receipts_client_id_idx ON goods_receipts (client_id);
+
+CREATE TABLE IF NOT EXISTS goods_receipt_lines (
+    id UUID PRIMARY KEY DEFAULT gen_random_uuid(),
+    receipt_id UUID NOT NULL REFERENCES goods_receipts (id) ON DELETE CASCADE,
+    line_no INTEGER NOT NULL,
+    product_id UUID NOT NULL REFERENCES products (id) ON DELETE CASCADE,
+    batch_no VARCHAR(50) NOT NULL,
+    mfg_date DATE NOT NULL,
+    exp_date DATE NOT NULL,
+    boxes INTEGER NOT NULL,
+    units_per_box INTEGER NOT NULL,
+    units_per_pack INTEGER NOT NULL,
+    packs_per_box INTEGER NOT NULL,
+    packages_configuration VARCHAR(255) NOT NULL,
+    total_packs INTEGER NOT NULL,
+    -- The batch this line created or topped up, set on confirmation.
+    batch_id UUID REFERENCES batch_details (id) ON DELETE SET NULL,
+    CONSTRAINT goods_receipt_lines_receipt_id_line_no_key UNIQUE (receipt_id, line_no)
+);
+
+CREATE INDEX IF NOT EXISTS goods_receipt_lines_batch_id_idx ON goods_receipt_lines (batch_id);
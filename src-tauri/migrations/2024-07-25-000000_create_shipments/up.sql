@@ -0,0 +1,31 @@
+CREATE TABLE IF NOT EXISTS shipments (
+    id UUID PRIMARY KEY DEFAULT gen_random_uuid(),
+    client_id UUID NOT NULL REFERENCES clients (id) ON DELETE CASCADE,
+    consignee VARCHAR(255) NOT NULL,
+    ship_date DATE NOT NULL,
+    carrier VARCHAR(100),
+    reference VARCHAR(100) NOT NULL,
+    status VARCHAR(20) NOT NULL DEFAULT 'draft',
+    created_at TIMESTAMPTZ NOT NULL DEFAULT NOW(),
+    updated_at TIMESTAMPTZ NOT NULL DEFAULT NOW(),
+    dispatched_at TIMESTAMPTZ,
+    cancelled_at TIMESTAMPTZ,
+    CONSTRAINT shipments_client_id_reference_key UNIQUE (client_id, reference),
+    CONSTRAINT shipments_status_check CHECK (status IN ('draft', 'dispatched', 'cancelled'))
+);
+
+CREATE INDEX IF NOT EXISTS shipments_client_id_idx ON shipments (client_id);
+
+CREATE TABLE IF NOT EXISTS shipment_lines (
+    id UUID PRIMARY KEY DEFAULT gen_random_uuid(),
+    shipment_id UUID NOT NULL REFERENCES shipments (id) ON DELETE CASCADE,
+    line_no INTEGER NOT NULL,
+    batch_id UUID NOT NULL REFERENCES batch_details (id) ON DELETE CASCADE,
+    quantity INTEGER NOT NULL,
+    unit VARCHAR(10) NOT NULL,
+    CONSTRAINT shipment_lines_shipment_id_line_no_key UNIQUE (shipment_id, line_no),
+    CONSTRAINT shipment_lines_quantity_check CHECK (quantity > 0),
+    CONSTRAINT shipment_lines_unit_check CHECK (unit IN ('boxes', 'packs', 'units'))
+);
+
+CREATE INDEX IF NOT EXISTS shipment_lines_batch_id_idx ON shipment_lines (batch_id);
@@ -0,0 +1,51 @@
+CREATE TABLE IF NOT EXISTS locations (
+    id UUID PRIMARY KEY DEFAULT gen_random_uuid(),
+    client_id UUID NOT NULL REFERENCES clients (id) ON DELETE CASCADE,
+    parent_id UUID REFERENCES locations (id) ON DELETE CASCADE,
+    kind VARCHAR(20) NOT NULL,
+    code VARCHAR(50) NOT NULL,
+    name VARCHAR(255),
+    created_at TIMESTAMPTZ NOT NULL DEFAULT NOW(),
+    CONSTRAINT locations_kind_check CHECK (kind IN ('warehouse', 'zone', 'rack', 'bin')),
+    -- Warehouses are the roots of the hierarchy; everything else has a parent.
+    CONSTRAINT locations_parent_check CHECK ((kind = 'warehouse') = (parent_id IS NULL))
+);
+
+CREATE INDEX IF NOT EXISTS locations_client_id_idx ON locations (client_id);
+CREATE UNIQUE INDEX IF NOT EXISTS locations_parent_id_code_key
+    ON locations (parent_id, code) WHERE parent_id IS NOT NULL;
+CREATE UNIQUE INDEX IF NOT EXISTS locations_warehouse_code_key
+    ON locations (client_id, code) WHERE parent_id IS NULL;
+
+-- How many units of a batch sit in a bin. Stock on hand that is in no bin
+-- yet is simply not listed here.
+CREATE TABLE IF NOT EXISTS bin_stock (
+    batch_id UUID NOT NULL REFERENCES batch_details (id) ON DELETE CASCADE,
+    location_id UUID NOT NULL REFERENCES locations (id) ON DELETE CASCADE,
+    units BIGINT NOT NULL,
+    updated_at TIMESTAMPTZ NOT NULL DEFAULT NOW(),
+    PRIMARY KEY (batch_id, location_id),
+    CONSTRAINT bin_stock_units_check CHECK (units > 0)
+);
+
+CREATE INDEX IF NOT EXISTS bin_stock_location_id_idx ON bin_stock (location_id);
+
+CREATE TABLE IF NOT EXISTS stock_transfers (
+    id UUID PRIMARY KEY DEFAULT gen_random_uuid(),
+    batch_id UUID NOT NULL REFERENCES batch_details (id) ON DELETE CASCADE,
+    from_location_id UUID REFERENCES locations (id) ON DELETE CASCADE,
+    to_location_id UUID REFERENCES locations (id) ON DELETE CASCADE,
+    quantity INTEGER NOT NULL,
+    unit VARCHAR(10) NOT NULL,
+    units BIGINT NOT NULL,
+    moved_by UUID NOT NULL REFERENCES clients (id) ON DELETE CASCADE,
+    moved_at TIMESTAMPTZ NOT NULL DEFAULT NOW(),
+    CONSTRAINT stock_transfers_quantity_check CHECK (quantity > 0 AND units > 0),
+    CONSTRAINT stock_transfers_unit_check CHECK (unit IN ('boxes', 'packs', 'units')),
+    CONSTRAINT stock_transfers_locations_check CHECK (
+        (from_location_id IS NOT NULL OR to_location_id IS NOT NULL)
+        AND from_location_id IS DISTINCT FROM to_location_id
+    )
+);
+
+CREATE INDEX IF NOT EXISTS stock_transfers_batch_id_idx ON stock_transfers (batch_id);
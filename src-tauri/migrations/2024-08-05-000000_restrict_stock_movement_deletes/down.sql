@@ -0,0 +1,16 @@
+CREATE OR REPLACE FUNCTION stock_movements_append_only() RETURNS TRIGGER AS $$
+BEGIN
+    IF TG_OP = 'DELETE' AND pg_trigger_depth() > 1 THEN
+        RETURN OLD;
+    END IF;
+    RAISE EXCEPTION 'stock movements are append-only';
+END;
+$$ LANGUAGE plpgsql;
+
+ALTER TABLE stock_movements
+    DROP CONSTRAINT stock_movements_created_by_fkey,
+    ADD CONSTRAINT stock_movements_created_by_fkey
+        FOREIGN KEY (created_by) REFERENCES clients (id) ON DELETE CASCADE,
+    DROP CONSTRAINT stock_movements_batch_id_fkey,
+    ADD CONSTRAINT stock_movements_batch_id_fkey
+        FOREIGN KEY (batch_id) REFERENCES batch_details (id) ON DELETE CASCADE;
@@ -0,0 +1,17 @@
+-- The ledger must outlive mistakes elsewhere: a batch or client that still
+-- has movements can no longer be deleted, instead of taking its history
+-- with it.
+ALTER TABLE stock_movements
+    DROP CONSTRAINT stock_movements_batch_id_fkey,
+    ADD CONSTRAINT stock_movements_batch_id_fkey
+        FOREIGN KEY (batch_id) REFERENCES batch_details (id) ON DELETE RESTRICT,
+    DROP CONSTRAINT stock_movements_created_by_fkey,
+    ADD CONSTRAINT stock_movements_created_by_fkey
+        FOREIGN KEY (created_by) REFERENCES clients (id) ON DELETE RESTRICT;
+
+-- With nothing cascading into the table any more, no delete is let through.
+CREATE OR REPLACE FUNCTION stock_movements_append_only() RETURNS TRIGGER AS $$
+BEGIN
+    RAISE EXCEPTION 'stock movements are append-only';
+END;
+$$ LANGUAGE plpgsql;
@@ -0,0 +1,4 @@
+ALTER TABLE shipment_lines
+    DROP CONSTRAINT shipment_lines_batch_id_fkey,
+    ADD CONSTRAINT shipment_lines_batch_id_fkey
+        FOREIGN KEY (batch_id) REFERENCES batch_details (id) ON DELETE CASCADE;
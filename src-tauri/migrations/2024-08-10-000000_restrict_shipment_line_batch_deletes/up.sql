@@ -0,0 +1,6 @@
+-- A shipment line is the record of what left in which batch; deleting the
+-- batch must not silently rewrite past shipments.
+ALTER TABLE shipment_lines
+    DROP CONSTRAINT shipment_lines_batch_id_fkey,
+    ADD CONSTRAINT shipment_lines_batch_id_fkey
+        FOREIGN KEY (batch_id) REFERENCES batch_details (id) ON DELETE RESTRICT;
@@ -0,0 +1,13 @@
+-- A batch entered by mistake can still be deleted: its opening receipt may
+-- be removed as long as it is the only movement in the batch's ledger.
+CREATE OR REPLACE FUNCTION stock_movements_append_only() RETURNS TRIGGER AS $$
+BEGIN
+    IF TG_OP = 'DELETE' AND OLD.movement_type = 'receipt' AND NOT EXISTS (
+        SELECT 1 FROM stock_movements
+        WHERE batch_id = OLD.batch_id AND id <> OLD.id
+    ) THEN
+        RETURN OLD;
+    END IF;
+    RAISE EXCEPTION 'stock movements are append-only';
+END;
+$$ LANGUAGE plpgsql;
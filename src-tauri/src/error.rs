@@ -0,0 +1,235 @@
+use diesel::result::{DatabaseErrorInformation, DatabaseErrorKind, Error as DieselError};
+use serde::{ser::SerializeStruct, Serialize, Serializer};
+use std::fmt;
+
+/// Broad category of an [`AppError`], used by the frontend to decide how to
+/// present it.
+#[derive(Serialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum ErrorKind {
+    NotFound,
+    Conflict,
+    InvalidInput,
+    Unauthorized,
+    Unavailable,
+    Internal,
+}
+
+/// One rejected row of a multi-row write, reported back so the user can fix
+/// the offending line instead of guessing.
+#[derive(Serialize, Debug, Clone)]
+pub struct RowError {
+    pub index: usize,
+    pub batch_no: String,
+    pub reason: String,
+}
+
+/// Error returned by every service and command.
+///
+/// Serializes to `{ kind, code, message, field, retryable }` so the frontend
+/// can branch on `code` instead of parsing messages. `BatchRejected` also
+/// carries a `rows` array and `KeyStore` a `recovery` hint.
+#[derive(Debug)]
+pub enum AppError {
+    NotFound,
+    UniqueViolation {
+        field: Option<String>,
+    },
+    ForeignKeyViolation {
+        field: Option<String>,
+    },
+    Conflict(String),
+    Validation {
+        field: Option<String>,
+        message: String,
+    },
+    BatchRejected {
+        rows: Vec<RowError>,
+    },
+    Unauthorized(String),
+    Locked(String),
+    /// Key material stored on this machine could not be used. `recovery`
+    /// tells the user how to get going again.
+    KeyStore {
+        code: &'static str,
+        message: String,
+        recovery: String,
+    },
+    DatabaseUnavailable(String),
+    Internal(String),
+}
+
+impl AppError {
+    pub fn validation(field: &str, message: impl Into<String>) -> Self {
+        AppError::Validation {
+            field: Some(field.to_string()),
+            message: message.into(),
+        }
+    }
+
+    pub fn kind(&self) -> ErrorKind {
+        match self {
+            AppError::NotFound => ErrorKind::NotFound,
+            AppError::UniqueViolation { .. }
+            | AppError::ForeignKeyViolation { .. }
+            | AppError::Conflict(_) => ErrorKind::Conflict,
+            AppError::Validation { .. } | AppError::BatchRejected { .. } => ErrorKind::InvalidInput,
+            AppError::Unauthorized(_)
+            | AppError::Locked(_)
+            | AppError::KeyStore {
+                code: "KEY_WRONG", ..
+            } => ErrorKind::Unauthorized,
+            AppError::KeyStore { .. } => ErrorKind::Internal,
+            AppError::DatabaseUnavailable(_) => ErrorKind::Unavailable,
+            AppError::Internal(_) => ErrorKind::Internal,
+        }
+    }
+
+    pub fn code(&self) -> &'static str {
+        match self {
+            AppError::NotFound => "NOT_FOUND",
+            AppError::UniqueViolation { .. } => "UNIQUE_VIOLATION",
+            AppError::ForeignKeyViolation { .. } => "FOREIGN_KEY_VIOLATION",
+            AppError::Conflict(_) => "CONFLICT",
+            AppError::Validation { .. } => "VALIDATION_FAILED",
+            AppError::BatchRejected { .. } => "BATCH_REJECTED",
+            AppError::Unauthorized(_) => "UNAUTHORIZED",
+            AppError::Locked(_) => "KEYS_LOCKED",
+            AppError::KeyStore { code, .. } => code,
+            AppError::DatabaseUnavailable(_) => "DATABASE_UNAVAILABLE",
+            AppError::Internal(_) => "INTERNAL",
+        }
+    }
+
+    pub fn field(&self) -> Option<&str> {
+        match self {
+            AppError::UniqueViolation { field }
+            | AppError::ForeignKeyViolation { field }
+            | AppError::Validation { field, .. } => field.as_deref(),
+            _ => None,
+        }
+    }
+
+    pub fn retryable(&self) -> bool {
+        matches!(self, AppError::DatabaseUnavailable(_))
+    }
+}
+
+impl fmt::Display for AppError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            AppError::NotFound => write!(f, "The requested record was not found"),
+            AppError::UniqueViolation { field: Some(field) } => {
+                write!(f, "A record with this {} already exists", field)
+            }
+            AppError::UniqueViolation { field: None } => {
+                write!(f, "A record with these values already exists")
+            }
+            AppError::ForeignKeyViolation { field: Some(field) } => {
+                write!(
+                    f,
+                    "The referenced {} does not exist or is still in use",
+                    field
+                )
+            }
+            AppError::ForeignKeyViolation { field: None } => {
+                write!(f, "The referenced record does not exist or is still in use")
+            }
+            AppError::Validation { message, .. } => write!(f, "{}", message),
+            AppError::BatchRejected { rows } => {
+                write!(
+                    f,
+                    "{} batch row(s) were rejected, nothing was saved",
+                    rows.len()
+                )
+            }
+            AppError::Conflict(message)
+            | AppError::Unauthorized(message)
+            | AppError::Locked(message)
+            | AppError::KeyStore { message, .. }
+            | AppError::DatabaseUnavailable(message)
+            | AppError::Internal(message) => write!(f, "{}", message),
+        }
+    }
+}
+
+impl std::error::Error for AppError {}
+
+impl Serialize for AppError {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        let mut state = serializer.serialize_struct("AppError", 7)?;
+        state.serialize_field("kind", &self.kind())?;
+        state.serialize_field("code", self.code())?;
+        state.serialize_field("message", &self.to_string())?;
+        state.serialize_field("field", &self.field())?;
+        state.serialize_field("retryable", &self.retryable())?;
+        match self {
+            AppError::BatchRejected { rows } => state.serialize_field("rows", rows)?,
+            _ => state.skip_field("rows")?,
+        }
+        match self {
+            AppError::KeyStore { recovery, .. } => state.serialize_field("recovery", recovery)?,
+            _ => state.skip_field("recovery")?,
+        }
+        state.end()
+    }
+}
+
+/// Maps the constraint names created by our migrations to the input field
+/// the user can correct.
+fn field_for_constraint(constraint: &str) -> Option<&'static str> {
+    match constraint {
+        "batch_details_product_id_batch_no_key" => Some("batch_no"),
+        "batch_details_product_id_fkey" => Some("product_id"),
+        "batch_details_status_check" => Some("status"),
+        "goods_receipts_client_id_supplier_delivery_note_key" => Some("delivery_note"),
+        "goods_receipt_lines_product_id_fkey" => Some("product_id"),
+        "shipments_client_id_reference_key" => Some("reference"),
+        "shipment_lines_batch_id_fkey" | "stock_movements_batch_id_fkey" => Some("batch_id"),
+        "locations_parent_id_code_key" | "locations_warehouse_code_key" => Some("code"),
+        "products_client_id_fkey"
+        | "sessions_client_id_fkey"
+        | "auth_challenges_client_id_fkey" => Some("client_id"),
+        _ => None,
+    }
+}
+
+fn field_from_info(info: &dyn DatabaseErrorInformation) -> Option<String> {
+    info.constraint_name()
+        .and_then(field_for_constraint)
+        .or(info.column_name())
+        .map(str::to_string)
+}
+
+impl From<DieselError> for AppError {
+    fn from(err: DieselError) -> Self {
+        match err {
+            DieselError::NotFound => AppError::NotFound,
+            DieselError::DatabaseError(kind, info) => match kind {
+                DatabaseErrorKind::UniqueViolation => AppError::UniqueViolation {
+                    field: field_from_info(info.as_ref()),
+                },
+                DatabaseErrorKind::ForeignKeyViolation => AppError::ForeignKeyViolation {
+                    field: field_from_info(info.as_ref()),
+                },
+                DatabaseErrorKind::NotNullViolation | DatabaseErrorKind::CheckViolation => {
+                    AppError::Validation {
+                        field: field_from_info(info.as_ref()),
+                        message: info.message().to_string(),
+                    }
+                }
+                DatabaseErrorKind::ClosedConnection => {
+                    AppError::DatabaseUnavailable(info.message().to_string())
+                }
+                _ => AppError::Internal(info.message().to_string()),
+            },
+            other => AppError::Internal(other.to_string()),
+        }
+    }
+}
+
+impl From<diesel::r2d2::PoolError> for AppError {
+    fn from(err: diesel::r2d2::PoolError) -> Self {
+        AppError::DatabaseUnavailable(err.to_string())
+    }
+}
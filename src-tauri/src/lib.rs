@@ -1,15 +1,25 @@
+mod error;
+mod packaging;
+
+pub use error::{AppError, ErrorKind, RowError};
+pub use packaging::{PackagingConfiguration, PackagingTotals};
+
 use serde::{Deserialize, Serialize};
 use {
     chrono::NaiveDate,
-    diesel::{pg::PgConnection, prelude::*},
+    diesel::{
+        pg::PgConnection,
+        r2d2::{ConnectionManager, Pool, PooledConnection},
+    },
+    diesel_migrations::{embed_migrations, EmbeddedMigrations, MigrationHarness},
     dotenvy::dotenv,
-    std::env,
+    std::{env, sync::RwLock, thread, time::Duration},
 };
 
-#[derive(Serialize)]
-pub struct ErrorResponse {
-    pub error: String,
-}
+pub type DbPool = Pool<ConnectionManager<PgConnection>>;
+pub type DbConnection = PooledConnection<ConnectionManager<PgConnection>>;
+
+pub const MIGRATIONS: EmbeddedMigrations = embed_migrations!("migrations");
 
 #[derive(Deserialize)]
 #[serde(rename_all = "camelCase")]
@@ -25,10 +35,231 @@ pub struct BatchInput {
     pub total_packs: i32,
 }
 
-pub fn establish_connection() -> PgConnection {
+/// Pool tuning, read from `DATABASE_POOL_*` variables with sensible defaults.
+pub struct PoolConfig {
+    pub max_size: u32,
+    pub min_idle: Option<u32>,
+    pub connection_timeout: Duration,
+    pub idle_timeout: Option<Duration>,
+    pub max_lifetime: Option<Duration>,
+}
+
+impl Default for PoolConfig {
+    fn default() -> Self {
+        PoolConfig {
+            max_size: 10,
+            min_idle: Some(1),
+            connection_timeout: Duration::from_secs(5),
+            idle_timeout: Some(Duration::from_secs(600)),
+            max_lifetime: Some(Duration::from_secs(1800)),
+        }
+    }
+}
+
+impl PoolConfig {
+    pub fn from_env() -> Self {
+        dotenv().ok();
+
+        let defaults = PoolConfig::default();
+        PoolConfig {
+            max_size: env_var("DATABASE_POOL_MAX_SIZE").unwrap_or(defaults.max_size),
+            min_idle: env_var("DATABASE_POOL_MIN_IDLE").or(defaults.min_idle),
+            connection_timeout: env_var("DATABASE_POOL_TIMEOUT_SECS")
+                .map(Duration::from_secs)
+                .unwrap_or(defaults.connection_timeout),
+            idle_timeout: env_var("DATABASE_POOL_IDLE_TIMEOUT_SECS")
+                .map(Duration::from_secs)
+                .or(defaults.idle_timeout),
+            max_lifetime: env_var("DATABASE_POOL_MAX_LIFETIME_SECS")
+                .map(Duration::from_secs)
+                .or(defaults.max_lifetime),
+        }
+    }
+}
+
+fn env_var<T: std::str::FromStr>(name: &str) -> Option<T> {
+    env::var(name).ok().and_then(|value| value.parse().ok())
+}
+
+/// Builds the connection pool shared by every command.
+///
+/// Connections are validated on checkout, so connections broken by a
+/// Postgres restart are dropped and replaced instead of being handed out.
+pub fn establish_pool() -> Result<DbPool, String> {
     dotenv().ok();
 
-    let database_url = env::var("DATABASE_URL").expect("DATABASE_URL must be set");
-    PgConnection::establish(&database_url)
-        .unwrap_or_else(|_| panic!("Error connecting to {}", database_url))
+    let database_url =
+        env::var("DATABASE_URL").map_err(|_| "DATABASE_URL must be set".to_string())?;
+    let config = PoolConfig::from_env();
+    let manager = ConnectionManager::<PgConnection>::new(&database_url);
+
+    Pool::builder()
+        .max_size(config.max_size)
+        .min_idle(config.min_idle)
+        .connection_timeout(config.connection_timeout)
+        .idle_timeout(config.idle_timeout)
+        .max_lifetime(config.max_lifetime)
+        .test_on_check_out(true)
+        .build(manager)
+        .map_err(|e| format!("Error connecting to the database: {}", e))
+}
+
+#[derive(Serialize, Clone, Debug)]
+#[serde(tag = "state", rename_all = "snake_case")]
+pub enum DatabaseStatus {
+    Connecting {
+        attempt: u32,
+    },
+    Connected,
+    Unavailable {
+        reason: String,
+        attempt: u32,
+        retry_in_secs: u64,
+    },
+    MigrationFailed {
+        reason: String,
+        attempt: u32,
+        retry_in_secs: u64,
+    },
+}
+
+/// Holds the pool once it exists, so the app can start (and report why)
+/// while Postgres is still unreachable.
+pub struct Database {
+    pool: RwLock<Option<DbPool>>,
+    status: RwLock<DatabaseStatus>,
+}
+
+impl Default for Database {
+    fn default() -> Self {
+        Database {
+            pool: RwLock::new(None),
+            status: RwLock::new(DatabaseStatus::Connecting { attempt: 0 }),
+        }
+    }
+}
+
+impl Database {
+    pub fn status(&self) -> DatabaseStatus {
+        self.status
+            .read()
+            .unwrap_or_else(|e| e.into_inner())
+            .clone()
+    }
+
+    pub fn pool(&self) -> Option<DbPool> {
+        self.pool.read().unwrap_or_else(|e| e.into_inner()).clone()
+    }
+
+    pub fn get(&self) -> Result<DbConnection, AppError> {
+        match self.pool() {
+            Some(pool) => pool.get().map_err(AppError::from),
+            None => Err(AppError::DatabaseUnavailable(match self.status() {
+                DatabaseStatus::Unavailable { reason, .. }
+                | DatabaseStatus::MigrationFailed { reason, .. } => {
+                    format!("Database unavailable: {}", reason)
+                }
+                _ => "Database unavailable: still connecting".to_string(),
+            })),
+        }
+    }
+
+    fn set_status(&self, status: DatabaseStatus) {
+        *self.status.write().unwrap_or_else(|e| e.into_inner()) = status;
+    }
+
+    /// Tries to build the pool and apply pending migrations until both
+    /// succeed, backing off exponentially between attempts, before handing
+    /// the pool out. `on_change` is called after every status update.
+    pub fn connect_with_retry<F>(&self, on_change: F)
+    where
+        F: Fn(&DatabaseStatus),
+    {
+        let mut attempt = 0;
+        let mut backoff = INITIAL_RETRY_BACKOFF;
+
+        loop {
+            attempt += 1;
+            self.set_status(DatabaseStatus::Connecting { attempt });
+            on_change(&self.status());
+
+            let status = match establish_pool() {
+                Ok(pool) => {
+                    let migrated = pool
+                        .get()
+                        .map_err(|e| e.to_string())
+                        .and_then(|mut conn| run_pending_migrations(&mut conn));
+
+                    match migrated {
+                        Ok(_) => {
+                            *self.pool.write().unwrap_or_else(|e| e.into_inner()) = Some(pool);
+                            self.set_status(DatabaseStatus::Connected);
+                            on_change(&self.status());
+                            return;
+                        }
+                        Err(reason) => DatabaseStatus::MigrationFailed {
+                            reason,
+                            attempt,
+                            retry_in_secs: backoff.as_secs(),
+                        },
+                    }
+                }
+                Err(reason) => DatabaseStatus::Unavailable {
+                    reason,
+                    attempt,
+                    retry_in_secs: backoff.as_secs(),
+                },
+            };
+
+            self.set_status(status);
+            on_change(&self.status());
+            thread::sleep(backoff);
+            backoff = (backoff * 2).min(MAX_RETRY_BACKOFF);
+        }
+    }
+}
+
+const INITIAL_RETRY_BACKOFF: Duration = Duration::from_secs(1);
+const MAX_RETRY_BACKOFF: Duration = Duration::from_secs(60);
+
+#[derive(Serialize)]
+pub struct SchemaVersion {
+    pub current: Option<String>,
+    pub applied: Vec<String>,
+    pub pending: Vec<String>,
+}
+
+/// Applies every embedded migration that has not run yet and returns the
+/// versions that were applied.
+pub fn run_pending_migrations(conn: &mut DbConnection) -> Result<Vec<String>, String> {
+    conn.run_pending_migrations(MIGRATIONS)
+        .map(|versions| versions.iter().map(|v| v.to_string()).collect())
+        .map_err(|e| format!("Error running migrations: {}", e))
+}
+
+pub fn pending_migrations(conn: &mut DbConnection) -> Result<Vec<String>, String> {
+    conn.pending_migrations(MIGRATIONS)
+        .map(|migrations| {
+            migrations
+                .iter()
+                .map(|m| m.name().version().to_string())
+                .collect()
+        })
+        .map_err(|e| format!("Error reading pending migrations: {}", e))
+}
+
+pub fn schema_version(conn: &mut DbConnection) -> Result<SchemaVersion, String> {
+    let mut applied: Vec<String> = conn
+        .applied_migrations()
+        .map_err(|e| format!("Error reading applied migrations: {}", e))?
+        .iter()
+        .map(|v| v.to_string())
+        .collect();
+    applied.sort();
+
+    Ok(SchemaVersion {
+        current: applied.last().cloned(),
+        pending: pending_migrations(conn)?,
+        applied,
+    })
 }
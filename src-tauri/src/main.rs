@@ -5,182 +5,396 @@ mod models;
 mod schema;
 mod services;
 
-use app::{establish_connection, BatchInput};
+use app::{
+    establish_pool, pending_migrations, run_pending_migrations, schema_version, AppError,
+    BatchInput, Database, DatabaseStatus, DbConnection,
+};
 use chrono::NaiveDateTime;
-use diesel::PgConnection;
-use models::{NewBatchDetail, NewProduct, UpdateBatchDetail, UpdateProduct};
+use models::{
+    BatchStatus, LocationKind, MovementType, NewProduct, QuantityUnit, ReceiptStatus,
+    ShipmentStatus, UpdateBatchDetail, UpdateProduct,
+};
 use services::{
-    batch_details_service, client_service, product_service,
-    session_management_service::{authenticate_client, Session},
+    allocation_service, batch_details_service, batch_status_service, client_service,
+    expiry_service::{self, ExpiryThresholds},
+    key_management_service::{recover_interrupted_rotation, Keyring, KeyringStatus},
+    location_service, product_service,
+    receipt_service::{self, ReceiptInput},
+    reconciliation_service, search_service,
+    session_management_service::{self, authenticate_client, require_session, Session},
+    shipment_service::{self, ShipmentInput},
+    stock_service,
 };
-use std::sync::Mutex;
+use std::{env, process, sync::Arc, thread};
+use tauri::Emitter;
+use tauri_plugin_notification::NotificationExt;
 use uuid::Uuid;
+use zeroize::Zeroizing;
 
 struct AppState {
-    conn: Mutex<PgConnection>,
+    database: Arc<Database>,
+    keyring: Keyring,
+    expiry_thresholds: ExpiryThresholds,
 }
 
-#[tauri::command]
-fn create_client(state: tauri::State<AppState>) -> Result<serde_json::Value, String> {
-    let mut conn = state.conn.lock().unwrap();
-    match client_service::create_client(&mut *conn) {
-        Ok(client) => Ok(serde_json::json!({ "client": client })),
-        Err(err) => Err(err.error),
+impl AppState {
+    /// Checks out a connection and resolves `token` to its session.
+    fn authorize(&self, token: &str) -> Result<(Session, DbConnection), AppError> {
+        let mut conn = self.database.get()?;
+        let session = require_session(&mut conn, token)?;
+        Ok((session, conn))
     }
 }
 
 #[tauri::command]
-fn sign_in() -> Result<serde_json::Value, String> {
-    match authenticate_client() {
-        Ok(session) => Ok(serde_json::json!({
-            "token": session.token,
-            "client_id": session.client_id,
-            "expires_at": session.expires_at,
-        })),
-        Err(err) => Err(err.to_string()),
-    }
+fn get_database_status(state: tauri::State<AppState>) -> DatabaseStatus {
+    state.database.status()
 }
 
 #[tauri::command]
-fn validate_session(session: Session) -> bool {
-    services::session_management_service::validate_session(&session)
+fn get_schema_version(state: tauri::State<AppState>) -> Result<serde_json::Value, AppError> {
+    let mut conn = state.database.get()?;
+    let version = schema_version(&mut conn).map_err(AppError::Internal)?;
+    Ok(serde_json::json!({ "schema": version }))
 }
 
 #[tauri::command]
-fn create_product(
+fn get_keyring_status(state: tauri::State<AppState>) -> KeyringStatus {
+    state.keyring.status()
+}
+
+#[tauri::command]
+fn set_passphrase(
+    state: tauri::State<AppState>,
+    passphrase: String,
+) -> Result<KeyringStatus, AppError> {
+    state.keyring.set_passphrase(&Zeroizing::new(passphrase))
+}
+
+#[tauri::command]
+fn unlock_keys(
+    state: tauri::State<AppState>,
+    passphrase: String,
+) -> Result<KeyringStatus, AppError> {
+    state.keyring.unlock(&Zeroizing::new(passphrase))
+}
+
+#[tauri::command]
+fn lock_keys(state: tauri::State<AppState>) -> KeyringStatus {
+    state.keyring.lock()
+}
+
+#[tauri::command]
+fn rotate_encryption_key(
+    state: tauri::State<AppState>,
+    current_passphrase: String,
+    new_passphrase: String,
+) -> Result<KeyringStatus, AppError> {
+    let current_passphrase = Zeroizing::new(current_passphrase);
+    let new_passphrase = Zeroizing::new(new_passphrase);
+    state.keyring.rotate(&current_passphrase, &new_passphrase)
+}
+
+#[tauri::command]
+fn list_clients(state: tauri::State<AppState>) -> Result<serde_json::Value, AppError> {
+    let mut conn = state.database.get()?;
+    let clients = client_service::list_local_clients(&mut conn)?;
+    Ok(serde_json::json!({ "clients": clients }))
+}
+
+#[tauri::command]
+fn create_client(
+    state: tauri::State<AppState>,
+    name: String,
+) -> Result<serde_json::Value, AppError> {
+    let mut conn = state.database.get()?;
+    let key = state.keyring.key()?;
+    let client = client_service::create_client(&mut conn, &key, &name)?;
+    Ok(serde_json::json!({ "client": client }))
+}
+
+#[tauri::command]
+fn rename_client(
+    state: tauri::State<AppState>,
+    token: String,
+    name: String,
+) -> Result<serde_json::Value, AppError> {
+    let (session, mut conn) = state.authorize(&token)?;
+    let client = client_service::rename_client(&mut conn, session.client_id, &name)?;
+    Ok(serde_json::json!({ "client": client }))
+}
+
+/// Takes the keyring rather than a session: identities whose `clients` row
+/// is gone cannot sign in, but must still be removable.
+#[tauri::command]
+fn remove_client(
     state: tauri::State<AppState>,
     client_id: Uuid,
+    confirmed: bool,
+) -> Result<serde_json::Value, AppError> {
+    let mut conn = state.database.get()?;
+    state.keyring.key()?;
+    client_service::remove_client(&mut conn, client_id, confirmed)?;
+    Ok(serde_json::json!({ "removed": client_id }))
+}
+
+#[tauri::command]
+fn export_client_identity(
+    state: tauri::State<AppState>,
+    token: String,
+    passphrase: String,
+) -> Result<serde_json::Value, AppError> {
+    let passphrase = Zeroizing::new(passphrase);
+    let (session, mut conn) = state.authorize(&token)?;
+    let key = state.keyring.key()?;
+    let bundle = client_service::export_client(&mut conn, &key, session.client_id, &passphrase)?;
+    Ok(serde_json::json!({ "bundle": bundle }))
+}
+
+#[tauri::command]
+fn import_client_identity(
+    state: tauri::State<AppState>,
+    bundle: String,
+    passphrase: String,
+) -> Result<serde_json::Value, AppError> {
+    let passphrase = Zeroizing::new(passphrase);
+    let mut conn = state.database.get()?;
+    let key = state.keyring.key()?;
+    let client = client_service::import_client(&mut conn, &key, &bundle, &passphrase)?;
+    Ok(serde_json::json!({ "client": client }))
+}
+
+#[tauri::command]
+fn sign_in(state: tauri::State<AppState>, client_id: Uuid) -> Result<serde_json::Value, AppError> {
+    let mut conn = state.database.get()?;
+    let key = state.keyring.key()?;
+    let session = authenticate_client(&mut conn, &key, client_id)?;
+    Ok(serde_json::json!({
+        "token": session.token,
+        "client_id": session.client_id,
+        "expires_at": session.expires_at,
+    }))
+}
+
+#[tauri::command]
+fn validate_session(state: tauri::State<AppState>, session: Session) -> bool {
+    state
+        .authorize(&session.token)
+        .is_ok_and(|(stored, _)| stored.client_id == session.client_id)
+}
+
+#[tauri::command]
+fn sign_out(state: tauri::State<AppState>, token: String) -> Result<serde_json::Value, AppError> {
+    let mut conn = state.database.get()?;
+    session_management_service::sign_out(&mut conn, &token)?;
+    Ok(serde_json::json!({ "signed_out": true }))
+}
+
+#[tauri::command]
+fn revoke_all_sessions(
+    state: tauri::State<AppState>,
+    token: String,
+) -> Result<serde_json::Value, AppError> {
+    let (session, mut conn) = state.authorize(&token)?;
+    let revoked = session_management_service::revoke_all_sessions(&mut conn, session.client_id)?;
+    Ok(serde_json::json!({ "revoked": revoked }))
+}
+
+#[tauri::command]
+fn create_product(
+    state: tauri::State<AppState>,
+    token: String,
     product_name: String,
     total_quantity: i32,
     total_shipper_boxes: i32,
-) -> Result<serde_json::Value, String> {
-    let mut conn = state.conn.lock().unwrap();
+) -> Result<serde_json::Value, AppError> {
+    let (session, mut conn) = state.authorize(&token)?;
     let new_product = NewProduct {
-        client_id,
+        client_id: session.client_id,
         product_name: &product_name,
         total_quantity,
         total_shipper_boxes,
     };
-    match product_service::create_product(&mut *conn, new_product) {
-        Ok(product) => Ok(serde_json::json!({ "product": product })),
-        Err(err) => Err(err.error),
-    }
+    let product = product_service::create_product(&mut conn, new_product)?;
+    Ok(serde_json::json!({ "product": product }))
 }
 
 #[tauri::command]
 fn get_product(
     state: tauri::State<AppState>,
+    token: String,
     product_id: Uuid,
-) -> Result<serde_json::Value, String> {
-    let mut conn = state.conn.lock().unwrap();
-    match product_service::get_product(&mut *conn, product_id) {
-        Ok(product) => Ok(serde_json::json!({ "product": product })),
-        Err(err) => Err(err.error),
-    }
+) -> Result<serde_json::Value, AppError> {
+    let (session, mut conn) = state.authorize(&token)?;
+    let product = product_service::get_product(&mut conn, session.client_id, product_id)?;
+    Ok(serde_json::json!({ "product": product }))
 }
 
 #[tauri::command]
-fn get_all_products(state: tauri::State<AppState>) -> Result<serde_json::Value, String> {
-    let mut conn = state.conn.lock().unwrap();
-    match product_service::get_all_products(&mut *conn) {
-        Ok(products) => Ok(serde_json::json!({ "products": products })),
-        Err(err) => Err(err.error),
-    }
+fn get_all_products(
+    state: tauri::State<AppState>,
+    token: String,
+) -> Result<serde_json::Value, AppError> {
+    let (session, mut conn) = state.authorize(&token)?;
+    let products = product_service::get_all_products(&mut conn, session.client_id)?;
+    Ok(serde_json::json!({ "products": products }))
 }
 
 #[tauri::command]
 fn get_all_products_for_client(
     state: tauri::State<AppState>,
-    client_id: Uuid,
-) -> Result<serde_json::Value, String> {
-    let mut conn = state.conn.lock().unwrap();
-    match product_service::get_all_products_for_client(&mut *conn, client_id) {
-        Ok(products) => Ok(serde_json::json!({ "data": products })),
-        Err(err) => Err(err.error),
-    }
+    token: String,
+) -> Result<serde_json::Value, AppError> {
+    let (session, mut conn) = state.authorize(&token)?;
+    let products = product_service::get_all_products_for_client(&mut conn, session.client_id)?;
+    Ok(serde_json::json!({ "data": products }))
+}
+
+#[tauri::command]
+fn query_products(
+    state: tauri::State<AppState>,
+    token: String,
+    query: Option<product_service::ProductQuery>,
+) -> Result<serde_json::Value, AppError> {
+    let (session, mut conn) = state.authorize(&token)?;
+    let page =
+        product_service::query_products(&mut conn, session.client_id, &query.unwrap_or_default())?;
+    Ok(serde_json::json!({ "data": page }))
+}
+
+#[tauri::command]
+fn search(
+    state: tauri::State<AppState>,
+    token: String,
+    query: String,
+    limit: Option<usize>,
+) -> Result<serde_json::Value, AppError> {
+    let (session, mut conn) = state.authorize(&token)?;
+    let hits = search_service::search(&mut conn, session.client_id, &query, limit)?;
+    Ok(serde_json::json!({ "data": hits }))
+}
+
+#[tauri::command]
+fn get_expiry_report(
+    state: tauri::State<AppState>,
+    token: String,
+    thresholds: Option<Vec<i64>>,
+) -> Result<serde_json::Value, AppError> {
+    let (session, mut conn) = state.authorize(&token)?;
+    let thresholds = match thresholds {
+        Some(days) => ExpiryThresholds::new(days)?,
+        None => state.expiry_thresholds.clone(),
+    };
+    let report = expiry_service::expiry_report(&mut conn, session.client_id, &thresholds)?;
+    Ok(serde_json::json!({ "data": report }))
 }
 
 #[tauri::command]
 fn update_product(
     state: tauri::State<AppState>,
+    token: String,
     product_id: Uuid,
     product_name: Option<String>,
     total_quantity: Option<i32>,
     total_shipper_boxes: Option<i32>,
     updated_at: Option<NaiveDateTime>,
-) -> Result<serde_json::Value, String> {
-    let mut conn = state.conn.lock().unwrap();
+) -> Result<serde_json::Value, AppError> {
+    let (session, mut conn) = state.authorize(&token)?;
     let product_data = UpdateProduct {
         product_name: product_name.as_deref(),
         total_quantity,
         total_shipper_boxes,
         updated_at,
+        derived_totals: None,
     };
-    match product_service::update_product(&mut *conn, product_id, product_data) {
-        Ok(product) => Ok(serde_json::json!({"data": product})),
-        Err(err) => Err(err.error),
-    }
+    let product =
+        product_service::update_product(&mut conn, session.client_id, product_id, product_data)?;
+    Ok(serde_json::json!({"data": product}))
 }
 
 #[tauri::command]
 fn delete_product(
     state: tauri::State<AppState>,
+    token: String,
     product_id: Uuid,
-) -> Result<serde_json::Value, String> {
-    let mut conn = state.conn.lock().unwrap();
-    match product_service::delete_product(&mut *conn, product_id) {
-        Ok(rows) => Ok(serde_json::json!({ "deleted": rows })),
-        Err(err) => Err(err.error),
-    }
+) -> Result<serde_json::Value, AppError> {
+    let (session, mut conn) = state.authorize(&token)?;
+    let rows = product_service::delete_product(&mut conn, session.client_id, product_id)?;
+    Ok(serde_json::json!({ "deleted": rows }))
+}
+
+#[tauri::command]
+fn reconcile_product_totals(
+    state: tauri::State<AppState>,
+    token: String,
+) -> Result<serde_json::Value, AppError> {
+    let (session, mut conn) = state.authorize(&token)?;
+    let report = reconciliation_service::reconcile_client_products(&mut conn, session.client_id)?;
+    Ok(serde_json::json!({ "data": report }))
+}
+
+#[tauri::command]
+fn get_product_reconciliation(
+    state: tauri::State<AppState>,
+    token: String,
+    product_id: Uuid,
+) -> Result<serde_json::Value, AppError> {
+    let (session, mut conn) = state.authorize(&token)?;
+    let reconciliation =
+        reconciliation_service::reconcile_product(&mut conn, session.client_id, product_id)?;
+    Ok(serde_json::json!({ "data": reconciliation }))
+}
+
+#[tauri::command]
+fn set_product_totals_mode(
+    state: tauri::State<AppState>,
+    token: String,
+    product_id: Uuid,
+    derived: bool,
+) -> Result<serde_json::Value, AppError> {
+    let (session, mut conn) = state.authorize(&token)?;
+    let product = reconciliation_service::set_derived_totals(
+        &mut conn,
+        session.client_id,
+        product_id,
+        derived,
+    )?;
+    Ok(serde_json::json!({ "product": product }))
 }
 
 #[tauri::command]
 fn create_batch_details(
     state: tauri::State<AppState>,
+    token: String,
     product_id: Uuid,
     batch: Vec<BatchInput>,
-) -> Result<serde_json::Value, String> {
-    let mut conn = state.conn.lock().unwrap();
-    let mut created_batches = Vec::new();
-
-    for batch_input in batch {
-        let new_batch_detail = NewBatchDetail {
-            product_id,
-            batch_no: batch_input.batch_no.as_str(),
-            mfg_date: batch_input.mfg_date,
-            exp_date: batch_input.exp_date,
-            boxes: batch_input.boxes,
-            units_per_box: batch_input.units_per_box,
-            units_per_pack: batch_input.units_per_pack,
-            packs_per_box: batch_input.packs_per_box,
-            packages_configuration: batch_input.packages_configuration.as_str(),
-            total_packs: batch_input.total_packs,
-        };
-
-        match batch_details_service::create_batch_detail(&mut *conn, new_batch_detail) {
-            Ok(batch_detail) => created_batches.push(batch_detail),
-            Err(err) => return Err(err.error),
-        }
-    }
-
+) -> Result<serde_json::Value, AppError> {
+    let (session, mut conn) = state.authorize(&token)?;
+    let created_batches = batch_details_service::create_batch_details(
+        &mut conn,
+        session.client_id,
+        product_id,
+        &batch,
+    )?;
     Ok(serde_json::json!({ "batch_details": created_batches }))
 }
 
 #[tauri::command]
 fn get_batch_detail(
     state: tauri::State<AppState>,
+    token: String,
     batch_detail_id: Uuid,
-) -> Result<serde_json::Value, String> {
-    let mut conn = state.conn.lock().unwrap();
-    match batch_details_service::get_batch_detail(&mut *conn, batch_detail_id) {
-        Ok(batch_detail) => Ok(serde_json::json!({ "batch_detail": batch_detail })),
-        Err(err) => Err(err.error),
-    }
+) -> Result<serde_json::Value, AppError> {
+    let (session, mut conn) = state.authorize(&token)?;
+    let batch_detail =
+        batch_details_service::get_batch_detail(&mut conn, session.client_id, batch_detail_id)?;
+    Ok(serde_json::json!({ "batch_detail": batch_detail }))
 }
 
 #[tauri::command]
 fn update_batch_detail(
     state: tauri::State<AppState>,
+    token: String,
     batch_detail_id: Uuid,
     batch_no: Option<String>,
     mfg_date: Option<chrono::NaiveDate>,
@@ -191,8 +405,8 @@ fn update_batch_detail(
     packs_per_box: Option<i32>,
     packages_configuration: Option<String>,
     total_packs: Option<i32>,
-) -> Result<serde_json::Value, String> {
-    let mut conn = state.conn.lock().unwrap();
+) -> Result<serde_json::Value, AppError> {
+    let (session, mut conn) = state.authorize(&token)?;
     let batch_detail_data = UpdateBatchDetail {
         batch_no: batch_no.as_deref(),
         mfg_date,
@@ -204,45 +418,473 @@ fn update_batch_detail(
         packages_configuration: packages_configuration.as_deref(),
         total_packs,
     };
-    match batch_details_service::update_batch_detail(&mut *conn, batch_detail_id, batch_detail_data)
-    {
-        Ok(batch_detail) => Ok(serde_json::json!({ "batch_details": batch_detail })),
-        Err(err) => Err(err.error),
-    }
+    let batch_detail = batch_details_service::update_batch_detail(
+        &mut conn,
+        session.client_id,
+        batch_detail_id,
+        batch_detail_data,
+    )?;
+    Ok(serde_json::json!({ "batch_details": batch_detail }))
 }
 
 #[tauri::command]
 fn delete_batch_detail(
     state: tauri::State<AppState>,
+    token: String,
     batch_detail_id: Uuid,
-) -> Result<serde_json::Value, String> {
-    let mut conn = state.conn.lock().unwrap();
-    match batch_details_service::delete_batch_detail(&mut *conn, batch_detail_id) {
-        Ok(rows) => Ok(serde_json::json!({ "deleted": rows })),
-        Err(err) => Err(err.error),
-    }
+) -> Result<serde_json::Value, AppError> {
+    let (session, mut conn) = state.authorize(&token)?;
+    let rows =
+        batch_details_service::delete_batch_detail(&mut conn, session.client_id, batch_detail_id)?;
+    Ok(serde_json::json!({ "deleted": rows }))
 }
 
 #[tauri::command]
 fn fetch_all_batches_for_product(
     state: tauri::State<AppState>,
+    token: String,
+    product_id: Uuid,
+    status: Option<Vec<BatchStatus>>,
+) -> Result<serde_json::Value, AppError> {
+    let (session, mut conn) = state.authorize(&token)?;
+    let products = batch_details_service::fetch_all_batches_for_product(
+        &mut conn,
+        session.client_id,
+        product_id,
+        status.as_deref(),
+    )?;
+    Ok(serde_json::json!({ "batch_details": products }))
+}
+
+#[tauri::command]
+fn transition_batch_status(
+    state: tauri::State<AppState>,
+    token: String,
+    batch_detail_id: Uuid,
+    status: BatchStatus,
+    reason: String,
+) -> Result<serde_json::Value, AppError> {
+    let (session, mut conn) = state.authorize(&token)?;
+    let (batch_detail, change) = batch_status_service::transition_batch_status(
+        &mut conn,
+        session.client_id,
+        batch_detail_id,
+        status,
+        &reason,
+    )?;
+    Ok(serde_json::json!({ "batch_detail": batch_detail, "change": change }))
+}
+
+#[tauri::command]
+fn get_batch_status_history(
+    state: tauri::State<AppState>,
+    token: String,
+    batch_detail_id: Uuid,
+) -> Result<serde_json::Value, AppError> {
+    let (session, mut conn) = state.authorize(&token)?;
+    let history =
+        batch_status_service::batch_status_history(&mut conn, session.client_id, batch_detail_id)?;
+    Ok(serde_json::json!({ "history": history }))
+}
+
+#[tauri::command]
+fn fetch_batches_by_status(
+    state: tauri::State<AppState>,
+    token: String,
+    status: Vec<BatchStatus>,
+) -> Result<serde_json::Value, AppError> {
+    let (session, mut conn) = state.authorize(&token)?;
+    let batch_details =
+        batch_status_service::fetch_batches_by_status(&mut conn, session.client_id, &status)?;
+    Ok(serde_json::json!({ "batch_details": batch_details }))
+}
+
+#[tauri::command]
+fn propose_allocation(
+    state: tauri::State<AppState>,
+    token: String,
     product_id: Uuid,
-) -> Result<serde_json::Value, String> {
-    let mut conn = state.conn.lock().unwrap();
-    match batch_details_service::fetch_all_batches_for_product(&mut *conn, product_id) {
-        Ok(products) => Ok(serde_json::json!({ "batch_details": products })),
-        Err(err) => Err(err.error),
+    quantity: i64,
+    unit: QuantityUnit,
+    min_shelf_life_days: Option<i64>,
+) -> Result<serde_json::Value, AppError> {
+    let (session, mut conn) = state.authorize(&token)?;
+    let allocation = allocation_service::propose_allocation(
+        &mut conn,
+        session.client_id,
+        product_id,
+        quantity,
+        unit,
+        min_shelf_life_days,
+    )?;
+    Ok(serde_json::json!({ "data": allocation }))
+}
+
+#[tauri::command]
+fn post_stock_movement(
+    state: tauri::State<AppState>,
+    token: String,
+    batch_detail_id: Uuid,
+    movement_type: MovementType,
+    quantity: i32,
+    unit: QuantityUnit,
+    reference: Option<String>,
+) -> Result<serde_json::Value, AppError> {
+    let (session, mut conn) = state.authorize(&token)?;
+    let (movement, on_hand) = stock_service::post_movement(
+        &mut conn,
+        session.client_id,
+        batch_detail_id,
+        movement_type,
+        quantity,
+        unit,
+        reference.as_deref(),
+    )?;
+    Ok(serde_json::json!({ "movement": movement, "on_hand": on_hand }))
+}
+
+#[tauri::command]
+fn get_batch_movements(
+    state: tauri::State<AppState>,
+    token: String,
+    batch_detail_id: Uuid,
+) -> Result<serde_json::Value, AppError> {
+    let (session, mut conn) = state.authorize(&token)?;
+    let (movements, on_hand) =
+        stock_service::batch_movements(&mut conn, session.client_id, batch_detail_id)?;
+    Ok(serde_json::json!({ "movements": movements, "on_hand": on_hand }))
+}
+
+#[tauri::command]
+fn create_goods_receipt(
+    state: tauri::State<AppState>,
+    token: String,
+    receipt: ReceiptInput,
+) -> Result<serde_json::Value, AppError> {
+    let (session, mut conn) = state.authorize(&token)?;
+    let receipt = receipt_service::create_goods_receipt(&mut conn, session.client_id, &receipt)?;
+    Ok(serde_json::json!({ "data": receipt }))
+}
+
+#[tauri::command]
+fn update_goods_receipt(
+    state: tauri::State<AppState>,
+    token: String,
+    receipt_id: Uuid,
+    receipt: ReceiptInput,
+) -> Result<serde_json::Value, AppError> {
+    let (session, mut conn) = state.authorize(&token)?;
+    let receipt =
+        receipt_service::update_goods_receipt(&mut conn, session.client_id, receipt_id, &receipt)?;
+    Ok(serde_json::json!({ "data": receipt }))
+}
+
+#[tauri::command]
+fn confirm_goods_receipt(
+    state: tauri::State<AppState>,
+    token: String,
+    receipt_id: Uuid,
+) -> Result<serde_json::Value, AppError> {
+    let (session, mut conn) = state.authorize(&token)?;
+    let receipt = receipt_service::confirm_goods_receipt(&mut conn, session.client_id, receipt_id)?;
+    Ok(serde_json::json!({ "data": receipt }))
+}
+
+#[tauri::command]
+fn cancel_goods_receipt(
+    state: tauri::State<AppState>,
+    token: String,
+    receipt_id: Uuid,
+) -> Result<serde_json::Value, AppError> {
+    let (session, mut conn) = state.authorize(&token)?;
+    let receipt = receipt_service::cancel_goods_receipt(&mut conn, session.client_id, receipt_id)?;
+    Ok(serde_json::json!({ "data": receipt }))
+}
+
+#[tauri::command]
+fn get_goods_receipt(
+    state: tauri::State<AppState>,
+    token: String,
+    receipt_id: Uuid,
+) -> Result<serde_json::Value, AppError> {
+    let (session, mut conn) = state.authorize(&token)?;
+    let receipt = receipt_service::get_goods_receipt(&mut conn, session.client_id, receipt_id)?;
+    Ok(serde_json::json!({ "data": receipt }))
+}
+
+#[tauri::command]
+fn list_goods_receipts(
+    state: tauri::State<AppState>,
+    token: String,
+    status: Option<ReceiptStatus>,
+) -> Result<serde_json::Value, AppError> {
+    let (session, mut conn) = state.authorize(&token)?;
+    let receipts = receipt_service::list_goods_receipts(&mut conn, session.client_id, status)?;
+    Ok(serde_json::json!({ "receipts": receipts }))
+}
+
+#[tauri::command]
+fn create_shipment(
+    state: tauri::State<AppState>,
+    token: String,
+    shipment: ShipmentInput,
+) -> Result<serde_json::Value, AppError> {
+    let (session, mut conn) = state.authorize(&token)?;
+    let shipment = shipment_service::create_shipment(&mut conn, session.client_id, &shipment)?;
+    Ok(serde_json::json!({ "data": shipment }))
+}
+
+#[tauri::command]
+fn update_shipment(
+    state: tauri::State<AppState>,
+    token: String,
+    shipment_id: Uuid,
+    shipment: ShipmentInput,
+) -> Result<serde_json::Value, AppError> {
+    let (session, mut conn) = state.authorize(&token)?;
+    let shipment =
+        shipment_service::update_shipment(&mut conn, session.client_id, shipment_id, &shipment)?;
+    Ok(serde_json::json!({ "data": shipment }))
+}
+
+#[tauri::command]
+fn dispatch_shipment(
+    state: tauri::State<AppState>,
+    token: String,
+    shipment_id: Uuid,
+) -> Result<serde_json::Value, AppError> {
+    let (session, mut conn) = state.authorize(&token)?;
+    let shipment = shipment_service::dispatch_shipment(&mut conn, session.client_id, shipment_id)?;
+    Ok(serde_json::json!({ "data": shipment }))
+}
+
+#[tauri::command]
+fn cancel_shipment(
+    state: tauri::State<AppState>,
+    token: String,
+    shipment_id: Uuid,
+) -> Result<serde_json::Value, AppError> {
+    let (session, mut conn) = state.authorize(&token)?;
+    let shipment = shipment_service::cancel_shipment(&mut conn, session.client_id, shipment_id)?;
+    Ok(serde_json::json!({ "data": shipment }))
+}
+
+#[tauri::command]
+fn get_shipment(
+    state: tauri::State<AppState>,
+    token: String,
+    shipment_id: Uuid,
+) -> Result<serde_json::Value, AppError> {
+    let (session, mut conn) = state.authorize(&token)?;
+    let shipment = shipment_service::get_shipment(&mut conn, session.client_id, shipment_id)?;
+    Ok(serde_json::json!({ "data": shipment }))
+}
+
+#[tauri::command]
+fn list_shipments(
+    state: tauri::State<AppState>,
+    token: String,
+    status: Option<ShipmentStatus>,
+) -> Result<serde_json::Value, AppError> {
+    let (session, mut conn) = state.authorize(&token)?;
+    let shipments = shipment_service::list_shipments(&mut conn, session.client_id, status)?;
+    Ok(serde_json::json!({ "shipments": shipments }))
+}
+
+#[tauri::command]
+fn get_packing_list(
+    state: tauri::State<AppState>,
+    token: String,
+    shipment_id: Uuid,
+) -> Result<serde_json::Value, AppError> {
+    let (session, mut conn) = state.authorize(&token)?;
+    let packing_list = shipment_service::packing_list(&mut conn, session.client_id, shipment_id)?;
+    Ok(serde_json::json!({ "data": packing_list }))
+}
+
+#[tauri::command]
+fn create_location(
+    state: tauri::State<AppState>,
+    token: String,
+    parent_id: Option<Uuid>,
+    kind: LocationKind,
+    code: String,
+    name: Option<String>,
+) -> Result<serde_json::Value, AppError> {
+    let (session, mut conn) = state.authorize(&token)?;
+    let location = location_service::create_location(
+        &mut conn,
+        session.client_id,
+        parent_id,
+        kind,
+        &code,
+        name.as_deref(),
+    )?;
+    Ok(serde_json::json!({ "location": location }))
+}
+
+#[tauri::command]
+fn list_locations(
+    state: tauri::State<AppState>,
+    token: String,
+) -> Result<serde_json::Value, AppError> {
+    let (session, mut conn) = state.authorize(&token)?;
+    let locations = location_service::list_locations(&mut conn, session.client_id)?;
+    Ok(serde_json::json!({ "locations": locations }))
+}
+
+#[tauri::command]
+fn get_location_stock(
+    state: tauri::State<AppState>,
+    token: String,
+    location_id: Uuid,
+) -> Result<serde_json::Value, AppError> {
+    let (session, mut conn) = state.authorize(&token)?;
+    let stock = location_service::location_stock(&mut conn, session.client_id, location_id)?;
+    Ok(serde_json::json!({ "data": stock }))
+}
+
+#[tauri::command]
+fn move_stock(
+    state: tauri::State<AppState>,
+    token: String,
+    batch_detail_id: Uuid,
+    from_location_id: Option<Uuid>,
+    to_location_id: Option<Uuid>,
+    quantity: i32,
+    unit: QuantityUnit,
+) -> Result<serde_json::Value, AppError> {
+    let (session, mut conn) = state.authorize(&token)?;
+    let (transfer, locations) = location_service::move_stock(
+        &mut conn,
+        session.client_id,
+        batch_detail_id,
+        from_location_id,
+        to_location_id,
+        quantity,
+        unit,
+    )?;
+    Ok(serde_json::json!({ "transfer": transfer, "locations": locations }))
+}
+
+#[tauri::command]
+fn where_is_batch(
+    state: tauri::State<AppState>,
+    token: String,
+    batch_detail_id: Uuid,
+) -> Result<serde_json::Value, AppError> {
+    let (session, mut conn) = state.authorize(&token)?;
+    let locations =
+        location_service::where_is_batch(&mut conn, session.client_id, batch_detail_id)?;
+    Ok(serde_json::json!({ "data": locations }))
+}
+
+#[tauri::command]
+fn get_batch_transfers(
+    state: tauri::State<AppState>,
+    token: String,
+    batch_detail_id: Uuid,
+) -> Result<serde_json::Value, AppError> {
+    let (session, mut conn) = state.authorize(&token)?;
+    let transfers =
+        location_service::batch_transfers(&mut conn, session.client_id, batch_detail_id)?;
+    Ok(serde_json::json!({ "transfers": transfers }))
+}
+
+/// `--migrate-only` applies pending migrations and exits without opening a
+/// window; adding `--dry-run` only lists what would be applied.
+fn migrate_only(dry_run: bool) -> Result<(), String> {
+    let pool = establish_pool()?;
+    let mut conn = pool.get().map_err(|e| e.to_string())?;
+
+    let versions = if dry_run {
+        pending_migrations(&mut conn)?
+    } else {
+        run_pending_migrations(&mut conn)?
+    };
+
+    if versions.is_empty() {
+        println!("Database schema is up to date");
     }
+    for version in versions {
+        if dry_run {
+            println!("Pending migration {}", version);
+        } else {
+            println!("Applied migration {}", version);
+        }
+    }
+    Ok(())
 }
 
 fn main() {
+    let args: Vec<String> = env::args().collect();
+    if args.iter().any(|arg| arg == "--migrate-only") {
+        let dry_run = args.iter().any(|arg| arg == "--dry-run");
+        if let Err(err) = migrate_only(dry_run) {
+            eprintln!("{}", err);
+            process::exit(1);
+        }
+        return;
+    }
+
+    match recover_interrupted_rotation() {
+        Ok(true) => eprintln!("Restored private keys from an interrupted key rotation"),
+        Ok(false) => {}
+        Err(err) => eprintln!(
+            "Failed to recover from an interrupted key rotation: {}",
+            err
+        ),
+    }
+
+    let database = Arc::new(Database::default());
+    let expiry_thresholds = ExpiryThresholds::from_env();
     let state = AppState {
-        conn: Mutex::new(establish_connection()),
+        database: database.clone(),
+        keyring: Keyring::default(),
+        expiry_thresholds: expiry_thresholds.clone(),
     };
 
     tauri::Builder::default()
         .plugin(tauri_plugin_store::Builder::new().build())
-        .setup(|_app| {
+        .plugin(tauri_plugin_notification::init())
+        .setup(move |app| {
+            let app_handle = app.handle().clone();
+            thread::spawn(move || {
+                database.connect_with_retry(|status| {
+                    let _ = app_handle.emit("database-status", status);
+                });
+
+                expiry_service::monitor_expiry(
+                    &database,
+                    &expiry_thresholds,
+                    expiry_service::check_interval_from_env(),
+                    |client_id, alerts| {
+                        let _ = app_handle.emit(
+                            "batch-expiry",
+                            serde_json::json!({ "client_id": client_id, "alerts": alerts }),
+                        );
+
+                        let (title, body) = match alerts {
+                            [alert] => ("Batch expiry".to_string(), alert.message()),
+                            _ => (
+                                format!("{} batches need attention", alerts.len()),
+                                alerts
+                                    .iter()
+                                    .map(|alert| alert.message())
+                                    .collect::<Vec<_>>()
+                                    .join("\n"),
+                            ),
+                        };
+                        let _ = app_handle
+                            .notification()
+                            .builder()
+                            .title(title)
+                            .body(body)
+                            .show();
+                    },
+                );
+            });
+
             // let stores = app.app_handle().state::<StoreCollection<Wry>>();
             // let app_data_dir = get_app_dir();
             // let store_dir = app_data_dir.join("store.bin");
@@ -256,20 +898,65 @@ fn main() {
         })
         .manage(state)
         .invoke_handler(tauri::generate_handler![
+            get_database_status,
+            get_schema_version,
+            get_keyring_status,
+            set_passphrase,
+            unlock_keys,
+            lock_keys,
+            rotate_encryption_key,
+            list_clients,
             create_client,
+            rename_client,
+            remove_client,
+            export_client_identity,
+            import_client_identity,
             sign_in,
             validate_session,
+            sign_out,
+            revoke_all_sessions,
             create_product,
             get_product,
             get_all_products,
             get_all_products_for_client,
+            query_products,
+            search,
+            get_expiry_report,
             update_product,
             delete_product,
+            reconcile_product_totals,
+            get_product_reconciliation,
+            set_product_totals_mode,
             create_batch_details,
             get_batch_detail,
             update_batch_detail,
             delete_batch_detail,
-            fetch_all_batches_for_product
+            fetch_all_batches_for_product,
+            transition_batch_status,
+            get_batch_status_history,
+            fetch_batches_by_status,
+            propose_allocation,
+            post_stock_movement,
+            get_batch_movements,
+            create_goods_receipt,
+            update_goods_receipt,
+            confirm_goods_receipt,
+            cancel_goods_receipt,
+            get_goods_receipt,
+            list_goods_receipts,
+            create_shipment,
+            update_shipment,
+            dispatch_shipment,
+            cancel_shipment,
+            get_shipment,
+            list_shipments,
+            get_packing_list,
+            create_location,
+            list_locations,
+            get_location_stock,
+            move_stock,
+            where_is_batch,
+            get_batch_transfers
         ])
         .run(tauri::generate_context!())
         .expect("error while running tauri application");
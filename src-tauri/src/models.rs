@@ -1,7 +1,19 @@
-use crate::schema::{batch_details, clients, products};
+use crate::schema::{
+    auth_challenges, batch_details, batch_status_history, bin_stock, clients, expiry_alerts,
+    goods_receipt_lines, goods_receipts, locations, products, sessions, shipment_lines, shipments,
+    stock_movements, stock_transfers,
+};
 use chrono::{NaiveDate, NaiveDateTime};
-use diesel::prelude::*;
+use diesel::{
+    deserialize::{self, FromSql, FromSqlRow},
+    expression::AsExpression,
+    pg::{Pg, PgValue},
+    prelude::*,
+    serialize::{self, IsNull, Output, ToSql},
+    sql_types::Text,
+};
 use serde::{Deserialize, Serialize};
+use std::io::Write;
 use uuid::Uuid;
 
 #[derive(Queryable, Selectable, Serialize, Deserialize, Debug)]
@@ -10,12 +22,16 @@ use uuid::Uuid;
 pub struct Client {
     pub id: Uuid,
     pub private_key_path: String,
+    pub public_key: Option<String>,
+    pub name: String,
+    pub created_at: NaiveDateTime,
 }
 
 #[derive(Insertable)]
 #[diesel(table_name = clients)]
 pub struct NewClient<'a> {
-    pub private_key_path: &'a String,
+    pub private_key_path: &'a str,
+    pub name: &'a str,
 }
 
 #[derive(
@@ -32,6 +48,7 @@ pub struct Product {
     pub total_shipper_boxes: i32,
     pub created_at: NaiveDateTime,
     pub updated_at: NaiveDateTime,
+    pub derived_totals: bool,
 }
 
 #[derive(Insertable)]
@@ -50,6 +67,7 @@ pub struct UpdateProduct<'a> {
     pub total_quantity: Option<i32>,
     pub total_shipper_boxes: Option<i32>,
     pub updated_at: Option<NaiveDateTime>,
+    pub derived_totals: Option<bool>,
 }
 
 #[derive(
@@ -72,6 +90,7 @@ pub struct BatchDetail {
     pub total_packs: i32,
     pub created_at: NaiveDateTime,
     pub updated_at: NaiveDateTime,
+    pub status: BatchStatus,
 }
 
 #[derive(Insertable)]
@@ -103,8 +122,569 @@ pub struct UpdateBatchDetail<'a> {
     pub total_packs: Option<i32>,
 }
 
+/// Where a batch is in its lifecycle. Stored as its snake_case name.
+#[derive(AsExpression, FromSqlRow, Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+#[diesel(sql_type = Text)]
+#[serde(rename_all = "snake_case")]
+pub enum BatchStatus {
+    Quarantine,
+    Released,
+    OnHold,
+    Recalled,
+    Expired,
+}
+
+impl BatchStatus {
+    /// Statuses whose batches are counted as stock on hand. Recalled and
+    /// expired batches are written off.
+    pub const IN_STOCK: [BatchStatus; 3] = [
+        BatchStatus::Quarantine,
+        BatchStatus::Released,
+        BatchStatus::OnHold,
+    ];
+
+    pub fn as_str(self) -> &'static str {
+        match self {
+            BatchStatus::Quarantine => "quarantine",
+            BatchStatus::Released => "released",
+            BatchStatus::OnHold => "on_hold",
+            BatchStatus::Recalled => "recalled",
+            BatchStatus::Expired => "expired",
+        }
+    }
+}
+
+impl ToSql<Text, Pg> for BatchStatus {
+    fn to_sql<'b>(&'b self, out: &mut Output<'b, '_, Pg>) -> serialize::Result {
+        out.write_all(self.as_str().as_bytes())?;
+        Ok(IsNull::No)
+    }
+}
+
+impl FromSql<Text, Pg> for BatchStatus {
+    fn from_sql(bytes: PgValue<'_>) -> deserialize::Result<Self> {
+        match bytes.as_bytes() {
+            b"quarantine" => Ok(BatchStatus::Quarantine),
+            b"released" => Ok(BatchStatus::Released),
+            b"on_hold" => Ok(BatchStatus::OnHold),
+            b"recalled" => Ok(BatchStatus::Recalled),
+            b"expired" => Ok(BatchStatus::Expired),
+            other => Err(format!("Unknown batch status {}", String::from_utf8_lossy(other)).into()),
+        }
+    }
+}
+
 #[derive(Serialize)]
 pub struct ProductWithBatches {
     pub product: Product,
     pub batch_details: Vec<BatchDetail>,
 }
+
+#[derive(Queryable, Selectable, Identifiable, Associations, Debug)]
+#[diesel(belongs_to(Client))]
+#[diesel(table_name = sessions)]
+#[diesel(check_for_backend(diesel::pg::Pg))]
+pub struct SessionRecord {
+    pub id: Uuid,
+    pub client_id: Uuid,
+    pub token_hash: String,
+    pub created_at: NaiveDateTime,
+    pub last_seen_at: NaiveDateTime,
+    pub expires_at: NaiveDateTime,
+    pub revoked_at: Option<NaiveDateTime>,
+}
+
+#[derive(Insertable)]
+#[diesel(table_name = sessions)]
+pub struct NewSessionRecord<'a> {
+    pub client_id: Uuid,
+    pub token_hash: &'a str,
+    pub expires_at: NaiveDateTime,
+}
+
+#[derive(Queryable, Selectable, Identifiable, Associations, Debug)]
+#[diesel(belongs_to(Client))]
+#[diesel(table_name = auth_challenges)]
+#[diesel(check_for_backend(diesel::pg::Pg))]
+pub struct AuthChallenge {
+    pub id: Uuid,
+    pub client_id: Uuid,
+    pub nonce: String,
+    pub created_at: NaiveDateTime,
+    pub expires_at: NaiveDateTime,
+    pub used_at: Option<NaiveDateTime>,
+}
+
+#[derive(Insertable)]
+#[diesel(table_name = auth_challenges)]
+pub struct NewAuthChallenge<'a> {
+    pub client_id: Uuid,
+    pub nonce: &'a str,
+    pub expires_at: NaiveDateTime,
+}
+
+/// Records that a batch was reported as having entered an expiry window,
+/// so each window is only reported once per expiry date.
+#[derive(Insertable)]
+#[diesel(table_name = expiry_alerts)]
+pub struct NewExpiryAlert {
+    pub batch_id: Uuid,
+    pub threshold_days: i32,
+    pub exp_date: NaiveDate,
+}
+
+/// One entry of a batch's status history: who moved it, when and why.
+#[derive(Queryable, Selectable, Serialize, Debug)]
+#[diesel(table_name = batch_status_history)]
+#[diesel(check_for_backend(diesel::pg::Pg))]
+pub struct BatchStatusChange {
+    pub id: Uuid,
+    pub batch_id: Uuid,
+    pub from_status: BatchStatus,
+    pub to_status: BatchStatus,
+    pub reason: String,
+    pub changed_by: Uuid,
+    pub changed_at: NaiveDateTime,
+}
+
+#[derive(Insertable)]
+#[diesel(table_name = batch_status_history)]
+pub struct NewBatchStatusChange<'a> {
+    pub batch_id: Uuid,
+    pub from_status: BatchStatus,
+    pub to_status: BatchStatus,
+    pub reason: &'a str,
+    pub changed_by: Uuid,
+}
+
+/// Why stock moved in or out of a batch. Stored as its snake_case name.
+#[derive(AsExpression, FromSqlRow, Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+#[diesel(sql_type = Text)]
+#[serde(rename_all = "snake_case")]
+pub enum MovementType {
+    Receipt,
+    Dispatch,
+    Adjustment,
+    WriteOff,
+}
+
+impl MovementType {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            MovementType::Receipt => "receipt",
+            MovementType::Dispatch => "dispatch",
+            MovementType::Adjustment => "adjustment",
+            MovementType::WriteOff => "write_off",
+        }
+    }
+}
+
+impl ToSql<Text, Pg> for MovementType {
+    fn to_sql<'b>(&'b self, out: &mut Output<'b, '_, Pg>) -> serialize::Result {
+        out.write_all(self.as_str().as_bytes())?;
+        Ok(IsNull::No)
+    }
+}
+
+impl FromSql<Text, Pg> for MovementType {
+    fn from_sql(bytes: PgValue<'_>) -> deserialize::Result<Self> {
+        match bytes.as_bytes() {
+            b"receipt" => Ok(MovementType::Receipt),
+            b"dispatch" => Ok(MovementType::Dispatch),
+            b"adjustment" => Ok(MovementType::Adjustment),
+            b"write_off" => Ok(MovementType::WriteOff),
+            other => {
+                Err(format!("Unknown movement type {}", String::from_utf8_lossy(other)).into())
+            }
+        }
+    }
+}
+
+/// The unit a stock quantity is counted in.
+#[derive(AsExpression, FromSqlRow, Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+#[diesel(sql_type = Text)]
+#[serde(rename_all = "snake_case")]
+pub enum QuantityUnit {
+    Boxes,
+    Packs,
+    Units,
+}
+
+impl QuantityUnit {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            QuantityUnit::Boxes => "boxes",
+            QuantityUnit::Packs => "packs",
+            QuantityUnit::Units => "units",
+        }
+    }
+}
+
+impl ToSql<Text, Pg> for QuantityUnit {
+    fn to_sql<'b>(&'b self, out: &mut Output<'b, '_, Pg>) -> serialize::Result {
+        out.write_all(self.as_str().as_bytes())?;
+        Ok(IsNull::No)
+    }
+}
+
+impl FromSql<Text, Pg> for QuantityUnit {
+    fn from_sql(bytes: PgValue<'_>) -> deserialize::Result<Self> {
+        match bytes.as_bytes() {
+            b"boxes" => Ok(QuantityUnit::Boxes),
+            b"packs" => Ok(QuantityUnit::Packs),
+            b"units" => Ok(QuantityUnit::Units),
+            other => {
+                Err(format!("Unknown quantity unit {}", String::from_utf8_lossy(other)).into())
+            }
+        }
+    }
+}
+
+/// One line of a batch's stock ledger. `quantity` is as entered, in `unit`,
+/// and signed like `units_delta`, the same change counted in units.
+#[derive(Queryable, Selectable, Serialize, Debug)]
+#[diesel(table_name = stock_movements)]
+#[diesel(check_for_backend(diesel::pg::Pg))]
+pub struct StockMovement {
+    pub id: Uuid,
+    pub batch_id: Uuid,
+    pub movement_type: MovementType,
+    pub quantity: i32,
+    pub unit: QuantityUnit,
+    pub units_delta: i64,
+    pub reference: Option<String>,
+    pub created_by: Uuid,
+    pub created_at: NaiveDateTime,
+}
+
+#[derive(Insertable)]
+#[diesel(table_name = stock_movements)]
+pub struct NewStockMovement<'a> {
+    pub batch_id: Uuid,
+    pub movement_type: MovementType,
+    pub quantity: i32,
+    pub unit: QuantityUnit,
+    pub units_delta: i64,
+    pub reference: Option<&'a str>,
+    pub created_by: Uuid,
+}
+
+/// Where an inbound receipt is in its workflow. Only drafts can be edited;
+/// confirming one books its lines into stock. Stored as its snake_case name.
+#[derive(AsExpression, FromSqlRow, Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+#[diesel(sql_type = Text)]
+#[serde(rename_all = "snake_case")]
+pub enum ReceiptStatus {
+    Draft,
+    Confirmed,
+    Cancelled,
+}
+
+impl ReceiptStatus {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            ReceiptStatus::Draft => "draft",
+            ReceiptStatus::Confirmed => "confirmed",
+            ReceiptStatus::Cancelled => "cancelled",
+        }
+    }
+}
+
+impl ToSql<Text, Pg> for ReceiptStatus {
+    fn to_sql<'b>(&'b self, out: &mut Output<'b, '_, Pg>) -> serialize::Result {
+        out.write_all(self.as_str().as_bytes())?;
+        Ok(IsNull::No)
+    }
+}
+
+impl FromSql<Text, Pg> for ReceiptStatus {
+    fn from_sql(bytes: PgValue<'_>) -> deserialize::Result<Self> {
+        match bytes.as_bytes() {
+            b"draft" => Ok(ReceiptStatus::Draft),
+            b"confirmed" => Ok(ReceiptStatus::Confirmed),
+            b"cancelled" => Ok(ReceiptStatus::Cancelled),
+            other => {
+                Err(format!("Unknown receipt status {}", String::from_utf8_lossy(other)).into())
+            }
+        }
+    }
+}
+
+#[derive(Queryable, Selectable, Identifiable, Associations, Serialize, Debug)]
+#[diesel(belongs_to(Client))]
+#[diesel(table_name = goods_receipts)]
+#[diesel(check_for_backend(diesel::pg::Pg))]
+pub struct GoodsReceipt {
+    pub id: Uuid,
+    pub client_id: Uuid,
+    pub supplier: String,
+    pub delivery_note: String,
+    pub received_date: NaiveDate,
+    pub status: ReceiptStatus,
+    pub created_at: NaiveDateTime,
+    pub updated_at: NaiveDateTime,
+    pub confirmed_at: Option<NaiveDateTime>,
+    pub cancelled_at: Option<NaiveDateTime>,
+}
+
+#[derive(Insertable)]
+#[diesel(table_name = goods_receipts)]
+pub struct NewGoodsReceipt<'a> {
+    pub client_id: Uuid,
+    pub supplier: &'a str,
+    pub delivery_note: &'a str,
+    pub received_date: NaiveDate,
+}
+
+/// One batch delivered on a receipt. `batch_id` is set once the receipt is
+/// confirmed, to the batch the line created or topped up.
+#[derive(Queryable, Selectable, Identifiable, Associations, Serialize, Debug)]
+#[diesel(belongs_to(GoodsReceipt, foreign_key = receipt_id))]
+#[diesel(table_name = goods_receipt_lines)]
+#[diesel(check_for_backend(diesel::pg::Pg))]
+pub struct GoodsReceiptLine {
+    pub id: Uuid,
+    pub receipt_id: Uuid,
+    pub line_no: i32,
+    pub product_id: Uuid,
+    pub batch_no: String,
+    pub mfg_date: NaiveDate,
+    pub exp_date: NaiveDate,
+    pub boxes: i32,
+    pub units_per_box: i32,
+    pub units_per_pack: i32,
+    pub packs_per_box: i32,
+    pub packages_configuration: String,
+    pub total_packs: i32,
+    pub batch_id: Option<Uuid>,
+}
+
+#[derive(Insertable)]
+#[diesel(table_name = goods_receipt_lines)]
+pub struct NewGoodsReceiptLine<'a> {
+    pub receipt_id: Uuid,
+    pub line_no: i32,
+    pub product_id: Uuid,
+    pub batch_no: &'a str,
+    pub mfg_date: NaiveDate,
+    pub exp_date: NaiveDate,
+    pub boxes: i32,
+    pub units_per_box: i32,
+    pub units_per_pack: i32,
+    pub packs_per_box: i32,
+    pub packages_configuration: &'a str,
+    pub total_packs: i32,
+}
+
+/// Where an outbound shipment is in its workflow. Only drafts can be
+/// edited; dispatching one takes its lines out of stock. Stored as its
+/// snake_case name.
+#[derive(AsExpression, FromSqlRow, Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+#[diesel(sql_type = Text)]
+#[serde(rename_all = "snake_case")]
+pub enum ShipmentStatus {
+    Draft,
+    Dispatched,
+    Cancelled,
+}
+
+impl ShipmentStatus {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            ShipmentStatus::Draft => "draft",
+            ShipmentStatus::Dispatched => "dispatched",
+            ShipmentStatus::Cancelled => "cancelled",
+        }
+    }
+}
+
+impl ToSql<Text, Pg> for ShipmentStatus {
+    fn to_sql<'b>(&'b self, out: &mut Output<'b, '_, Pg>) -> serialize::Result {
+        out.write_all(self.as_str().as_bytes())?;
+        Ok(IsNull::No)
+    }
+}
+
+impl FromSql<Text, Pg> for ShipmentStatus {
+    fn from_sql(bytes: PgValue<'_>) -> deserialize::Result<Self> {
+        match bytes.as_bytes() {
+            b"draft" => Ok(ShipmentStatus::Draft),
+            b"dispatched" => Ok(ShipmentStatus::Dispatched),
+            b"cancelled" => Ok(ShipmentStatus::Cancelled),
+            other => {
+                Err(format!("Unknown shipment status {}", String::from_utf8_lossy(other)).into())
+            }
+        }
+    }
+}
+
+#[derive(Queryable, Selectable, Identifiable, Associations, Serialize, Debug)]
+#[diesel(belongs_to(Client))]
+#[diesel(table_name = shipments)]
+#[diesel(check_for_backend(diesel::pg::Pg))]
+pub struct Shipment {
+    pub id: Uuid,
+    pub client_id: Uuid,
+    pub consignee: String,
+    pub ship_date: NaiveDate,
+    pub carrier: Option<String>,
+    pub reference: String,
+    pub status: ShipmentStatus,
+    pub created_at: NaiveDateTime,
+    pub updated_at: NaiveDateTime,
+    pub dispatched_at: Option<NaiveDateTime>,
+    pub cancelled_at: Option<NaiveDateTime>,
+}
+
+#[derive(Insertable)]
+#[diesel(table_name = shipments)]
+pub struct NewShipment<'a> {
+    pub client_id: Uuid,
+    pub consignee: &'a str,
+    pub ship_date: NaiveDate,
+    pub carrier: Option<&'a str>,
+    pub reference: &'a str,
+}
+
+/// A quantity of one batch on a shipment, in `unit`.
+#[derive(Queryable, Selectable, Identifiable, Associations, Serialize, Debug)]
+#[diesel(belongs_to(Shipment))]
+#[diesel(table_name = shipment_lines)]
+#[diesel(check_for_backend(diesel::pg::Pg))]
+pub struct ShipmentLine {
+    pub id: Uuid,
+    pub shipment_id: Uuid,
+    pub line_no: i32,
+    pub batch_id: Uuid,
+    pub quantity: i32,
+    pub unit: QuantityUnit,
+}
+
+#[derive(Insertable)]
+#[diesel(table_name = shipment_lines)]
+pub struct NewShipmentLine {
+    pub shipment_id: Uuid,
+    pub line_no: i32,
+    pub batch_id: Uuid,
+    pub quantity: i32,
+    pub unit: QuantityUnit,
+}
+
+/// Level of a storage location. Each level sits inside the one before it,
+/// and stock is only ever kept in bins. Stored as its snake_case name.
+#[derive(AsExpression, FromSqlRow, Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+#[diesel(sql_type = Text)]
+#[serde(rename_all = "snake_case")]
+pub enum LocationKind {
+    Warehouse,
+    Zone,
+    Rack,
+    Bin,
+}
+
+impl LocationKind {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            LocationKind::Warehouse => "warehouse",
+            LocationKind::Zone => "zone",
+            LocationKind::Rack => "rack",
+            LocationKind::Bin => "bin",
+        }
+    }
+
+    /// The kind of location this one must be placed in; warehouses have
+    /// no parent.
+    pub fn parent_kind(self) -> Option<LocationKind> {
+        match self {
+            LocationKind::Warehouse => None,
+            LocationKind::Zone => Some(LocationKind::Warehouse),
+            LocationKind::Rack => Some(LocationKind::Zone),
+            LocationKind::Bin => Some(LocationKind::Rack),
+        }
+    }
+}
+
+impl ToSql<Text, Pg> for LocationKind {
+    fn to_sql<'b>(&'b self, out: &mut Output<'b, '_, Pg>) -> serialize::Result {
+        out.write_all(self.as_str().as_bytes())?;
+        Ok(IsNull::No)
+    }
+}
+
+impl FromSql<Text, Pg> for LocationKind {
+    fn from_sql(bytes: PgValue<'_>) -> deserialize::Result<Self> {
+        match bytes.as_bytes() {
+            b"warehouse" => Ok(LocationKind::Warehouse),
+            b"zone" => Ok(LocationKind::Zone),
+            b"rack" => Ok(LocationKind::Rack),
+            b"bin" => Ok(LocationKind::Bin),
+            other => {
+                Err(format!("Unknown location kind {}", String::from_utf8_lossy(other)).into())
+            }
+        }
+    }
+}
+
+#[derive(Queryable, Selectable, Identifiable, Associations, Serialize, Clone, Debug)]
+#[diesel(belongs_to(Client))]
+#[diesel(table_name = locations)]
+#[diesel(check_for_backend(diesel::pg::Pg))]
+pub struct Location {
+    pub id: Uuid,
+    pub client_id: Uuid,
+    pub parent_id: Option<Uuid>,
+    pub kind: LocationKind,
+    pub code: String,
+    pub name: Option<String>,
+    pub created_at: NaiveDateTime,
+}
+
+#[derive(Insertable)]
+#[diesel(table_name = locations)]
+pub struct NewLocation<'a> {
+    pub client_id: Uuid,
+    pub parent_id: Option<Uuid>,
+    pub kind: LocationKind,
+    pub code: &'a str,
+    pub name: Option<&'a str>,
+}
+
+#[derive(Queryable, Selectable, Serialize, Debug)]
+#[diesel(table_name = bin_stock)]
+#[diesel(check_for_backend(diesel::pg::Pg))]
+pub struct BinStock {
+    pub batch_id: Uuid,
+    pub location_id: Uuid,
+    pub units: i64,
+    pub updated_at: NaiveDateTime,
+}
+
+/// Stock of a batch moved between bins. A missing `from_location_id` is a
+/// put-away of stock that was in no bin; a missing `to_location_id` takes
+/// stock out of the bins, e.g. when it is staged for dispatch.
+#[derive(Queryable, Selectable, Serialize, Debug)]
+#[diesel(table_name = stock_transfers)]
+#[diesel(check_for_backend(diesel::pg::Pg))]
+pub struct StockTransfer {
+    pub id: Uuid,
+    pub batch_id: Uuid,
+    pub from_location_id: Option<Uuid>,
+    pub to_location_id: Option<Uuid>,
+    pub quantity: i32,
+    pub unit: QuantityUnit,
+    pub units: i64,
+    pub moved_by: Uuid,
+    pub moved_at: NaiveDateTime,
+}
+
+#[derive(Insertable)]
+#[diesel(table_name = stock_transfers)]
+pub struct NewStockTransfer {
+    pub batch_id: Uuid,
+    pub from_location_id: Option<Uuid>,
+    pub to_location_id: Option<Uuid>,
+    pub quantity: i32,
+    pub unit: QuantityUnit,
+    pub units: i64,
+    pub moved_by: Uuid,
+}
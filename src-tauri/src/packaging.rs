@@ -0,0 +1,287 @@
+use serde::Serialize;
+use std::{fmt, str::FromStr};
+
+/// Box layout in the `layers x rows x packs x units` format entered on the
+/// batch form, e.g. `4 x 5 x 6 x 10` is 4 layers of 5 rows of 6 packs, each
+/// pack holding 10 units.
+#[derive(Serialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PackagingConfiguration {
+    pub layers: i32,
+    pub rows_per_layer: i32,
+    pub packs_per_row: i32,
+    pub units_per_pack: i32,
+}
+
+#[derive(Serialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PackagingTotals {
+    pub packs_per_box: i32,
+    pub units_per_box: i32,
+    pub total_packs: i32,
+    pub total_units: i64,
+}
+
+impl FromStr for PackagingConfiguration {
+    type Err = String;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        let parts: Vec<&str> = value.split(['x', 'X']).map(str::trim).collect();
+        if parts.len() != 4 {
+            return Err(
+                "Package configuration must be in format: 'layers x rows x packs x units'"
+                    .to_string(),
+            );
+        }
+
+        let mut numbers = [0; 4];
+        for (number, part) in numbers.iter_mut().zip(&parts) {
+            *number = match part.parse::<i32>() {
+                Ok(parsed) if parsed > 0 => parsed,
+                _ => return Err(format!("Invalid number in configuration: {}", part)),
+            };
+        }
+
+        let configuration = PackagingConfiguration {
+            layers: numbers[0],
+            rows_per_layer: numbers[1],
+            packs_per_row: numbers[2],
+            units_per_pack: numbers[3],
+        };
+        configuration.units_per_box()?;
+        Ok(configuration)
+    }
+}
+
+impl fmt::Display for PackagingConfiguration {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{} x {} x {} x {}",
+            self.layers, self.rows_per_layer, self.packs_per_row, self.units_per_pack
+        )
+    }
+}
+
+fn too_large() -> String {
+    "Package configuration is too large".to_string()
+}
+
+impl PackagingConfiguration {
+    pub fn packs_per_box(&self) -> Result<i32, String> {
+        self.layers
+            .checked_mul(self.rows_per_layer)
+            .and_then(|packs| packs.checked_mul(self.packs_per_row))
+            .ok_or_else(too_large)
+    }
+
+    pub fn units_per_box(&self) -> Result<i32, String> {
+        self.packs_per_box()?
+            .checked_mul(self.units_per_pack)
+            .ok_or_else(too_large)
+    }
+
+    pub fn totals(&self, boxes: i32) -> Result<PackagingTotals, String> {
+        let packs_per_box = self.packs_per_box()?;
+        let units_per_box = self.units_per_box()?;
+
+        Ok(PackagingTotals {
+            packs_per_box,
+            units_per_box,
+            total_packs: packs_per_box.checked_mul(boxes).ok_or_else(too_large)?,
+            total_units: i64::from(units_per_box) * i64::from(boxes),
+        })
+    }
+
+    /// Compares the quantities a client sent against the ones derived from
+    /// this configuration, returning `(field, reason)` for each disagreement.
+    pub fn mismatches(
+        &self,
+        boxes: i32,
+        units_per_pack: i32,
+        packs_per_box: i32,
+        units_per_box: i32,
+        total_packs: i32,
+    ) -> Vec<(&'static str, String)> {
+        let totals = match self.totals(boxes) {
+            Ok(totals) => totals,
+            Err(reason) => return vec![("packages_configuration", reason)],
+        };
+
+        let mut mismatches = Vec::new();
+        let mut check = |field: &'static str, label: &str, claimed: i32, expected: i32| {
+            if claimed != expected {
+                mismatches.push((
+                    field,
+                    format!(
+                        "{} mismatch: configuration {} gives {}, but {} was sent",
+                        label, self, expected, claimed
+                    ),
+                ));
+            }
+        };
+        check(
+            "units_per_pack",
+            "Units per pack",
+            units_per_pack,
+            self.units_per_pack,
+        );
+        check(
+            "packs_per_box",
+            "Packs per box",
+            packs_per_box,
+            totals.packs_per_box,
+        );
+        check(
+            "units_per_box",
+            "Units per box",
+            units_per_box,
+            totals.units_per_box,
+        );
+        check(
+            "total_packs",
+            "Total packs",
+            total_packs,
+            totals.total_packs,
+        );
+
+        mismatches
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn configuration() -> PackagingConfiguration {
+        "4 x 5 x 6 x 10".parse().unwrap()
+    }
+
+    #[test]
+    fn parses_either_separator_case() {
+        let expected = PackagingConfiguration {
+            layers: 4,
+            rows_per_layer: 5,
+            packs_per_row: 6,
+            units_per_pack: 10,
+        };
+
+        assert_eq!("4 x 5 x 6 x 10".parse(), Ok(expected));
+        assert_eq!("4X5X6X10".parse(), Ok(expected));
+        assert_eq!(" 4 X 5 x 6 X 10 ".parse(), Ok(expected));
+    }
+
+    #[test]
+    fn rejects_zero_and_negative_parts() {
+        for value in ["0 x 5 x 6 x 10", "4 x 5 x 6 x 0", "4 x -5 x 6 x 10"] {
+            assert!(
+                value
+                    .parse::<PackagingConfiguration>()
+                    .unwrap_err()
+                    .starts_with("Invalid number in configuration"),
+                "{} should be rejected",
+                value
+            );
+        }
+    }
+
+    #[test]
+    fn rejects_non_numeric_parts() {
+        for value in ["4 x five x 6 x 10", "4 x 5 x 6 x ", "4 x 5.5 x 6 x 10"] {
+            assert!(value.parse::<PackagingConfiguration>().is_err());
+        }
+    }
+
+    #[test]
+    fn rejects_wrong_number_of_parts() {
+        for value in ["", "4 x 5 x 6", "4 x 5 x 6 x 10 x 2"] {
+            assert!(
+                value
+                    .parse::<PackagingConfiguration>()
+                    .unwrap_err()
+                    .starts_with("Package configuration must be in format"),
+                "{} should be rejected",
+                value
+            );
+        }
+    }
+
+    #[test]
+    fn rejects_configurations_that_overflow() {
+        assert_eq!(
+            "2000 x 2000 x 2000 x 1".parse::<PackagingConfiguration>(),
+            Err(too_large())
+        );
+    }
+
+    #[test]
+    fn totals_follow_the_configuration() {
+        assert_eq!(
+            configuration().totals(3),
+            Ok(PackagingTotals {
+                packs_per_box: 120,
+                units_per_box: 1200,
+                total_packs: 360,
+                total_units: 3600,
+            })
+        );
+    }
+
+    #[test]
+    fn matching_quantities_have_no_mismatches() {
+        assert!(configuration().mismatches(3, 10, 120, 1200, 360).is_empty());
+    }
+
+    #[test]
+    fn reports_each_mismatch_by_field() {
+        let fields = |mismatches: Vec<(&'static str, String)>| {
+            mismatches
+                .into_iter()
+                .map(|(field, _)| field)
+                .collect::<Vec<_>>()
+        };
+        let configuration = configuration();
+
+        assert_eq!(
+            fields(configuration.mismatches(3, 12, 120, 1200, 360)),
+            ["units_per_pack"]
+        );
+        assert_eq!(
+            fields(configuration.mismatches(3, 10, 100, 1200, 360)),
+            ["packs_per_box"]
+        );
+        assert_eq!(
+            fields(configuration.mismatches(3, 10, 120, 1000, 360)),
+            ["units_per_box"]
+        );
+        assert_eq!(
+            fields(configuration.mismatches(3, 10, 120, 1200, 300)),
+            ["total_packs"]
+        );
+        assert_eq!(
+            fields(configuration.mismatches(3, 1, 1, 1, 1)),
+            [
+                "units_per_pack",
+                "packs_per_box",
+                "units_per_box",
+                "total_packs"
+            ]
+        );
+    }
+
+    #[test]
+    fn mismatch_reason_names_the_configuration() {
+        let mismatches = configuration().mismatches(3, 12, 120, 1200, 360);
+
+        assert_eq!(
+            mismatches[0].1,
+            "Units per pack mismatch: configuration 4 x 5 x 6 x 10 gives 10, but 12 was sent"
+        );
+    }
+
+    #[test]
+    fn overflowing_totals_are_reported_against_the_configuration() {
+        assert_eq!(
+            configuration().mismatches(i32::MAX, 10, 120, 1200, 360),
+            [("packages_configuration", too_large())]
+        );
+    }
+}
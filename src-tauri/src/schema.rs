@@ -1,5 +1,17 @@
 // @generated automatically by Diesel CLI.
 
+diesel::table! {
+    auth_challenges (id) {
+        id -> Uuid,
+        client_id -> Uuid,
+        #[max_length = 64]
+        nonce -> Varchar,
+        created_at -> Timestamptz,
+        expires_at -> Timestamptz,
+        used_at -> Nullable<Timestamptz>,
+    }
+}
+
 diesel::table! {
     batch_details (id) {
         id -> Uuid,
@@ -17,6 +29,31 @@ diesel::table! {
         total_packs -> Int4,
         created_at -> Timestamptz,
         updated_at -> Timestamptz,
+        #[max_length = 20]
+        status -> Varchar,
+    }
+}
+
+diesel::table! {
+    batch_status_history (id) {
+        id -> Uuid,
+        batch_id -> Uuid,
+        #[max_length = 20]
+        from_status -> Varchar,
+        #[max_length = 20]
+        to_status -> Varchar,
+        reason -> Text,
+        changed_by -> Uuid,
+        changed_at -> Timestamptz,
+    }
+}
+
+diesel::table! {
+    bin_stock (batch_id, location_id) {
+        batch_id -> Uuid,
+        location_id -> Uuid,
+        units -> Int8,
+        updated_at -> Timestamptz,
     }
 }
 
@@ -24,6 +61,73 @@ diesel::table! {
     clients (id) {
         id -> Uuid,
         private_key_path -> Text,
+        public_key -> Nullable<Text>,
+        #[max_length = 100]
+        name -> Varchar,
+        created_at -> Timestamptz,
+    }
+}
+
+diesel::table! {
+    expiry_alerts (batch_id, threshold_days, exp_date) {
+        batch_id -> Uuid,
+        threshold_days -> Int4,
+        exp_date -> Date,
+        alerted_at -> Timestamptz,
+    }
+}
+
+diesel::table! {
+    goods_receipt_lines (id) {
+        id -> Uuid,
+        receipt_id -> Uuid,
+        line_no -> Int4,
+        product_id -> Uuid,
+        #[max_length = 50]
+        batch_no -> Varchar,
+        mfg_date -> Date,
+        exp_date -> Date,
+        boxes -> Int4,
+        units_per_box -> Int4,
+        units_per_pack -> Int4,
+        packs_per_box -> Int4,
+        #[max_length = 255]
+        packages_configuration -> Varchar,
+        total_packs -> Int4,
+        batch_id -> Nullable<Uuid>,
+    }
+}
+
+diesel::table! {
+    goods_receipts (id) {
+        id -> Uuid,
+        client_id -> Uuid,
+        #[max_length = 255]
+        supplier -> Varchar,
+        #[max_length = 100]
+        delivery_note -> Varchar,
+        received_date -> Date,
+        #[max_length = 20]
+        status -> Varchar,
+        created_at -> Timestamptz,
+        updated_at -> Timestamptz,
+        confirmed_at -> Nullable<Timestamptz>,
+        cancelled_at -> Nullable<Timestamptz>,
+    }
+}
+
+diesel::table! {
+    locations (id) {
+        id -> Uuid,
+        client_id -> Uuid,
+        parent_id -> Nullable<Uuid>,
+        #[max_length = 20]
+        kind -> Varchar,
+        #[max_length = 50]
+        code -> Varchar,
+        #[max_length = 255]
+        name -> Nullable<Varchar>,
+        created_at -> Timestamptz,
     }
 }
 
@@ -37,14 +141,123 @@ diesel::table! {
         total_shipper_boxes -> Int4,
         created_at -> Timestamptz,
         updated_at -> Timestamptz,
+        derived_totals -> Bool,
+    }
+}
+
+diesel::table! {
+    sessions (id) {
+        id -> Uuid,
+        client_id -> Uuid,
+        #[max_length = 64]
+        token_hash -> Varchar,
+        created_at -> Timestamptz,
+        last_seen_at -> Timestamptz,
+        expires_at -> Timestamptz,
+        revoked_at -> Nullable<Timestamptz>,
+    }
+}
+
+diesel::table! {
+    shipment_lines (id) {
+        id -> Uuid,
+        shipment_id -> Uuid,
+        line_no -> Int4,
+        batch_id -> Uuid,
+        quantity -> Int4,
+        #[max_length = 10]
+        unit -> Varchar,
+    }
+}
+
+diesel::table! {
+    shipments (id) {
+        id -> Uuid,
+        client_id -> Uuid,
+        #[max_length = 255]
+        consignee -> Varchar,
+        ship_date -> Date,
+        #[max_length = 100]
+        carrier -> Nullable<Varchar>,
+        #[max_length = 100]
+        reference -> Varchar,
+        #[max_length = 20]
+        status -> Varchar,
+        created_at -> Timestamptz,
+        updated_at -> Timestamptz,
+        dispatched_at -> Nullable<Timestamptz>,
+        cancelled_at -> Nullable<Timestamptz>,
+    }
+}
+
+diesel::table! {
+    stock_movements (id) {
+        id -> Uuid,
+        batch_id -> Uuid,
+        #[max_length = 20]
+        movement_type -> Varchar,
+        quantity -> Int4,
+        #[max_length = 10]
+        unit -> Varchar,
+        units_delta -> Int8,
+        #[max_length = 100]
+        reference -> Nullable<Varchar>,
+        created_by -> Uuid,
+        created_at -> Timestamptz,
+    }
+}
+
+diesel::table! {
+    stock_transfers (id) {
+        id -> Uuid,
+        batch_id -> Uuid,
+        from_location_id -> Nullable<Uuid>,
+        to_location_id -> Nullable<Uuid>,
+        quantity -> Int4,
+        #[max_length = 10]
+        unit -> Varchar,
+        units -> Int8,
+        moved_by -> Uuid,
+        moved_at -> Timestamptz,
     }
 }
 
+diesel::joinable!(auth_challenges -> clients (client_id));
 diesel::joinable!(batch_details -> products (product_id));
+diesel::joinable!(batch_status_history -> batch_details (batch_id));
+diesel::joinable!(batch_status_history -> clients (changed_by));
+diesel::joinable!(bin_stock -> batch_details (batch_id));
+diesel::joinable!(bin_stock -> locations (location_id));
+diesel::joinable!(expiry_alerts -> batch_details (batch_id));
+diesel::joinable!(goods_receipt_lines -> batch_details (batch_id));
+diesel::joinable!(goods_receipt_lines -> goods_receipts (receipt_id));
+diesel::joinable!(goods_receipt_lines -> products (product_id));
+diesel::joinable!(goods_receipts -> clients (client_id));
+diesel::joinable!(locations -> clients (client_id));
 diesel::joinable!(products -> clients (client_id));
+diesel::joinable!(sessions -> clients (client_id));
+diesel::joinable!(shipment_lines -> batch_details (batch_id));
+diesel::joinable!(shipment_lines -> shipments (shipment_id));
+diesel::joinable!(shipments -> clients (client_id));
+diesel::joinable!(stock_movements -> batch_details (batch_id));
+diesel::joinable!(stock_movements -> clients (created_by));
+diesel::joinable!(stock_transfers -> batch_details (batch_id));
+diesel::joinable!(stock_transfers -> clients (moved_by));
 
 diesel::allow_tables_to_appear_in_same_query!(
+    auth_challenges,
     batch_details,
+    batch_status_history,
+    bin_stock,
     clients,
+    expiry_alerts,
+    goods_receipt_lines,
+    goods_receipts,
+    locations,
     products,
+    sessions,
+    shipment_lines,
+    shipments,
+    stock_movements,
+    stock_transfers,
 );
@@ -1,5 +1,14 @@
+pub mod allocation_service;
 pub mod batch_details_service;
+pub mod batch_status_service;
 pub mod client_service;
+pub mod expiry_service;
 pub mod key_management_service;
+pub mod location_service;
 pub mod product_service;
+pub mod receipt_service;
+pub mod reconciliation_service;
+pub mod search_service;
 pub mod session_management_service;
+pub mod shipment_service;
+pub mod stock_service;
@@ -0,0 +1,362 @@
+use app::{AppError, DbConnection};
+use chrono::{NaiveDate, TimeDelta, Utc};
+use diesel::{ExpressionMethods, QueryDsl, RunQueryDsl, SelectableHelper};
+use serde::Serialize;
+use uuid::Uuid;
+
+use super::{
+    product_service::get_product,
+    stock_service::{stock_levels, units_in, PackedQuantity, StockLevel},
+};
+use crate::models::{BatchDetail, BatchStatus, QuantityUnit};
+use crate::schema::batch_details;
+
+/// What to take from one batch. `quantity` is in the requested unit; the
+/// rest breaks it down for the picker into full boxes, loose packs and, for
+/// unit picks, units from an opened pack.
+#[derive(Serialize)]
+pub struct PickLine {
+    pub batch_id: Uuid,
+    pub batch_no: String,
+    pub exp_date: NaiveDate,
+    pub quantity: i64,
+    pub boxes: i64,
+    pub loose_packs: i64,
+    pub loose_units: i64,
+}
+
+#[derive(Serialize)]
+pub struct SkippedBatch {
+    pub batch_id: Uuid,
+    pub batch_no: String,
+    pub reason: String,
+}
+
+/// A proposed first-expiry-first-out pick. Nothing is reserved: the
+/// proposal reflects the stock on hand at the time it was made.
+#[derive(Serialize)]
+pub struct Allocation {
+    pub product_id: Uuid,
+    pub unit: QuantityUnit,
+    pub requested: i64,
+    pub allocated: i64,
+    pub shortfall: i64,
+    pub lines: Vec<PickLine>,
+    pub skipped: Vec<SkippedBatch>,
+}
+
+fn pick_line(batch: &BatchDetail, quantity: i64, unit: QuantityUnit) -> PickLine {
+    let packed = PackedQuantity::new(batch, quantity * units_in(batch, unit));
+
+    PickLine {
+        batch_id: batch.id,
+        batch_no: batch.batch_no.clone(),
+        exp_date: batch.exp_date,
+        quantity,
+        boxes: packed.boxes,
+        loose_packs: packed.loose_packs,
+        loose_units: packed.loose_units,
+    }
+}
+
+/// Fills `quantity` from `batches` in expiry order. Only released batches
+/// that are still good on `earliest_expiry` are picked from.
+fn allocate(
+    product_id: Uuid,
+    mut batches: Vec<(BatchDetail, StockLevel)>,
+    quantity: i64,
+    unit: QuantityUnit,
+    earliest_expiry: NaiveDate,
+) -> Allocation {
+    batches.sort_by(|(a, _), (b, _)| {
+        a.exp_date
+            .cmp(&b.exp_date)
+            .then(a.mfg_date.cmp(&b.mfg_date))
+            .then_with(|| a.batch_no.cmp(&b.batch_no))
+    });
+
+    let mut remaining = quantity;
+    let mut lines = Vec::new();
+    let mut skipped = Vec::new();
+    for (batch, level) in &batches {
+        if remaining == 0 {
+            break;
+        }
+
+        let reason = if batch.status != BatchStatus::Released {
+            Some(format!("Batch is {}", batch.status.as_str()))
+        } else if batch.exp_date < earliest_expiry {
+            Some(format!(
+                "Expires on {}, before the required {}",
+                batch.exp_date, earliest_expiry
+            ))
+        } else if level.in_unit(unit) <= 0 {
+            Some("No stock left".to_string())
+        } else {
+            None
+        };
+        if let Some(reason) = reason {
+            skipped.push(SkippedBatch {
+                batch_id: batch.id,
+                batch_no: batch.batch_no.clone(),
+                reason,
+            });
+            continue;
+        }
+
+        let take = level.in_unit(unit).min(remaining);
+        lines.push(pick_line(batch, take, unit));
+        remaining -= take;
+    }
+
+    Allocation {
+        product_id,
+        unit,
+        requested: quantity,
+        allocated: quantity - remaining,
+        shortfall: remaining,
+        lines,
+        skipped,
+    }
+}
+
+/// Proposes which batches of `_product_id` should fill an order of
+/// `quantity` boxes, packs or units, earliest expiry first. Batches
+/// expiring within `min_shelf_life_days` of today are skipped, as are
+/// batches that are not released; if the rest can't cover the order the
+/// gap is reported as `shortfall`.
+pub fn propose_allocation(
+    conn: &mut DbConnection,
+    _client_id: Uuid,
+    _product_id: Uuid,
+    quantity: i64,
+    unit: QuantityUnit,
+    min_shelf_life_days: Option<i64>,
+) -> Result<Allocation, AppError> {
+    if quantity <= 0 {
+        return Err(AppError::validation(
+            "quantity",
+            "Quantity must be greater than 0",
+        ));
+    }
+    let min_shelf_life_days = min_shelf_life_days.unwrap_or(0);
+    if !(0..=3650).contains(&min_shelf_life_days) {
+        return Err(AppError::validation(
+            "min_shelf_life_days",
+            "Minimum shelf life must be between 0 and 3650 days",
+        ));
+    }
+
+    get_product(conn, _client_id, _product_id)?;
+    let batches = batch_details::table
+        .filter(batch_details::product_id.eq(_product_id))
+        .select(BatchDetail::as_select())
+        .load(conn)
+        .map_err(AppError::from)?;
+    let batches = stock_levels(conn, batches)?;
+
+    let earliest_expiry = Utc::now().date_naive() + TimeDelta::days(min_shelf_life_days);
+    Ok(allocate(
+        _product_id,
+        batches,
+        quantity,
+        unit,
+        earliest_expiry,
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn date(day: u32) -> NaiveDate {
+        NaiveDate::from_ymd_opt(2025, 1, day).unwrap()
+    }
+
+    /// A batch of 10 units per pack and 2 packs per box.
+    fn batch(batch_no: &str, mfg_day: u32, exp_day: u32, status: BatchStatus) -> BatchDetail {
+        let now = date(1).and_hms_opt(0, 0, 0).unwrap();
+        BatchDetail {
+            id: Uuid::new_v4(),
+            product_id: Uuid::nil(),
+            batch_no: batch_no.to_string(),
+            mfg_date: date(mfg_day),
+            exp_date: date(exp_day),
+            boxes: 10,
+            units_per_box: 20,
+            units_per_pack: 10,
+            packs_per_box: 2,
+            packages_configuration: "1 x 1 x 2 x 10".to_string(),
+            total_packs: 20,
+            created_at: now,
+            updated_at: now,
+            status,
+        }
+    }
+
+    fn stocked(batch: BatchDetail, units: i64) -> (BatchDetail, StockLevel) {
+        let level = StockLevel::new(&batch, units);
+        (batch, level)
+    }
+
+    fn released(batch_no: &str, exp_day: u32, units: i64) -> (BatchDetail, StockLevel) {
+        stocked(batch(batch_no, 1, exp_day, BatchStatus::Released), units)
+    }
+
+    fn picked(allocation: &Allocation) -> Vec<(&str, i64)> {
+        allocation
+            .lines
+            .iter()
+            .map(|line| (line.batch_no.as_str(), line.quantity))
+            .collect()
+    }
+
+    fn skipped(allocation: &Allocation) -> Vec<(&str, &str)> {
+        allocation
+            .skipped
+            .iter()
+            .map(|batch| (batch.batch_no.as_str(), batch.reason.as_str()))
+            .collect()
+    }
+
+    #[test]
+    fn picks_earliest_expiry_first() {
+        let batches = vec![
+            released("LATE", 30, 100),
+            released("EARLY", 10, 100),
+            released("MIDDLE", 20, 100),
+        ];
+
+        let allocation = allocate(Uuid::nil(), batches, 150, QuantityUnit::Units, date(1));
+
+        assert_eq!(picked(&allocation), [("EARLY", 100), ("MIDDLE", 50)]);
+        assert_eq!(allocation.allocated, 150);
+        assert_eq!(allocation.shortfall, 0);
+        assert!(allocation.skipped.is_empty());
+    }
+
+    #[test]
+    fn breaks_expiry_ties_by_manufacturing_date_then_batch_no() {
+        let batches = vec![
+            stocked(batch("B", 5, 20, BatchStatus::Released), 10),
+            stocked(batch("A", 5, 20, BatchStatus::Released), 10),
+            stocked(batch("C", 2, 20, BatchStatus::Released), 10),
+        ];
+
+        let allocation = allocate(Uuid::nil(), batches, 30, QuantityUnit::Units, date(1));
+
+        assert_eq!(picked(&allocation), [("C", 10), ("A", 10), ("B", 10)]);
+    }
+
+    #[test]
+    fn skips_batches_that_are_not_released() {
+        let batches = vec![
+            stocked(batch("QUARANTINE", 1, 10, BatchStatus::Quarantine), 100),
+            stocked(batch("ON-HOLD", 1, 11, BatchStatus::OnHold), 100),
+            stocked(batch("RECALLED", 1, 12, BatchStatus::Recalled), 100),
+            stocked(batch("EXPIRED", 1, 13, BatchStatus::Expired), 100),
+            released("RELEASED", 14, 100),
+        ];
+
+        let allocation = allocate(Uuid::nil(), batches, 50, QuantityUnit::Units, date(1));
+
+        assert_eq!(picked(&allocation), [("RELEASED", 50)]);
+        assert_eq!(
+            skipped(&allocation),
+            [
+                ("QUARANTINE", "Batch is quarantine"),
+                ("ON-HOLD", "Batch is on_hold"),
+                ("RECALLED", "Batch is recalled"),
+                ("EXPIRED", "Batch is expired"),
+            ]
+        );
+    }
+
+    #[test]
+    fn skips_batches_expiring_before_the_required_shelf_life() {
+        let batches = vec![released("SHORT", 9, 100), released("ON-THE-DAY", 10, 100)];
+
+        let allocation = allocate(Uuid::nil(), batches, 50, QuantityUnit::Units, date(10));
+
+        assert_eq!(picked(&allocation), [("ON-THE-DAY", 50)]);
+        assert_eq!(
+            skipped(&allocation),
+            [(
+                "SHORT",
+                "Expires on 2025-01-09, before the required 2025-01-10"
+            )]
+        );
+    }
+
+    #[test]
+    fn skips_batches_without_stock_in_the_requested_unit() {
+        // 15 units is a pack and a half, but not a full box.
+        let batches = vec![released("PARTIAL", 10, 15), released("FULL", 20, 40)];
+
+        let allocation = allocate(Uuid::nil(), batches, 1, QuantityUnit::Boxes, date(1));
+
+        assert_eq!(picked(&allocation), [("FULL", 1)]);
+        assert_eq!(skipped(&allocation), [("PARTIAL", "No stock left")]);
+    }
+
+    #[test]
+    fn reports_what_could_not_be_covered_as_shortfall() {
+        let batches = vec![
+            released("FIRST", 10, 30),
+            stocked(batch("HELD", 1, 15, BatchStatus::OnHold), 500),
+            released("SECOND", 20, 25),
+        ];
+
+        let allocation = allocate(Uuid::nil(), batches, 5, QuantityUnit::Packs, date(1));
+
+        assert_eq!(picked(&allocation), [("FIRST", 3), ("SECOND", 2)]);
+        assert_eq!(allocation.requested, 5);
+        assert_eq!(allocation.allocated, 5);
+        assert_eq!(allocation.shortfall, 0);
+
+        let batches = vec![released("FIRST", 10, 30), released("SECOND", 20, 25)];
+        let allocation = allocate(Uuid::nil(), batches, 8, QuantityUnit::Packs, date(1));
+
+        assert_eq!(picked(&allocation), [("FIRST", 3), ("SECOND", 2)]);
+        assert_eq!(allocation.allocated, 5);
+        assert_eq!(allocation.shortfall, 3);
+    }
+
+    #[test]
+    fn stops_looking_once_the_order_is_filled() {
+        let batches = vec![
+            released("FIRST", 10, 100),
+            stocked(batch("HELD", 1, 20, BatchStatus::OnHold), 100),
+        ];
+
+        let allocation = allocate(Uuid::nil(), batches, 100, QuantityUnit::Units, date(1));
+
+        assert_eq!(picked(&allocation), [("FIRST", 100)]);
+        assert!(allocation.skipped.is_empty());
+    }
+
+    #[test]
+    fn breaks_picks_down_into_boxes_packs_and_units() {
+        let breakdown = |quantity: i64, unit: QuantityUnit| {
+            let allocation = allocate(
+                Uuid::nil(),
+                vec![released("BATCH", 10, 200)],
+                quantity,
+                unit,
+                date(1),
+            );
+            let line = &allocation.lines[0];
+            (
+                line.quantity,
+                line.boxes,
+                line.loose_packs,
+                line.loose_units,
+            )
+        };
+
+        assert_eq!(breakdown(45, QuantityUnit::Units), (45, 2, 0, 5));
+        assert_eq!(breakdown(57, QuantityUnit::Units), (57, 2, 1, 7));
+        assert_eq!(breakdown(5, QuantityUnit::Packs), (5, 2, 1, 0));
+        assert_eq!(breakdown(3, QuantityUnit::Boxes), (3, 3, 0, 0));
+    }
+}
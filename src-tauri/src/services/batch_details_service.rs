@@ -1,69 +1,301 @@
-use app::ErrorResponse;
-use diesel::{ExpressionMethods, PgConnection, QueryDsl, RunQueryDsl};
+use app::{AppError, BatchInput, DbConnection, PackagingConfiguration, RowError};
+use diesel::{Connection, ExpressionMethods, QueryDsl, RunQueryDsl, SelectableHelper};
+use std::collections::HashSet;
 use uuid::Uuid;
 
+use super::{
+    product_service::get_product,
+    reconciliation_service::sync_product_totals,
+    stock_service::{record_movement, remove_opening_receipts},
+};
+
 use crate::schema::batch_details::dsl::*;
 use crate::{
-    models::{BatchDetail, NewBatchDetail, UpdateBatchDetail},
-    schema::batch_details,
+    models::{
+        BatchDetail, BatchStatus, MovementType, NewBatchDetail, QuantityUnit, UpdateBatchDetail,
+    },
+    schema::{batch_details, products},
 };
 
 pub fn create_batch_detail(
-    conn: &mut PgConnection,
+    conn: &mut DbConnection,
     new_batch_detail: NewBatchDetail,
-) -> Result<BatchDetail, ErrorResponse> {
+) -> Result<BatchDetail, AppError> {
     diesel::insert_into(batch_details::table)
         .values(&new_batch_detail)
         .get_result::<BatchDetail>(conn)
-        .map_err(|e| ErrorResponse {
-            error: e.to_string(),
-        })
+        .map_err(AppError::from)
+}
+
+/// Returns every reason `input` cannot be stored, without touching the database.
+pub fn validate_batch_input(input: &BatchInput) -> Vec<String> {
+    let mut reasons = Vec::new();
+
+    let trimmed_batch_no = input.batch_no.trim();
+    if trimmed_batch_no.is_empty() {
+        reasons.push("Batch number is required".to_string());
+    } else if trimmed_batch_no.len() > 50 {
+        reasons.push("Batch number must be at most 50 characters".to_string());
+    }
+    if input.exp_date <= input.mfg_date {
+        reasons.push("Expiry date must be after the manufacturing date".to_string());
+    }
+    if input.boxes <= 0 {
+        reasons.push("Number of boxes must be greater than 0".to_string());
+    }
+    if input.packages_configuration.len() > 255 {
+        reasons.push("Package configuration must be at most 255 characters".to_string());
+    }
+
+    match input
+        .packages_configuration
+        .parse::<PackagingConfiguration>()
+    {
+        Ok(configuration) => reasons.extend(
+            configuration
+                .mismatches(
+                    input.boxes,
+                    input.units_per_pack,
+                    input.packs_per_box,
+                    input.units_per_box,
+                    input.total_packs,
+                )
+                .into_iter()
+                .map(|(_, reason)| reason),
+        ),
+        Err(reason) => reasons.push(reason),
+    }
+
+    reasons
+}
+
+/// Inserts all of `inputs` for a product in a single transaction, opening
+/// each batch's stock ledger with a receipt of its packs.
+///
+/// Every row is validated up front; if any row is invalid, or any insert
+/// fails, nothing is written and the offending rows are reported by index.
+pub fn create_batch_details(
+    conn: &mut DbConnection,
+    _client_id: Uuid,
+    _product_id: Uuid,
+    inputs: &[BatchInput],
+) -> Result<Vec<BatchDetail>, AppError> {
+    get_product(conn, _client_id, _product_id)?;
+
+    let mut rejected = Vec::new();
+    let mut seen_batch_nos = HashSet::new();
+
+    for (index, input) in inputs.iter().enumerate() {
+        for reason in validate_batch_input(input) {
+            rejected.push(RowError {
+                index,
+                batch_no: input.batch_no.clone(),
+                reason,
+            });
+        }
+        if !seen_batch_nos.insert(input.batch_no.trim()) {
+            rejected.push(RowError {
+                index,
+                batch_no: input.batch_no.clone(),
+                reason: "Batch number appears more than once in this request".to_string(),
+            });
+        }
+    }
+
+    if !rejected.is_empty() {
+        return Err(AppError::BatchRejected { rows: rejected });
+    }
+
+    conn.transaction(|conn| {
+        let mut created = Vec::with_capacity(inputs.len());
+
+        for (index, input) in inputs.iter().enumerate() {
+            let new_batch_detail = NewBatchDetail {
+                product_id: _product_id,
+                batch_no: input.batch_no.trim(),
+                mfg_date: input.mfg_date,
+                exp_date: input.exp_date,
+                boxes: input.boxes,
+                units_per_box: input.units_per_box,
+                units_per_pack: input.units_per_pack,
+                packs_per_box: input.packs_per_box,
+                packages_configuration: input.packages_configuration.trim(),
+                total_packs: input.total_packs,
+            };
+
+            let batch_detail = create_batch_detail(conn, new_batch_detail).map_err(|err| {
+                AppError::BatchRejected {
+                    rows: vec![RowError {
+                        index,
+                        batch_no: input.batch_no.clone(),
+                        reason: err.to_string(),
+                    }],
+                }
+            })?;
+            record_movement(
+                conn,
+                &batch_detail,
+                MovementType::Receipt,
+                batch_detail.total_packs,
+                QuantityUnit::Packs,
+                None,
+                _client_id,
+            )?;
+            created.push(batch_detail);
+        }
+
+        sync_product_totals(conn, _product_id)?;
+        Ok(created)
+    })
 }
 
+/// Loads a batch only if its product belongs to `_client_id`.
 pub fn get_batch_detail(
-    conn: &mut PgConnection,
+    conn: &mut DbConnection,
+    _client_id: Uuid,
     batch_detail_id: Uuid,
-) -> Result<BatchDetail, ErrorResponse> {
+) -> Result<BatchDetail, AppError> {
     batch_details
-        .find(batch_detail_id)
+        .inner_join(products::table)
+        .filter(id.eq(batch_detail_id))
+        .filter(products::client_id.eq(_client_id))
+        .select(BatchDetail::as_select())
         .get_result::<BatchDetail>(conn)
-        .map_err(|e| ErrorResponse {
-            error: e.to_string(),
-        })
+        .map_err(AppError::from)
+}
+
+/// Checks the row that would result from applying `changes` to `current`,
+/// so a partial update cannot leave quantities disagreeing with the layout.
+/// The received quantities are fixed once the batch exists; stock changes go
+/// through the ledger instead.
+fn validate_batch_update(
+    current: &BatchDetail,
+    changes: &UpdateBatchDetail,
+) -> Result<(), AppError> {
+    if changes
+        .boxes
+        .is_some_and(|changed| changed != current.boxes)
+        || changes
+            .total_packs
+            .is_some_and(|changed| changed != current.total_packs)
+    {
+        return Err(AppError::validation(
+            "total_packs",
+            "Received quantities cannot be edited; post a stock adjustment instead",
+        ));
+    }
+
+    let mfg = changes.mfg_date.unwrap_or(current.mfg_date);
+    let exp = changes.exp_date.unwrap_or(current.exp_date);
+    if exp <= mfg {
+        return Err(AppError::validation(
+            "exp_date",
+            "Expiry date must be after the manufacturing date",
+        ));
+    }
+
+    let configuration = changes
+        .packages_configuration
+        .unwrap_or(&current.packages_configuration)
+        .parse::<PackagingConfiguration>()
+        .map_err(|reason| AppError::validation("packages_configuration", reason))?;
+
+    let mismatches = configuration.mismatches(
+        changes.boxes.unwrap_or(current.boxes),
+        changes.units_per_pack.unwrap_or(current.units_per_pack),
+        changes.packs_per_box.unwrap_or(current.packs_per_box),
+        changes.units_per_box.unwrap_or(current.units_per_box),
+        changes.total_packs.unwrap_or(current.total_packs),
+    );
+    match mismatches.first() {
+        Some((field, _)) => Err(AppError::validation(
+            field,
+            mismatches
+                .iter()
+                .map(|(_, reason)| reason.as_str())
+                .collect::<Vec<_>>()
+                .join("; "),
+        )),
+        None => Ok(()),
+    }
 }
 
 pub fn update_batch_detail(
-    conn: &mut PgConnection,
+    conn: &mut DbConnection,
+    _client_id: Uuid,
     batch_detail_id: Uuid,
     batch_detail_data: UpdateBatchDetail,
-) -> Result<BatchDetail, ErrorResponse> {
-    diesel::update(batch_details.find(batch_detail_id))
-        .set(&batch_detail_data)
-        .get_result::<BatchDetail>(conn)
-        .map_err(|e| ErrorResponse {
-            error: e.to_string(),
-        })
+) -> Result<BatchDetail, AppError> {
+    conn.transaction(|conn| {
+        get_batch_detail(conn, _client_id, batch_detail_id)?;
+        let current = batch_details
+            .find(batch_detail_id)
+            .for_update()
+            .get_result::<BatchDetail>(conn)?;
+        validate_batch_update(&current, &batch_detail_data)?;
+
+        let updated = diesel::update(batch_details.find(batch_detail_id))
+            .set(&batch_detail_data)
+            .get_result::<BatchDetail>(conn)
+            .map_err(AppError::from)?;
+
+        sync_product_totals(conn, updated.product_id)?;
+        Ok(updated)
+    })
 }
 
 pub fn delete_batch_detail(
-    conn: &mut PgConnection,
+    conn: &mut DbConnection,
+    _client_id: Uuid,
     batch_detail_id: Uuid,
-) -> Result<usize, ErrorResponse> {
-    diesel::delete(batch_details.find(batch_detail_id))
-        .execute(conn)
-        .map_err(|e| ErrorResponse {
-            error: e.to_string(),
-        })
+) -> Result<usize, AppError> {
+    conn.transaction(|conn| {
+        get_batch_detail(conn, _client_id, batch_detail_id)?;
+        // Locking the batch keeps a movement from being posted between the
+        // ledger check and the delete.
+        batch_details
+            .find(batch_detail_id)
+            .for_update()
+            .select(id)
+            .get_result::<Uuid>(conn)
+            .map_err(AppError::from)?;
+
+        // The ledger is append-only, so only a batch whose stock never moved
+        // after it was entered can go, along with its opening receipt.
+        if !remove_opening_receipts(conn, &[batch_detail_id])? {
+            return Err(AppError::Conflict(
+                "This batch has stock movements and can't be deleted; \
+                 write off its remaining stock instead"
+                    .to_string(),
+            ));
+        }
+
+        let deleted_from = diesel::delete(batch_details.find(batch_detail_id))
+            .returning(product_id)
+            .get_results::<Uuid>(conn)
+            .map_err(AppError::from)?;
+
+        for _product_id in &deleted_from {
+            sync_product_totals(conn, *_product_id)?;
+        }
+        Ok(deleted_from.len())
+    })
 }
 
+/// Loads the product's batches, optionally only those in one of `statuses`.
 pub fn fetch_all_batches_for_product(
-    conn: &mut PgConnection,
+    conn: &mut DbConnection,
+    _client_id: Uuid,
     _product_id: Uuid,
-) -> Result<Vec<BatchDetail>, ErrorResponse> {
-    batch_details
+    statuses: Option<&[BatchStatus]>,
+) -> Result<Vec<BatchDetail>, AppError> {
+    get_product(conn, _client_id, _product_id)?;
+
+    let mut query = batch_details
         .filter(product_id.eq(_product_id))
-        .load::<BatchDetail>(conn)
-        .map_err(|e| ErrorResponse {
-            error: e.to_string(),
-        })
+        .into_boxed();
+    if let Some(statuses) = statuses {
+        query = query.filter(status.eq_any(statuses));
+    }
+    query.load::<BatchDetail>(conn).map_err(AppError::from)
 }
@@ -0,0 +1,193 @@
+use app::{AppError, DbConnection};
+use chrono::Utc;
+use diesel::{Connection, ExpressionMethods, QueryDsl, RunQueryDsl, SelectableHelper};
+use uuid::Uuid;
+
+use super::{batch_details_service::get_batch_detail, reconciliation_service::sync_product_totals};
+use crate::models::{BatchDetail, BatchStatus, BatchStatusChange, NewBatchStatusChange};
+use crate::schema::{batch_details, batch_status_history, products};
+
+const MAX_REASON_LENGTH: usize = 500;
+
+/// The statuses a batch in `from` may be moved to. Recalls are final;
+/// expired stock can still be recalled so the recall covers every unit.
+pub fn allowed_transitions(from: BatchStatus) -> &'static [BatchStatus] {
+    use BatchStatus::*;
+
+    match from {
+        Quarantine => &[Released, OnHold, Recalled, Expired],
+        Released => &[Quarantine, OnHold, Recalled, Expired],
+        OnHold => &[Quarantine, Released, Recalled, Expired],
+        Expired => &[Recalled],
+        Recalled => &[],
+    }
+}
+
+/// Rejects a move from `from` to `to` that the state machine doesn't allow,
+/// including staying in the same status.
+fn check_transition(from: BatchStatus, to: BatchStatus) -> Result<(), AppError> {
+    if from == to {
+        return Err(AppError::validation(
+            "status",
+            format!("Batch is already {}", to.as_str()),
+        ));
+    }
+    if !allowed_transitions(from).contains(&to) {
+        return Err(AppError::validation(
+            "status",
+            format!(
+                "A {} batch cannot be moved to {}",
+                from.as_str(),
+                to.as_str()
+            ),
+        ));
+    }
+    Ok(())
+}
+
+/// Moves a batch of `_client_id` to `to`, recording who made the change and
+/// why. Product totals are re-derived since the batch may have left or
+/// re-entered stock.
+pub fn transition_batch_status(
+    conn: &mut DbConnection,
+    _client_id: Uuid,
+    batch_detail_id: Uuid,
+    to: BatchStatus,
+    reason: &str,
+) -> Result<(BatchDetail, BatchStatusChange), AppError> {
+    let reason = reason.trim();
+    if reason.is_empty() {
+        return Err(AppError::validation(
+            "reason",
+            "A reason is required to change a batch's status",
+        ));
+    }
+    if reason.chars().count() > MAX_REASON_LENGTH {
+        return Err(AppError::validation(
+            "reason",
+            format!(
+                "Reason cannot be longer than {} characters",
+                MAX_REASON_LENGTH
+            ),
+        ));
+    }
+
+    conn.transaction(|conn| {
+        get_batch_detail(conn, _client_id, batch_detail_id)?;
+        let current = batch_details::table
+            .find(batch_detail_id)
+            .for_update()
+            .select(BatchDetail::as_select())
+            .get_result(conn)
+            .map_err(AppError::from)?;
+
+        check_transition(current.status, to)?;
+
+        let updated = diesel::update(batch_details::table.find(batch_detail_id))
+            .set((
+                batch_details::status.eq(to),
+                batch_details::updated_at.eq(Utc::now().naive_utc()),
+            ))
+            .returning(BatchDetail::as_returning())
+            .get_result(conn)
+            .map_err(AppError::from)?;
+
+        let change = diesel::insert_into(batch_status_history::table)
+            .values(&NewBatchStatusChange {
+                batch_id: batch_detail_id,
+                from_status: current.status,
+                to_status: to,
+                reason,
+                changed_by: _client_id,
+            })
+            .returning(BatchStatusChange::as_returning())
+            .get_result(conn)
+            .map_err(AppError::from)?;
+
+        sync_product_totals(conn, updated.product_id)?;
+        Ok((updated, change))
+    })
+}
+
+/// Status changes of one batch, most recent first.
+pub fn batch_status_history(
+    conn: &mut DbConnection,
+    _client_id: Uuid,
+    batch_detail_id: Uuid,
+) -> Result<Vec<BatchStatusChange>, AppError> {
+    get_batch_detail(conn, _client_id, batch_detail_id)?;
+
+    batch_status_history::table
+        .filter(batch_status_history::batch_id.eq(batch_detail_id))
+        .select(BatchStatusChange::as_select())
+        .order(batch_status_history::changed_at.desc())
+        .load(conn)
+        .map_err(AppError::from)
+}
+
+/// Every batch of `_client_id` in one of `statuses`, soonest expiry first.
+pub fn fetch_batches_by_status(
+    conn: &mut DbConnection,
+    _client_id: Uuid,
+    statuses: &[BatchStatus],
+) -> Result<Vec<BatchDetail>, AppError> {
+    batch_details::table
+        .inner_join(products::table)
+        .filter(products::client_id.eq(_client_id))
+        .filter(batch_details::status.eq_any(statuses))
+        .select(BatchDetail::as_select())
+        .order((batch_details::exp_date.asc(), batch_details::batch_no.asc()))
+        .load(conn)
+        .map_err(AppError::from)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use BatchStatus::*;
+
+    const ALL: [BatchStatus; 5] = [Quarantine, Released, OnHold, Recalled, Expired];
+
+    /// Every `(from, to)` pair that is allowed; all others are forbidden.
+    const ALLOWED: [(BatchStatus, BatchStatus); 13] = [
+        (Quarantine, Released),
+        (Quarantine, OnHold),
+        (Quarantine, Recalled),
+        (Quarantine, Expired),
+        (Released, Quarantine),
+        (Released, OnHold),
+        (Released, Recalled),
+        (Released, Expired),
+        (OnHold, Quarantine),
+        (OnHold, Released),
+        (OnHold, Recalled),
+        (OnHold, Expired),
+        (Expired, Recalled),
+    ];
+
+    #[test]
+    fn allows_exactly_the_listed_transitions() {
+        for from in ALL {
+            for to in ALL {
+                assert_eq!(
+                    allowed_transitions(from).contains(&to),
+                    ALLOWED.contains(&(from, to)),
+                    "{} -> {}",
+                    from.as_str(),
+                    to.as_str()
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn rejects_forbidden_transitions_as_invalid_status() {
+        for from in ALL {
+            for to in ALL.into_iter().filter(|to| !ALLOWED.contains(&(from, *to))) {
+                let err = check_transition(from, to).unwrap_err();
+                assert!(matches!(err, AppError::Validation { .. }));
+                assert_eq!(err.field(), Some("status"));
+            }
+        }
+    }
+}
@@ -1,48 +1,233 @@
-use app::ErrorResponse;
-use diesel::{ExpressionMethods, PgConnection, QueryDsl, RunQueryDsl};
+use app::{AppError, DbConnection};
+use base64::{engine::general_purpose, Engine};
+use chrono::Utc;
+use diesel::{Connection, ExpressionMethods, OptionalExtension, QueryDsl, RunQueryDsl};
+use serde::Serialize;
+use uuid::Uuid;
 
-use crate::{models::Client, schema::clients, schema::clients::dsl::*};
+use crate::{
+    models::{Client, NewClient},
+    schema::clients,
+    schema::clients::dsl::*,
+};
 
 use super::{
-    key_management_service::generate_key_pair,
-    session_management_service::get_client_id_from_private_key,
+    key_management_service::{
+        self, generate_key_pair, local_client_ids, remove_client_keys, store_private_key,
+        IdentityMetadata, MasterKey,
+    },
+    session_management_service::revoke_all_sessions,
 };
 
+/// A client identity whose keys are stored on this machine. `registered` is
+/// false when the keys belong to a client missing from this database.
+#[derive(Serialize)]
+pub struct LocalClient {
+    pub id: Uuid,
+    pub name: Option<String>,
+    pub registered: bool,
+}
+
+fn validate_name(client_name: &str) -> Result<&str, AppError> {
+    let client_name = client_name.trim();
+    if client_name.is_empty() {
+        return Err(AppError::validation("name", "Client name cannot be empty"));
+    }
+    if client_name.chars().count() > 100 {
+        return Err(AppError::validation(
+            "name",
+            "Client name cannot be longer than 100 characters",
+        ));
+    }
+    Ok(client_name)
+}
+
+pub fn list_local_clients(conn: &mut DbConnection) -> Result<Vec<LocalClient>, AppError> {
+    let local_ids = local_client_ids()?;
+    let registered = clients
+        .filter(id.eq_any(&local_ids))
+        .load::<Client>(conn)
+        .map_err(AppError::from)?;
 
+    let mut local_clients: Vec<LocalClient> = local_ids
+        .into_iter()
+        .map(|local_id| {
+            let client = registered.iter().find(|client| client.id == local_id);
+            LocalClient {
+                id: local_id,
+                name: client.map(|client| client.name.clone()),
+                registered: client.is_some(),
+            }
+        })
+        .collect();
+    local_clients.sort_by(|a, b| a.name.cmp(&b.name).then(a.id.cmp(&b.id)));
 
-pub fn create_client(conn: &mut PgConnection) -> Result<Client, ErrorResponse> {
-    match get_client_id_from_private_key() {
-        Ok(_) => {
-            return Err(ErrorResponse {
-                error: "Client with private key already exists".to_string(),
-            });
-        }
-        Err(_) => {}
+    Ok(local_clients)
+}
+
+pub fn create_client(
+    conn: &mut DbConnection,
+    key: &MasterKey,
+    client_name: &str,
+) -> Result<Client, AppError> {
+    let client_name = validate_name(client_name)?;
+
+    // The key file is written inside the transaction so a failure to write
+    // it rolls the row back. If anything after that fails, including the
+    // commit, the key file is deleted again instead of being left orphaned.
+    let mut written_key = None;
+    let created = conn.transaction(|conn| {
+        let placeholder_path = "placeholder";
+        let new_client = diesel::insert_into(clients::table)
+            .values(&NewClient {
+                private_key_path: placeholder_path,
+                name: client_name,
+            })
+            .get_result::<Client>(conn)
+            .map_err(AppError::from)?;
+
+        let (private_key_path_str, public_key_bytes) = generate_key_pair(key, new_client.id)?;
+        written_key = Some(new_client.id);
+
+        let _ = diesel::update(clients.find(new_client.id))
+            .set((
+                private_key_path.eq(&private_key_path_str),
+                public_key.eq(general_purpose::STANDARD.encode(public_key_bytes)),
+            ))
+            .execute(conn)
+            .map_err(AppError::from)?;
+
+        let updated_client = clients
+            .find(new_client.id)
+            .get_result::<Client>(conn)
+            .map_err(AppError::from)?;
+
+        Ok(updated_client)
+    });
+
+    if let (Err(_), Some(client_id)) = (&created, written_key) {
+        let _ = remove_client_keys(client_id);
     }
+    created
+}
+
+pub fn rename_client(
+    conn: &mut DbConnection,
+    client_id: Uuid,
+    client_name: &str,
+) -> Result<Client, AppError> {
+    let client_name = validate_name(client_name)?;
 
-    let placeholder_path = "placeholder";
-    let new_client = diesel::insert_into(clients::table)
-        .values((private_key_path.eq(placeholder_path),))
+    diesel::update(clients.find(client_id))
+        .set(name.eq(client_name))
         .get_result::<Client>(conn)
-        .map_err(|e| ErrorResponse {
-            error: e.to_string(),
-        })?;
+        .map_err(AppError::from)
+}
 
-    let private_key_path_str = generate_key_pair(new_client.id);
+/// Removes the identity from this machine: its sessions are revoked and its
+/// keys deleted. The `clients` row and its products are kept.
+///
+/// Without an exported backup the keys are gone for good, so the caller has
+/// to pass `confirmed` to show the user agreed to that.
+pub fn remove_client(
+    conn: &mut DbConnection,
+    client_id: Uuid,
+    confirmed: bool,
+) -> Result<(), AppError> {
+    let local_ids = local_client_ids()?;
+    if !local_ids.contains(&client_id) {
+        return Err(AppError::NotFound);
+    }
+    if !confirmed {
+        return Err(AppError::Conflict(
+            "Export this client's identity first, or confirm that its keys will be deleted for good"
+                .to_string(),
+        ));
+    }
 
-    let _ = diesel::update(clients.find(new_client.id))
-        .set(private_key_path.eq(&private_key_path_str))
-        .execute(conn)
-        .map_err(|e| ErrorResponse {
-            error: e.to_string(),
-        })?;
+    revoke_all_sessions(conn, client_id)?;
+    Ok(remove_client_keys(client_id)?)
+}
 
-    let updated_client = clients
-        .find(new_client.id)
+/// Produces a passphrase-protected backup of `client_id`'s identity that
+/// [`import_client`] can restore on another machine.
+pub fn export_client(
+    conn: &mut DbConnection,
+    key: &MasterKey,
+    client_id: Uuid,
+    passphrase: &str,
+) -> Result<String, AppError> {
+    let client = clients
+        .find(client_id)
         .get_result::<Client>(conn)
-        .map_err(|e| ErrorResponse {
-            error: e.to_string(),
+        .map_err(AppError::from)?;
+    let registered_key = client.public_key.ok_or_else(|| {
+        AppError::Conflict("Sign in once before exporting this client".to_string())
+    })?;
+
+    let metadata = IdentityMetadata {
+        client_id: client.id,
+        name: client.name,
+        public_key: registered_key,
+        exported_at: Utc::now().naive_utc(),
+    };
+    key_management_service::export_identity(key, metadata, passphrase)
+}
+
+/// Restores an identity backup on this machine and re-links it to the
+/// existing `clients` row, which must hold the same public key.
+pub fn import_client(
+    conn: &mut DbConnection,
+    key: &MasterKey,
+    bundle: &str,
+    passphrase: &str,
+) -> Result<Client, AppError> {
+    let (metadata, private_key) = key_management_service::open_identity_bundle(bundle, passphrase)?;
+
+    if local_client_ids()?.contains(&metadata.client_id) {
+        return Err(AppError::Conflict(
+            "This client is already set up on this machine".to_string(),
+        ));
+    }
+
+    conn.transaction(|conn| {
+        let client = clients
+            .find(metadata.client_id)
+            .for_update()
+            .get_result::<Client>(conn)
+            .optional()
+            .map_err(AppError::from)?
+            .ok_or_else(|| {
+                AppError::Conflict(
+                    "The client in this backup is not registered in this database".to_string(),
+                )
+            })?;
+        // Without a registered key there is nothing to check the backup
+        // against, and accepting it would let any bundle claim the client.
+        let registered_key = client.public_key.ok_or_else(|| {
+            AppError::Conflict(
+                "This client has no registered key to check the backup against; \
+                 sign in on the machine it was created on first"
+                    .to_string(),
+            )
         })?;
+        if registered_key != metadata.public_key {
+            return Err(AppError::Conflict(
+                "The key in this backup does not match the registered client".to_string(),
+            ));
+        }
 
-    Ok(updated_client)
+        let private_key_path_str =
+            store_private_key(key, private_key.expose(), metadata.client_id)?;
+        diesel::update(clients.find(metadata.client_id))
+            .set((
+                private_key_path.eq(&private_key_path_str),
+                public_key.eq(&metadata.public_key),
+            ))
+            .get_result::<Client>(conn)
+            .map_err(|err| {
+                let _ = remove_client_keys(metadata.client_id);
+                AppError::from(err)
+            })
+    })
 }
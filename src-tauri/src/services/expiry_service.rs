@@ -0,0 +1,350 @@
+use app::{AppError, Database, DbConnection};
+use chrono::{NaiveDate, TimeDelta, Utc};
+use diesel::{
+    dsl::{exists, not},
+    ExpressionMethods, QueryDsl, RunQueryDsl, SelectableHelper,
+};
+use dotenvy::dotenv;
+use serde::Serialize;
+use std::{env, thread, time::Duration};
+use uuid::Uuid;
+
+use super::key_management_service::local_client_ids;
+use crate::models::{BatchDetail, BatchStatus, NewExpiryAlert, Product};
+use crate::schema::{batch_details, expiry_alerts, products};
+
+const DEFAULT_THRESHOLDS: [i64; 3] = [30, 90, 180];
+const MAX_THRESHOLD_DAYS: i64 = 3650;
+/// How often the background monitor looks for batches entering a window,
+/// unless `EXPIRY_CHECK_INTERVAL_SECS` says otherwise.
+const DEFAULT_CHECK_INTERVAL: Duration = Duration::from_secs(60 * 60);
+/// `threshold_days` recorded for alerts about batches that have expired.
+const EXPIRED_THRESHOLD: i32 = 0;
+
+/// Alert windows in days before expiry, sorted and without duplicates.
+#[derive(Serialize, Clone, Debug)]
+pub struct ExpiryThresholds(Vec<i64>);
+
+impl Default for ExpiryThresholds {
+    fn default() -> Self {
+        ExpiryThresholds(DEFAULT_THRESHOLDS.to_vec())
+    }
+}
+
+impl ExpiryThresholds {
+    pub fn new(mut days: Vec<i64>) -> Result<Self, AppError> {
+        if days.is_empty() {
+            return Err(AppError::validation(
+                "thresholds",
+                "At least one alert window is required",
+            ));
+        }
+        if let Some(invalid) = days
+            .iter()
+            .find(|days| !(1..=MAX_THRESHOLD_DAYS).contains(*days))
+        {
+            return Err(AppError::validation(
+                "thresholds",
+                format!(
+                    "Alert windows must be between 1 and {} days, got {}",
+                    MAX_THRESHOLD_DAYS, invalid
+                ),
+            ));
+        }
+        days.sort_unstable();
+        days.dedup();
+        Ok(ExpiryThresholds(days))
+    }
+
+    /// Reads a comma-separated list of days from `EXPIRY_ALERT_DAYS`,
+    /// falling back to 30, 90 and 180 when it is unset or invalid.
+    pub fn from_env() -> Self {
+        dotenv().ok();
+
+        let Ok(value) = env::var("EXPIRY_ALERT_DAYS") else {
+            return ExpiryThresholds::default();
+        };
+        let parsed = value
+            .split(',')
+            .map(|days| days.trim().parse::<i64>())
+            .collect::<Result<Vec<_>, _>>()
+            .map_err(|e| AppError::validation("thresholds", e.to_string()))
+            .and_then(ExpiryThresholds::new);
+
+        parsed.unwrap_or_else(|err| {
+            eprintln!("Ignoring EXPIRY_ALERT_DAYS={:?}: {}", value, err);
+            ExpiryThresholds::default()
+        })
+    }
+
+    /// A batch is expired once its expiry date has passed, and otherwise
+    /// belongs to the narrowest window it falls in.
+    pub fn classify(&self, days_left: i64) -> ExpiryStatus {
+        if days_left < 0 {
+            return ExpiryStatus::Expired;
+        }
+        match self.0.iter().find(|within| days_left <= **within) {
+            Some(within_days) => ExpiryStatus::Expiring {
+                within_days: *within_days,
+            },
+            None => ExpiryStatus::Ok,
+        }
+    }
+
+    fn widest(&self) -> i64 {
+        self.0.last().copied().unwrap_or(0)
+    }
+}
+
+#[derive(Serialize, Clone, Copy, Debug, PartialEq)]
+#[serde(tag = "status", rename_all = "snake_case")]
+pub enum ExpiryStatus {
+    Expired,
+    Expiring { within_days: i64 },
+    Ok,
+}
+
+impl ExpiryStatus {
+    fn threshold_days(self) -> Option<i32> {
+        match self {
+            ExpiryStatus::Expired => Some(EXPIRED_THRESHOLD),
+            ExpiryStatus::Expiring { within_days } => i32::try_from(within_days).ok(),
+            ExpiryStatus::Ok => None,
+        }
+    }
+}
+
+#[derive(Serialize)]
+pub struct ClassifiedBatch {
+    pub product_id: Uuid,
+    pub product_name: String,
+    pub batch: BatchDetail,
+    pub days_left: i64,
+    pub status: ExpiryStatus,
+}
+
+#[derive(Serialize)]
+pub struct ExpiryWindow {
+    pub within_days: i64,
+    pub batches: Vec<ClassifiedBatch>,
+}
+
+/// A client's in-stock batches split by expiry status, each list soonest
+/// first. `expiring` has one entry per alert window, narrowest first.
+#[derive(Serialize)]
+pub struct ExpiryReport {
+    pub as_of: NaiveDate,
+    pub expired: Vec<ClassifiedBatch>,
+    pub expiring: Vec<ExpiryWindow>,
+    pub ok: Vec<ClassifiedBatch>,
+}
+
+fn today() -> NaiveDate {
+    Utc::now().date_naive()
+}
+
+pub fn expiry_report(
+    conn: &mut DbConnection,
+    _client_id: Uuid,
+    thresholds: &ExpiryThresholds,
+) -> Result<ExpiryReport, AppError> {
+    let as_of = today();
+    let batches = batch_details::table
+        .inner_join(products::table)
+        .filter(products::client_id.eq(_client_id))
+        .filter(batch_details::status.eq_any(BatchStatus::IN_STOCK))
+        .select((BatchDetail::as_select(), Product::as_select()))
+        .order((batch_details::exp_date.asc(), batch_details::batch_no.asc()))
+        .load::<(BatchDetail, Product)>(conn)
+        .map_err(AppError::from)?;
+
+    let mut report = ExpiryReport {
+        as_of,
+        expired: Vec::new(),
+        expiring: thresholds
+            .0
+            .iter()
+            .map(|within_days| ExpiryWindow {
+                within_days: *within_days,
+                batches: Vec::new(),
+            })
+            .collect(),
+        ok: Vec::new(),
+    };
+
+    for (batch, product) in batches {
+        let days_left = (batch.exp_date - as_of).num_days();
+        let status = thresholds.classify(days_left);
+        let classified = ClassifiedBatch {
+            product_id: product.id,
+            product_name: product.product_name,
+            batch,
+            days_left,
+            status,
+        };
+        match status {
+            ExpiryStatus::Expired => report.expired.push(classified),
+            ExpiryStatus::Expiring { within_days } => {
+                if let Some(window) = report
+                    .expiring
+                    .iter_mut()
+                    .find(|window| window.within_days == within_days)
+                {
+                    window.batches.push(classified);
+                }
+            }
+            ExpiryStatus::Ok => report.ok.push(classified),
+        }
+    }
+
+    Ok(report)
+}
+
+/// A batch that has just entered an alert window or expired.
+#[derive(Serialize, Clone)]
+pub struct ExpiryAlert {
+    pub client_id: Uuid,
+    pub product_id: Uuid,
+    pub product_name: String,
+    pub batch_id: Uuid,
+    pub batch_no: String,
+    pub exp_date: NaiveDate,
+    pub days_left: i64,
+    pub status: ExpiryStatus,
+}
+
+impl ExpiryAlert {
+    pub fn message(&self) -> String {
+        match self.status {
+            ExpiryStatus::Expired => format!(
+                "{} batch {} expired on {}",
+                self.product_name, self.batch_no, self.exp_date
+            ),
+            _ => format!(
+                "{} batch {} expires in {} days ({})",
+                self.product_name, self.batch_no, self.days_left, self.exp_date
+            ),
+        }
+    }
+}
+
+/// Finds `_client_id`'s in-stock batches that have entered a window or
+/// expired since the last check. Each window is reported once per batch and
+/// expiry date, so changing a batch's expiry date re-arms its alerts.
+pub fn check_expiry_alerts(
+    conn: &mut DbConnection,
+    _client_id: Uuid,
+    thresholds: &ExpiryThresholds,
+) -> Result<Vec<ExpiryAlert>, AppError> {
+    let as_of = today();
+    let client_batches = batch_details::table
+        .inner_join(products::table)
+        .filter(products::client_id.eq(_client_id))
+        .select(batch_details::id);
+
+    // Alerts for an expiry date the batch no longer has can never fire again.
+    diesel::delete(
+        expiry_alerts::table
+            .filter(expiry_alerts::batch_id.eq_any(client_batches))
+            .filter(not(exists(
+                batch_details::table
+                    .filter(batch_details::id.eq(expiry_alerts::batch_id))
+                    .filter(batch_details::exp_date.eq(expiry_alerts::exp_date)),
+            ))),
+    )
+    .execute(conn)
+    .map_err(AppError::from)?;
+
+    let candidates = batch_details::table
+        .inner_join(products::table)
+        .filter(products::client_id.eq(_client_id))
+        .filter(batch_details::exp_date.le(as_of + TimeDelta::days(thresholds.widest())))
+        .filter(batch_details::status.eq_any(BatchStatus::IN_STOCK))
+        .select((BatchDetail::as_select(), Product::as_select()))
+        .order(batch_details::exp_date.asc())
+        .load::<(BatchDetail, Product)>(conn)
+        .map_err(AppError::from)?;
+
+    let mut alerts = Vec::new();
+    for (batch, product) in candidates {
+        let days_left = (batch.exp_date - as_of).num_days();
+        let status = thresholds.classify(days_left);
+        let Some(threshold_days) = status.threshold_days() else {
+            continue;
+        };
+
+        let inserted = diesel::insert_into(expiry_alerts::table)
+            .values(&NewExpiryAlert {
+                batch_id: batch.id,
+                threshold_days,
+                exp_date: batch.exp_date,
+            })
+            .on_conflict_do_nothing()
+            .execute(conn)
+            .map_err(AppError::from)?;
+        if inserted == 0 {
+            continue;
+        }
+
+        alerts.push(ExpiryAlert {
+            client_id: product.client_id,
+            product_id: product.id,
+            product_name: product.product_name,
+            batch_id: batch.id,
+            batch_no: batch.batch_no,
+            exp_date: batch.exp_date,
+            days_left,
+            status,
+        });
+    }
+
+    Ok(alerts)
+}
+
+/// Check interval from `EXPIRY_CHECK_INTERVAL_SECS`, defaulting to an hour.
+pub fn check_interval_from_env() -> Duration {
+    dotenv().ok();
+
+    env::var("EXPIRY_CHECK_INTERVAL_SECS")
+        .ok()
+        .and_then(|value| value.parse().ok())
+        .filter(|secs| *secs > 0)
+        .map(Duration::from_secs)
+        .unwrap_or(DEFAULT_CHECK_INTERVAL)
+}
+
+/// Runs [`check_expiry_alerts`] every `interval` for as long as the app
+/// lives, calling `on_alerts` with the client's id whenever its batches
+/// entered a window. Checks are skipped while the database is unavailable.
+///
+/// Only clients whose keys are on this machine are checked: sessions can
+/// only be opened with local keys, and other clients sharing the database
+/// must not have their stock reported here.
+pub fn monitor_expiry<F>(
+    database: &Database,
+    thresholds: &ExpiryThresholds,
+    interval: Duration,
+    on_alerts: F,
+) where
+    F: Fn(Uuid, &[ExpiryAlert]),
+{
+    loop {
+        let checked = local_client_ids()
+            .map_err(AppError::from)
+            .and_then(|client_ids| {
+                let mut conn = database.get()?;
+                for client_id in client_ids {
+                    let alerts = check_expiry_alerts(&mut conn, client_id, thresholds)?;
+                    if !alerts.is_empty() {
+                        on_alerts(client_id, &alerts);
+                    }
+                }
+                Ok(())
+            });
+        match checked {
+            Ok(()) | Err(AppError::DatabaseUnavailable(_)) => {}
+            Err(err) => eprintln!("Expiry check failed: {}", err),
+        }
+        thread::sleep(interval);
+    }
+}
@@ -1,41 +1,691 @@
 use aes_gcm::{
-    aead::{
-        consts::{U12, U32},
-        generic_array::GenericArray,
-        rand_core::RngCore,
-        Aead, OsRng,
-    },
+    aead::{consts::U12, generic_array::GenericArray, rand_core::RngCore, Aead, OsRng, Payload},
     Aes256Gcm, KeyInit, Nonce,
 };
+use app::AppError;
+use argon2::{Algorithm, Argon2, Params, Version};
 use base64::{engine::general_purpose, Engine};
-use core::panic;
+use chrono::NaiveDateTime;
 use dirs::data_dir;
 use dotenvy::dotenv;
-use ring::{rand, signature};
+use ring::{
+    digest, rand,
+    signature::{self, KeyPair},
+};
+use serde::{Deserialize, Serialize};
 use std::{
-    env,
+    env, fmt,
     fs::{self, File},
-    io::Write,
-    path::PathBuf,
+    io::{self, Write},
+    path::{Path, PathBuf},
+    sync::RwLock,
 };
 use uuid::Uuid;
+use zeroize::{Zeroize, ZeroizeOnDrop, Zeroizing};
 
-pub fn get_app_dir() -> PathBuf {
+pub fn get_app_dir() -> Result<PathBuf, KeyError> {
     data_dir()
-        .expect("Unable to get data directory")
-        .join("product-tracker-app")
+        .map(|dir| dir.join("product-tracker-app"))
+        .ok_or(KeyError::NoDataDirectory)
+}
+
+/// Directory holding the keys of `client_id`.
+pub fn client_dir(client_id: Uuid) -> Result<PathBuf, KeyError> {
+    Ok(get_app_dir()?.join(client_id.to_string()))
+}
+
+fn private_key_path(client_id: Uuid) -> Result<PathBuf, KeyError> {
+    Ok(client_dir(client_id)?.join("private_key"))
+}
+
+/// Failure handling key material on disk. Converts into
+/// [`AppError::KeyStore`] with a hint on how to recover.
+#[derive(Debug)]
+pub enum KeyError {
+    NoDataDirectory,
+    MissingDirectory(PathBuf),
+    MissingFile(PathBuf),
+    Corrupt(String),
+    WrongKey(String),
+    Io(String),
+    Crypto(String),
+}
+
+impl fmt::Display for KeyError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            KeyError::NoDataDirectory => {
+                write!(f, "Unable to find a data directory to keep keys in")
+            }
+            KeyError::MissingDirectory(path) => {
+                write!(f, "Key directory {} does not exist", path.display())
+            }
+            KeyError::MissingFile(path) => {
+                write!(f, "Private key file {} does not exist", path.display())
+            }
+            KeyError::Corrupt(reason) => write!(f, "Private key file is corrupt: {}", reason),
+            KeyError::WrongKey(reason) | KeyError::Io(reason) | KeyError::Crypto(reason) => {
+                write!(f, "{}", reason)
+            }
+        }
+    }
+}
+
+impl From<KeyError> for AppError {
+    fn from(err: KeyError) -> Self {
+        let (code, recovery) = match err {
+            KeyError::NoDataDirectory => (
+                "KEY_DIR_MISSING",
+                "The system did not report a folder for app data. Check that your user \
+                 account has a home folder, then restart the app.",
+            ),
+            KeyError::MissingDirectory(_) | KeyError::MissingFile(_) => (
+                "KEY_MISSING",
+                "This client's key is not on this machine. Import an identity backup, \
+                 or remove the client and register a new one.",
+            ),
+            KeyError::Corrupt(_) => (
+                "KEY_CORRUPT",
+                "The key file is damaged. Import an identity backup, or remove the client \
+                 and register a new one.",
+            ),
+            KeyError::WrongKey(_) => (
+                "KEY_WRONG",
+                "The key file was encrypted with a different key. Unlock with the passphrase \
+                 that was active when it was created, or check ENCRYPTION_KEY.",
+            ),
+            KeyError::Io(_) => (
+                "KEY_IO",
+                "Check that the app data folder exists, is writable and has free space, \
+                 then try again.",
+            ),
+            KeyError::Crypto(_) => (
+                "KEY_CRYPTO",
+                "Try again. If this keeps happening, restart the app.",
+            ),
+        };
+
+        AppError::KeyStore {
+            code,
+            message: err.to_string(),
+            recovery: recovery.to_string(),
+        }
+    }
+}
+
+fn io_error(action: &str) -> impl Fn(io::Error) -> KeyError + '_ {
+    move |e| KeyError::Io(format!("Failed to {}: {}", action, e))
+}
+
+/// Like [`io_error`], but reports a missing file or directory as such.
+fn read_error(path: &Path) -> impl Fn(io::Error) -> KeyError + '_ {
+    move |e| match e.kind() {
+        io::ErrorKind::NotFound => match path.parent() {
+            Some(parent) if !parent.exists() => KeyError::MissingDirectory(parent.to_path_buf()),
+            _ => KeyError::MissingFile(path.to_path_buf()),
+        },
+        _ => KeyError::Io(format!("Failed to read {}: {}", path.display(), e)),
+    }
+}
+
+/// Ids of every client with a key directory on this machine.
+pub fn local_client_ids() -> Result<Vec<Uuid>, KeyError> {
+    let app_data_dir = get_app_dir()?;
+    if !app_data_dir.exists() {
+        return Ok(Vec::new());
+    }
+
+    let entries = fs::read_dir(app_data_dir).map_err(io_error("read app data directory"))?;
+    Ok(entries
+        .flatten()
+        .filter(|entry| entry.file_type().map(|ft| ft.is_dir()).unwrap_or(false))
+        .filter_map(|entry| Uuid::parse_str(&entry.file_name().to_string_lossy()).ok())
+        .collect())
+}
+
+/// Deletes the key directory of `client_id` from this machine.
+pub fn remove_client_keys(client_id: Uuid) -> Result<(), KeyError> {
+    let dir = client_dir(client_id)?;
+    if !dir.exists() {
+        return Err(KeyError::MissingDirectory(dir));
+    }
+    fs::remove_dir_all(dir).map_err(io_error("remove client keys"))
+}
+
+/// A 256-bit AES key. Wiped when dropped, and deliberately neither `Debug`
+/// nor `Serialize`.
+#[derive(Clone, Default)]
+pub struct EncryptionKey([u8; 32]);
+
+impl EncryptionKey {
+    fn cipher(&self) -> Aes256Gcm {
+        Aes256Gcm::new(GenericArray::from_slice(&self.0))
+    }
+}
+
+impl Drop for EncryptionKey {
+    fn drop(&mut self) {
+        self.0.zeroize();
+    }
+}
+
+impl ZeroizeOnDrop for EncryptionKey {}
+
+/// Decrypted key material such as a PKCS#8 private key. Wiped when dropped,
+/// and deliberately neither `Debug` nor `Serialize`; read it through
+/// [`SecretBytes::expose`] only where the plaintext is needed.
+pub struct SecretBytes(Vec<u8>);
+
+impl SecretBytes {
+    pub fn expose(&self) -> &[u8] {
+        &self.0
+    }
+}
+
+impl From<Vec<u8>> for SecretBytes {
+    fn from(bytes: Vec<u8>) -> Self {
+        SecretBytes(bytes)
+    }
+}
+
+impl Drop for SecretBytes {
+    fn drop(&mut self) {
+        self.0.zeroize();
+    }
+}
+
+impl ZeroizeOnDrop for SecretBytes {}
+
+/// Version written to the header of new private key files.
+const KEY_FILE_VERSION: u32 = 1;
+const KEY_FILE_ALGORITHM: &str = "aes-256-gcm";
+
+/// Plaintext encrypted with the passphrase-derived key and stored next to
+/// the KDF parameters, so a wrong passphrase is caught on unlock instead of
+/// when a private key fails to decrypt.
+const PASSPHRASE_CHECK: &[u8] = b"product-tracker-passphrase-check";
+const MIN_PASSPHRASE_LENGTH: usize = 8;
+
+fn validate_passphrase(passphrase: &str) -> Result<(), AppError> {
+    if passphrase.chars().count() < MIN_PASSPHRASE_LENGTH {
+        return Err(AppError::validation(
+            "passphrase",
+            format!(
+                "Passphrase must be at least {} characters long",
+                MIN_PASSPHRASE_LENGTH
+            ),
+        ));
+    }
+    Ok(())
+}
+
+fn passphrase_config_path() -> Result<PathBuf, KeyError> {
+    Ok(get_app_dir()?.join("passphrase.json"))
+}
+
+/// Upper bounds on the Argon2id cost parameters read back from disk or from
+/// an identity bundle, well above [`KdfParams::generate`]'s defaults. Without
+/// them a crafted file could make unlocking allocate unbounded memory or run
+/// for hours.
+const MAX_KDF_MEMORY_KIB: u32 = 256 * 1024;
+const MAX_KDF_ITERATIONS: u32 = 16;
+const MAX_KDF_PARALLELISM: u32 = 16;
+
+/// Argon2id parameters and salt used to derive a key from a passphrase.
+/// Nothing in here is secret.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+pub struct KdfParams {
+    pub algorithm: String,
+    pub memory_kib: u32,
+    pub iterations: u32,
+    pub parallelism: u32,
+    pub salt: String,
+}
+
+impl KdfParams {
+    fn generate() -> Self {
+        let mut salt = [0u8; 16];
+        OsRng.fill_bytes(&mut salt);
+
+        KdfParams {
+            algorithm: "argon2id".to_string(),
+            memory_kib: Params::DEFAULT_M_COST,
+            iterations: Params::DEFAULT_T_COST,
+            parallelism: Params::DEFAULT_P_COST,
+            salt: general_purpose::STANDARD.encode(salt),
+        }
+    }
+
+    /// Rejects parameters above the fixed maxima, before any work is done.
+    fn check_limits(&self) -> Result<(), String> {
+        let limits = [
+            ("memory", self.memory_kib, MAX_KDF_MEMORY_KIB),
+            ("iterations", self.iterations, MAX_KDF_ITERATIONS),
+            ("parallelism", self.parallelism, MAX_KDF_PARALLELISM),
+        ];
+        match limits.iter().find(|(_, value, max)| value > max) {
+            Some((name, value, max)) => Err(format!(
+                "key derivation {} {} exceeds the maximum of {}",
+                name, value, max
+            )),
+            None => Ok(()),
+        }
+    }
+
+    fn derive_key(&self, passphrase: &str) -> Result<EncryptionKey, AppError> {
+        if self.algorithm != "argon2id" {
+            return Err(AppError::Internal(format!(
+                "Unsupported key derivation algorithm: {}",
+                self.algorithm
+            )));
+        }
+        self.check_limits().map_err(|reason| {
+            AppError::Internal(format!("Invalid key derivation parameters: {}", reason))
+        })?;
+        let params = Params::new(self.memory_kib, self.iterations, self.parallelism, Some(32))
+            .map_err(|e| AppError::Internal(format!("Invalid key derivation parameters: {}", e)))?;
+        let salt = general_purpose::STANDARD
+            .decode(&self.salt)
+            .map_err(|_| AppError::Internal("Passphrase salt is corrupt".to_string()))?;
+
+        let mut key = EncryptionKey::default();
+        Argon2::new(Algorithm::Argon2id, Version::V0x13, params)
+            .hash_password_into(passphrase.as_bytes(), &salt, &mut key.0)
+            .map_err(|e| AppError::Internal(format!("Failed to derive key: {}", e)))?;
+        Ok(key)
+    }
+}
+
+/// Where a key-encryption key comes from, recorded in every key file header.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+#[serde(tag = "source", rename_all = "snake_case")]
+pub enum KeySource {
+    Environment,
+    Passphrase(KdfParams),
+}
+
+/// The key protecting the private key files, together with where it came
+/// from.
+#[derive(Clone)]
+pub struct MasterKey {
+    key: EncryptionKey,
+    source: KeySource,
+}
+
+impl MasterKey {
+    /// Short fingerprint written into key file headers, so a file can be
+    /// matched with the key that encrypted it without trying to decrypt it.
+    pub fn id(&self) -> String {
+        let mut input = Zeroizing::new(b"product-tracker-key-id:".to_vec());
+        input.extend_from_slice(&self.key.0);
+        digest::digest(&digest::SHA256, &input).as_ref()[..8]
+            .iter()
+            .map(|byte| format!("{:02x}", byte))
+            .collect()
+    }
 }
 
-fn get_encryption_key() -> GenericArray<u8, U32> {
+#[derive(Serialize, Deserialize)]
+struct PassphraseConfig {
+    #[serde(flatten)]
+    kdf: KdfParams,
+    check: String,
+}
+
+impl PassphraseConfig {
+    fn load() -> Result<Self, AppError> {
+        let contents = fs::read_to_string(passphrase_config_path()?).map_err(|e| {
+            AppError::Internal(format!("Failed to read passphrase settings: {}", e))
+        })?;
+        serde_json::from_str(&contents)
+            .map_err(|e| AppError::Internal(format!("Passphrase settings are corrupt: {}", e)))
+    }
+
+    fn save(&self) -> Result<(), AppError> {
+        let path = passphrase_config_path()?;
+        let staged = path.with_extension("json.new");
+        let contents =
+            serde_json::to_string_pretty(self).map_err(|e| AppError::Internal(e.to_string()))?;
+        fs::create_dir_all(get_app_dir()?)
+            .and_then(|_| fs::write(&staged, contents))
+            .and_then(|_| fs::rename(&staged, &path))
+            .map_err(|e| AppError::Internal(format!("Failed to save passphrase settings: {}", e)))
+    }
+
+    /// Derives a key from `passphrase` with fresh parameters and returns it
+    /// with the settings needed to derive it again.
+    fn create(passphrase: &str) -> Result<(Self, MasterKey), AppError> {
+        validate_passphrase(passphrase)?;
+
+        let kdf = KdfParams::generate();
+        let key = kdf.derive_key(passphrase)?;
+        let config = PassphraseConfig {
+            check: general_purpose::STANDARD.encode(encrypt(&key, PASSPHRASE_CHECK)?),
+            kdf: kdf.clone(),
+        };
+        Ok((
+            config,
+            MasterKey {
+                key,
+                source: KeySource::Passphrase(kdf),
+            },
+        ))
+    }
+
+    /// Derives the key for `passphrase`, failing if it is the wrong one.
+    fn unlock(&self, passphrase: &str) -> Result<MasterKey, AppError> {
+        let key = self.kdf.derive_key(passphrase)?;
+        let check = general_purpose::STANDARD
+            .decode(&self.check)
+            .map_err(|_| AppError::Internal("Passphrase settings are corrupt".to_string()))?;
+
+        match decrypt(&key, &check) {
+            Ok(plaintext) if plaintext.expose() == PASSPHRASE_CHECK => {}
+            Ok(_) | Err(KeyError::WrongKey(_)) => {
+                return Err(AppError::Unauthorized("Wrong passphrase".to_string()))
+            }
+            Err(_) => {
+                return Err(AppError::Internal(
+                    "Passphrase settings are corrupt".to_string(),
+                ))
+            }
+        }
+
+        Ok(MasterKey {
+            key,
+            source: KeySource::Passphrase(self.kdf.clone()),
+        })
+    }
+}
+
+/// The legacy key taken verbatim from the `ENCRYPTION_KEY` variable.
+fn env_encryption_key() -> Option<Result<MasterKey, AppError>> {
     dotenv().ok();
-    let key = env::var("ENCRYPTION_KEY").expect("ENCRYPTON_KEY must be set");
+    let key = Zeroizing::new(env::var("ENCRYPTION_KEY").ok()?);
     let key_bytes = key.as_bytes();
 
     if key_bytes.len() != 32 {
-        panic!("ENCRYPTION_KEY must be exactly 32 bytes long");
+        return Some(Err(AppError::Internal(
+            "ENCRYPTION_KEY must be exactly 32 bytes long".to_string(),
+        )));
+    }
+
+    Some(Ok(MasterKey {
+        key: {
+            let mut key = EncryptionKey::default();
+            key.0.copy_from_slice(key_bytes);
+            key
+        },
+        source: KeySource::Environment,
+    }))
+}
+
+/// Where the key protecting the private key files comes from. A passphrase,
+/// once set, takes precedence over `ENCRYPTION_KEY`.
+#[derive(Serialize, Clone, Copy, Debug, PartialEq, Eq)]
+#[serde(tag = "mode", rename_all = "snake_case")]
+pub enum KeyringStatus {
+    Uninitialized,
+    Environment,
+    Passphrase { locked: bool },
+}
+
+/// Holds the passphrase-derived key while the app is unlocked.
+#[derive(Default)]
+pub struct Keyring {
+    unlocked: RwLock<Option<MasterKey>>,
+}
+
+impl Keyring {
+    fn unlocked_key(&self) -> Option<MasterKey> {
+        self.unlocked
+            .read()
+            .unwrap_or_else(|e| e.into_inner())
+            .clone()
+    }
+
+    fn set_unlocked(&self, key: Option<MasterKey>) {
+        *self.unlocked.write().unwrap_or_else(|e| e.into_inner()) = key;
+    }
+
+    pub fn status(&self) -> KeyringStatus {
+        if passphrase_config_path().is_ok_and(|path| path.exists()) {
+            KeyringStatus::Passphrase {
+                locked: self.unlocked_key().is_none(),
+            }
+        } else if env_encryption_key().is_some() {
+            KeyringStatus::Environment
+        } else {
+            KeyringStatus::Uninitialized
+        }
+    }
+
+    /// The key to encrypt and decrypt private key files with.
+    pub fn key(&self) -> Result<MasterKey, AppError> {
+        match self.status() {
+            KeyringStatus::Passphrase { locked: false } => {
+                self.unlocked_key().ok_or_else(locked_error)
+            }
+            KeyringStatus::Passphrase { locked: true } => Err(locked_error()),
+            KeyringStatus::Environment => env_encryption_key().unwrap_or_else(|| {
+                Err(AppError::Internal("ENCRYPTION_KEY is not set".to_string()))
+            }),
+            KeyringStatus::Uninitialized => Err(AppError::Locked(
+                "No encryption key is configured, set a passphrase first".to_string(),
+            )),
+        }
+    }
+
+    pub fn unlock(&self, passphrase: &str) -> Result<KeyringStatus, AppError> {
+        if !passphrase_config_path()?.exists() {
+            return Err(AppError::Conflict("No passphrase has been set".to_string()));
+        }
+
+        let key = PassphraseConfig::load()?.unlock(passphrase)?;
+        self.set_unlocked(Some(key));
+        Ok(self.status())
+    }
+
+    pub fn lock(&self) -> KeyringStatus {
+        self.set_unlocked(None);
+        self.status()
+    }
+
+    /// Switches to passphrase mode. Private keys already protected by
+    /// `ENCRYPTION_KEY` are re-encrypted with the derived key.
+    pub fn set_passphrase(&self, passphrase: &str) -> Result<KeyringStatus, AppError> {
+        if passphrase_config_path()?.exists() {
+            return Err(AppError::Conflict(
+                "A passphrase is already set".to_string(),
+            ));
+        }
+
+        let (config, next) = PassphraseConfig::create(passphrase)?;
+        let current = env_encryption_key().transpose()?;
+        rotate_keys(current.as_ref(), &next, &config)?;
+
+        self.set_unlocked(Some(next));
+        Ok(self.status())
+    }
+
+    /// Replaces the passphrase and re-encrypts every private key with the
+    /// key derived from the new one.
+    pub fn rotate(
+        &self,
+        current_passphrase: &str,
+        new_passphrase: &str,
+    ) -> Result<KeyringStatus, AppError> {
+        if !passphrase_config_path()?.exists() {
+            return Err(AppError::Conflict(
+                "Set a passphrase before rotating the encryption key".to_string(),
+            ));
+        }
+
+        let current = PassphraseConfig::load()?.unlock(current_passphrase)?;
+        let (config, next) = PassphraseConfig::create(new_passphrase)?;
+        rotate_keys(Some(&current), &next, &config)?;
+
+        self.set_unlocked(Some(next));
+        Ok(self.status())
+    }
+}
+
+fn locked_error() -> AppError {
+    AppError::Locked("Keys are locked, unlock them with your passphrase".to_string())
+}
+
+/// Copy of every private key file and of the passphrase settings taken
+/// before a rotation touches anything. Only removed once the rotation has
+/// fully succeeded.
+fn rotation_backup_dir() -> Result<PathBuf, KeyError> {
+    Ok(get_app_dir()?.join("rotation-backup"))
+}
+
+/// Written last into the backup directory and removed first once the
+/// rotation succeeded; a backup without it is either unfinished or no
+/// longer needed, and the originals must not be restored from it.
+const BACKUP_COMPLETE_MARKER: &str = "complete";
+
+fn staged_path(path: &Path) -> PathBuf {
+    path.with_extension("new")
+}
+
+fn create_rotation_backup(client_ids: &[Uuid]) -> Result<(), KeyError> {
+    let backup_dir = rotation_backup_dir()?;
+    fs::create_dir_all(&backup_dir).map_err(io_error("create key backup"))?;
+
+    for client_id in client_ids {
+        fs::copy(
+            private_key_path(*client_id)?,
+            backup_dir.join(client_id.to_string()),
+        )
+        .map_err(io_error("back up private key"))?;
+    }
+    let config_path = passphrase_config_path()?;
+    if config_path.exists() {
+        fs::copy(config_path, backup_dir.join("passphrase.json"))
+            .map_err(io_error("back up passphrase settings"))?;
+    }
+
+    fs::write(backup_dir.join(BACKUP_COMPLETE_MARKER), b"").map_err(io_error("finish key backup"))
+}
+
+fn restore_rotation_backup() -> Result<(), KeyError> {
+    let backup_dir = rotation_backup_dir()?;
+
+    for client_id in local_client_ids()? {
+        let staged = staged_path(&private_key_path(client_id)?);
+        if staged.exists() {
+            fs::remove_file(staged).map_err(io_error("remove staged private key"))?;
+        }
+
+        let backup = backup_dir.join(client_id.to_string());
+        if backup.exists() {
+            fs::copy(backup, private_key_path(client_id)?)
+                .map_err(io_error("restore private key"))?;
+        }
+    }
+
+    let config_path = passphrase_config_path()?;
+    let config_backup = backup_dir.join("passphrase.json");
+    if config_backup.exists() {
+        fs::copy(config_backup, config_path).map_err(io_error("restore passphrase settings"))?;
+    } else if config_path.exists() {
+        fs::remove_file(config_path).map_err(io_error("restore passphrase settings"))?;
+    }
+
+    fs::remove_dir_all(backup_dir).map_err(io_error("remove key backup"))
+}
+
+/// Puts the key files back the way they were if the app stopped in the
+/// middle of a rotation. Returns whether anything was restored.
+pub fn recover_interrupted_rotation() -> Result<bool, KeyError> {
+    let backup_dir = rotation_backup_dir()?;
+    if !backup_dir.exists() {
+        return Ok(false);
+    }
+
+    if backup_dir.join(BACKUP_COMPLETE_MARKER).exists() {
+        restore_rotation_backup()?;
+        return Ok(true);
+    }
+
+    fs::remove_dir_all(backup_dir).map_err(io_error("remove key backup"))?;
+    Ok(false)
+}
+
+/// Re-encrypts every local private key from `current` to `next` and saves
+/// `config` as the passphrase settings, as a single all-or-nothing step.
+///
+/// The new files are staged next to the originals and checked before
+/// anything is replaced. If a later step fails the backup taken up front is
+/// restored, and [`recover_interrupted_rotation`] does the same on the next
+/// start if the app dies midway.
+fn rotate_keys(
+    current: Option<&MasterKey>,
+    next: &MasterKey,
+    config: &PassphraseConfig,
+) -> Result<(), AppError> {
+    recover_interrupted_rotation()?;
+
+    let client_ids = local_client_ids()?;
+    let mut private_keys = Vec::new();
+    if !client_ids.is_empty() {
+        let current = current.ok_or_else(|| {
+            AppError::Conflict(
+                "Existing private keys can't be read without the current key".to_string(),
+            )
+        })?;
+        for client_id in &client_ids {
+            let private_key = read_private_key(current, &private_key_path(*client_id)?)?;
+            private_keys.push((*client_id, private_key));
+        }
     }
 
-    GenericArray::clone_from_slice(&key_bytes)
+    create_rotation_backup(&client_ids)?;
+
+    let rotated = (|| {
+        for (client_id, private_key) in &private_keys {
+            let staged = staged_path(&private_key_path(*client_id)?);
+            write_private_key(next, private_key.expose(), &staged)?;
+            if read_private_key(next, &staged)?.expose() != private_key.expose() {
+                return Err(KeyError::Crypto(
+                    "Re-encrypted private key did not verify".to_string(),
+                )
+                .into());
+            }
+        }
+
+        config.save()?;
+        for (client_id, _) in &private_keys {
+            let path = private_key_path(*client_id)?;
+            fs::rename(staged_path(&path), &path).map_err(io_error("replace private key"))?;
+        }
+        Ok(())
+    })();
+
+    // Removing the marker is what commits the rotation: from then on the
+    // backup is just leftovers, which the next start discards if they can't
+    // be removed here.
+    let backup_dir = rotation_backup_dir()?;
+    let committed = rotated.and_then(|()| {
+        fs::remove_file(backup_dir.join(BACKUP_COMPLETE_MARKER))
+            .map_err(io_error("finish key rotation"))?;
+        Ok(())
+    });
+
+    match committed {
+        Ok(()) => {
+            if let Err(err) = fs::remove_dir_all(&backup_dir) {
+                eprintln!("Failed to remove key backup: {}", err);
+            }
+            Ok(())
+        }
+        Err(err) => {
+            restore_rotation_backup()?;
+            Err(err)
+        }
+    }
 }
 
 pub fn generate_nonce() -> Nonce<U12> {
@@ -44,55 +694,294 @@ pub fn generate_nonce() -> Nonce<U12> {
     Nonce::clone_from_slice(&nonce)
 }
 
-pub fn encrypt(data: &[u8]) -> Vec<u8> {
-    let key = get_encryption_key();
-    let cipher = Aes256Gcm::new(&key);
+pub fn encrypt(key: &EncryptionKey, data: &[u8]) -> Result<Vec<u8>, KeyError> {
+    let cipher = key.cipher();
     let nonce = generate_nonce();
     let mut ciphertext = nonce.as_slice().to_vec();
-    ciphertext.extend(cipher.encrypt(&nonce, data).expect("encryption failure!"));
-    ciphertext
+    ciphertext.extend(
+        cipher
+            .encrypt(&nonce, data)
+            .map_err(|_| KeyError::Crypto("Encryption failed".to_string()))?,
+    );
+    Ok(ciphertext)
 }
 
-pub fn decrypt(data: &[u8]) -> Vec<u8> {
-    let key = get_encryption_key();
-    let cipher = Aes256Gcm::new(&key);
+/// Reverses [`encrypt`]. Data too short to hold the 12-byte nonce is
+/// reported as corrupt rather than split.
+pub fn decrypt(key: &EncryptionKey, data: &[u8]) -> Result<SecretBytes, KeyError> {
+    if data.len() <= 12 {
+        return Err(KeyError::Corrupt(format!(
+            "expected more than 12 bytes, found {}",
+            data.len()
+        )));
+    }
+
+    let cipher = key.cipher();
     let (nonce, ciphertext) = data.split_at(12);
     let nonce = Nonce::<U12>::from_slice(nonce);
     cipher
         .decrypt(nonce, ciphertext)
-        .expect("decryption failure!")
+        .map(SecretBytes::from)
+        .map_err(|_| KeyError::WrongKey("Failed to decrypt private key".to_string()))
+}
+
+/// On-disk format of a private key file. Files written before the format
+/// was versioned hold bare base64 of `nonce || ciphertext` and are still
+/// read; they are rewritten in this format on the next rotation.
+#[derive(Serialize, Deserialize)]
+struct KeyFile {
+    version: u32,
+    key_id: String,
+    algorithm: String,
+    kdf: KeySource,
+    nonce: String,
+    ciphertext: String,
+}
+
+impl KeyFile {
+    /// The header fields are authenticated along with the ciphertext, so
+    /// they can't be swapped without the file failing to decrypt.
+    fn associated_data(&self) -> String {
+        format!("{}:{}:{}", self.version, self.algorithm, self.key_id)
+    }
+}
+
+fn write_private_key(key: &MasterKey, private_key: &[u8], path: &Path) -> Result<(), KeyError> {
+    let nonce = generate_nonce();
+    let mut key_file = KeyFile {
+        version: KEY_FILE_VERSION,
+        key_id: key.id(),
+        algorithm: KEY_FILE_ALGORITHM.to_string(),
+        kdf: key.source.clone(),
+        nonce: general_purpose::STANDARD.encode(nonce),
+        ciphertext: String::new(),
+    };
+    let aad = key_file.associated_data();
+    let ciphertext = key
+        .key
+        .cipher()
+        .encrypt(
+            &nonce,
+            Payload {
+                msg: private_key,
+                aad: aad.as_bytes(),
+            },
+        )
+        .map_err(|_| KeyError::Crypto("Failed to encrypt private key".to_string()))?;
+    key_file.ciphertext = general_purpose::STANDARD.encode(ciphertext);
+
+    let contents = serde_json::to_string_pretty(&key_file)
+        .map_err(|e| KeyError::Crypto(format!("Failed to encode private key: {}", e)))?;
+    let mut file = File::create(path).map_err(io_error("create private key file"))?;
+    file.write_all(contents.as_bytes())
+        .and_then(|_| file.sync_all())
+        .map_err(io_error("write private key"))
 }
 
-pub fn store_private_key(private_key: &[u8], client_id: Uuid) -> String {
-    let app_data_dir = get_app_dir();
-    let client_dir = app_data_dir.join(client_id.to_string());
-    fs::create_dir_all(&client_dir).expect("Failed to create client directory");
+fn read_private_key(key: &MasterKey, path: &Path) -> Result<SecretBytes, KeyError> {
+    let contents = fs::read_to_string(path).map_err(read_error(path))?;
 
-    let encrypted_key = encrypt(private_key);
-    let encoded_key = general_purpose::STANDARD.encode(encrypted_key);
+    if !contents.trim_start().starts_with('{') {
+        let encrypted_key = general_purpose::STANDARD
+            .decode(contents.trim())
+            .map_err(|_| KeyError::Corrupt("not valid base64".to_string()))?;
+        return decrypt(&key.key, &encrypted_key);
+    }
 
-    let key_path = client_dir.join("private_key");
-    let mut file = File::create(&key_path).expect("Failed to create private key file");
-    file.write_all(encoded_key.as_bytes())
-        .expect("Failed to write private key");
+    let key_file: KeyFile =
+        serde_json::from_str(&contents).map_err(|e| KeyError::Corrupt(e.to_string()))?;
+    if key_file.version != KEY_FILE_VERSION || key_file.algorithm != KEY_FILE_ALGORITHM {
+        return Err(KeyError::Corrupt(format!(
+            "unsupported format (version {}, {})",
+            key_file.version, key_file.algorithm
+        )));
+    }
+    if key_file.key_id != key.id() {
+        return Err(KeyError::WrongKey(format!(
+            "Private key was encrypted with a different key ({})",
+            key_file.key_id
+        )));
+    }
 
-    key_path.to_str().unwrap().to_string()
+    let nonce = general_purpose::STANDARD
+        .decode(&key_file.nonce)
+        .ok()
+        .filter(|nonce| nonce.len() == 12)
+        .ok_or_else(|| KeyError::Corrupt("bad nonce".to_string()))?;
+    let ciphertext = general_purpose::STANDARD
+        .decode(&key_file.ciphertext)
+        .map_err(|_| KeyError::Corrupt("bad ciphertext".to_string()))?;
+    let aad = key_file.associated_data();
+    key.key
+        .cipher()
+        .decrypt(
+            Nonce::<U12>::from_slice(&nonce),
+            Payload {
+                msg: &ciphertext,
+                aad: aad.as_bytes(),
+            },
+        )
+        .map(SecretBytes::from)
+        .map_err(|_| KeyError::Corrupt("contents do not match the header".to_string()))
 }
 
-pub fn load_private_key(path: &str) -> Vec<u8> {
-    let encoded_key = fs::read_to_string(path).expect("Failed to read private key file");
-    let encrypted_key = general_purpose::STANDARD
-        .decode(&encoded_key)
-        .expect("Failed to decode private key");
-    decrypt(&encrypted_key)
+pub fn store_private_key(
+    key: &MasterKey,
+    private_key: &[u8],
+    client_id: Uuid,
+) -> Result<String, KeyError> {
+    let client_dir = client_dir(client_id)?;
+    fs::create_dir_all(&client_dir).map_err(io_error("create client directory"))?;
+
+    let key_path = private_key_path(client_id)?;
+    write_private_key(key, private_key, &key_path)?;
+
+    Ok(key_path.to_string_lossy().into_owned())
 }
 
-pub fn generate_key_pair(client_id: Uuid) -> String {
+pub fn load_private_key(key: &MasterKey, path: &str) -> Result<SecretBytes, KeyError> {
+    read_private_key(key, Path::new(path))
+}
+
+/// Generates a new Ed25519 key pair for `client_id`, stores the encrypted
+/// private key and returns its path together with the raw public key.
+pub fn generate_key_pair(key: &MasterKey, client_id: Uuid) -> Result<(String, Vec<u8>), KeyError> {
     let rng = rand::SystemRandom::new();
-    let key_pair =
-        signature::Ed25519KeyPair::generate_pkcs8(&rng).expect("Failed to generate key pair");
+    // ring's `Document` is not wiped when dropped, so it only lives long
+    // enough to be copied into `SecretBytes`.
+    let pkcs8 = signature::Ed25519KeyPair::generate_pkcs8(&rng)
+        .map(|document| SecretBytes::from(document.as_ref().to_vec()))
+        .map_err(|_| KeyError::Crypto("Failed to generate key pair".to_string()))?;
+    let key_pair = signature::Ed25519KeyPair::from_pkcs8(pkcs8.expose())
+        .map_err(|_| KeyError::Crypto("Failed to read generated key pair".to_string()))?;
+
+    let private_key_path = store_private_key(key, pkcs8.expose(), client_id)?;
+
+    Ok((private_key_path, key_pair.public_key().as_ref().to_vec()))
+}
+
+const IDENTITY_BUNDLE_FORMAT: &str = "product-tracker-identity";
+const IDENTITY_BUNDLE_VERSION: u32 = 1;
+
+/// Client details carried in the clear by an identity bundle. They are
+/// authenticated together with the encrypted key, so editing them makes the
+/// import fail.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct IdentityMetadata {
+    pub client_id: Uuid,
+    pub name: String,
+    pub public_key: String,
+    pub exported_at: NaiveDateTime,
+}
+
+/// Portable backup of one client identity: its private key encrypted with a
+/// key derived from a passphrase chosen at export, independent of the key
+/// protecting the files on this machine.
+#[derive(Serialize, Deserialize)]
+struct IdentityBundle {
+    format: String,
+    version: u32,
+    #[serde(flatten)]
+    metadata: IdentityMetadata,
+    kdf: KdfParams,
+    nonce: String,
+    ciphertext: String,
+}
 
-    let private_key_path = store_private_key(key_pair.as_ref(), client_id);
+fn bundle_associated_data(metadata: &IdentityMetadata) -> Result<String, AppError> {
+    serde_json::to_string(metadata).map_err(|e| AppError::Internal(e.to_string()))
+}
+
+fn invalid_bundle(reason: &str) -> AppError {
+    AppError::validation("bundle", format!("Not a valid identity backup: {}", reason))
+}
+
+/// Encrypts the private key of `metadata.client_id` under `passphrase` and
+/// returns the bundle as JSON.
+pub fn export_identity(
+    key: &MasterKey,
+    metadata: IdentityMetadata,
+    passphrase: &str,
+) -> Result<String, AppError> {
+    validate_passphrase(passphrase)?;
+    let private_key = read_private_key(key, &private_key_path(metadata.client_id)?)?;
+
+    let kdf = KdfParams::generate();
+    let bundle_key = kdf.derive_key(passphrase)?;
+    let nonce = generate_nonce();
+    let aad = bundle_associated_data(&metadata)?;
+    let ciphertext = bundle_key
+        .cipher()
+        .encrypt(
+            &nonce,
+            Payload {
+                msg: private_key.expose(),
+                aad: aad.as_bytes(),
+            },
+        )
+        .map_err(|_| KeyError::Crypto("Failed to encrypt identity backup".to_string()))?;
+
+    let bundle = IdentityBundle {
+        format: IDENTITY_BUNDLE_FORMAT.to_string(),
+        version: IDENTITY_BUNDLE_VERSION,
+        metadata,
+        kdf,
+        nonce: general_purpose::STANDARD.encode(nonce),
+        ciphertext: general_purpose::STANDARD.encode(ciphertext),
+    };
+    serde_json::to_string_pretty(&bundle).map_err(|e| AppError::Internal(e.to_string()))
+}
+
+/// Decrypts a bundle made by [`export_identity`], returning its metadata and
+/// the PKCS#8 private key after checking the key matches the public key in
+/// the metadata.
+pub fn open_identity_bundle(
+    bundle: &str,
+    passphrase: &str,
+) -> Result<(IdentityMetadata, SecretBytes), AppError> {
+    let bundle: IdentityBundle =
+        serde_json::from_str(bundle).map_err(|e| invalid_bundle(&e.to_string()))?;
+    if bundle.format != IDENTITY_BUNDLE_FORMAT || bundle.version != IDENTITY_BUNDLE_VERSION {
+        return Err(invalid_bundle(&format!(
+            "unsupported format {} version {}",
+            bundle.format, bundle.version
+        )));
+    }
+
+    let nonce = general_purpose::STANDARD
+        .decode(&bundle.nonce)
+        .ok()
+        .filter(|nonce| nonce.len() == 12)
+        .ok_or_else(|| invalid_bundle("bad nonce"))?;
+    let ciphertext = general_purpose::STANDARD
+        .decode(&bundle.ciphertext)
+        .map_err(|_| invalid_bundle("bad ciphertext"))?;
+
+    bundle
+        .kdf
+        .check_limits()
+        .map_err(|reason| invalid_bundle(&reason))?;
+    let bundle_key = bundle.kdf.derive_key(passphrase)?;
+    let aad = bundle_associated_data(&bundle.metadata)?;
+    let private_key = bundle_key
+        .cipher()
+        .decrypt(
+            Nonce::<U12>::from_slice(&nonce),
+            Payload {
+                msg: &ciphertext,
+                aad: aad.as_bytes(),
+            },
+        )
+        .map(SecretBytes::from)
+        .map_err(|_| {
+            AppError::Unauthorized("Wrong passphrase, or the backup has been modified".to_string())
+        })?;
+
+    let key_pair = signature::Ed25519KeyPair::from_pkcs8(private_key.expose())
+        .map_err(|_| invalid_bundle("the key is not a valid Ed25519 key"))?;
+    if general_purpose::STANDARD.encode(key_pair.public_key()) != bundle.metadata.public_key {
+        return Err(invalid_bundle("the key does not match its public key"));
+    }
 
-    private_key_path
+    Ok((bundle.metadata, private_key))
 }
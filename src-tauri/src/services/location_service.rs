@@ -0,0 +1,532 @@
+use app::{AppError, DbConnection};
+use chrono::Utc;
+use diesel::{
+    Connection, ExpressionMethods, OptionalExtension, QueryDsl, RunQueryDsl, SelectableHelper,
+};
+use serde::Serialize;
+use std::collections::{HashMap, HashSet};
+use uuid::Uuid;
+
+use super::{
+    batch_details_service::get_batch_detail,
+    stock_service::{on_hand_units, units_in, StockLevel},
+};
+use crate::models::{
+    BatchDetail, BinStock, Location, LocationKind, NewLocation, NewStockTransfer, Product,
+    QuantityUnit, StockTransfer,
+};
+use crate::schema::{batch_details, bin_stock, locations, products, stock_transfers};
+
+const MAX_CODE_LENGTH: usize = 50;
+const MAX_NAME_LENGTH: usize = 255;
+
+/// A location with its full path, e.g. `WH1 / A / R01 / B03`.
+#[derive(Serialize)]
+pub struct LocationEntry {
+    #[serde(flatten)]
+    pub location: Location,
+    pub path: String,
+}
+
+#[derive(Serialize)]
+pub struct BinPlacement {
+    pub location_id: Uuid,
+    pub path: String,
+    pub stock: StockLevel,
+}
+
+/// Where the stock of one batch is. `unplaced` is stock on hand that is in
+/// no bin. `excess_in_bins` is how many units the bins hold beyond what is
+/// on hand; stock leaving the batch is taken out of its bins as it goes, so
+/// only bins filled before that was done can show an excess.
+#[derive(Serialize)]
+pub struct BatchLocations {
+    pub batch: BatchDetail,
+    pub on_hand: StockLevel,
+    pub bins: Vec<BinPlacement>,
+    pub unplaced: StockLevel,
+    pub excess_in_bins: i64,
+}
+
+#[derive(Serialize)]
+pub struct BinBatch {
+    pub location_id: Uuid,
+    pub path: String,
+    pub product_id: Uuid,
+    pub product_name: String,
+    pub batch: BatchDetail,
+    pub stock: StockLevel,
+}
+
+/// Everything stored in a location and the locations inside it.
+#[derive(Serialize)]
+pub struct LocationStock {
+    pub location: LocationEntry,
+    pub batches: Vec<BinBatch>,
+}
+
+fn client_locations(conn: &mut DbConnection, _client_id: Uuid) -> Result<Vec<Location>, AppError> {
+    locations::table
+        .filter(locations::client_id.eq(_client_id))
+        .select(Location::as_select())
+        .load(conn)
+        .map_err(AppError::from)
+}
+
+/// Full paths of `all`, keyed by location id.
+fn location_paths(all: &[Location]) -> HashMap<Uuid, String> {
+    let by_id: HashMap<Uuid, &Location> = all.iter().map(|l| (l.id, l)).collect();
+
+    all.iter()
+        .map(|location| {
+            let mut codes = vec![location.code.as_str()];
+            let mut parent = location.parent_id;
+            // The hierarchy is at most four levels deep, so this always ends.
+            while let Some(parent_location) = parent.and_then(|id| by_id.get(&id)) {
+                codes.push(&parent_location.code);
+                parent = parent_location.parent_id;
+            }
+            codes.reverse();
+            (location.id, codes.join(" / "))
+        })
+        .collect()
+}
+
+fn find_location(
+    conn: &mut DbConnection,
+    _client_id: Uuid,
+    location_id: Uuid,
+) -> Result<Location, AppError> {
+    locations::table
+        .find(location_id)
+        .filter(locations::client_id.eq(_client_id))
+        .select(Location::as_select())
+        .get_result(conn)
+        .map_err(AppError::from)
+}
+
+/// Adds a warehouse, or a zone, rack or bin inside `parent_id`. Codes are
+/// unique among siblings.
+pub fn create_location(
+    conn: &mut DbConnection,
+    _client_id: Uuid,
+    parent_id: Option<Uuid>,
+    kind: LocationKind,
+    code: &str,
+    name: Option<&str>,
+) -> Result<LocationEntry, AppError> {
+    let code = code.trim();
+    if code.is_empty() {
+        return Err(AppError::validation("code", "Location code is required"));
+    }
+    if code.chars().count() > MAX_CODE_LENGTH {
+        return Err(AppError::validation(
+            "code",
+            format!(
+                "Location code cannot be longer than {} characters",
+                MAX_CODE_LENGTH
+            ),
+        ));
+    }
+    let name = name.map(str::trim).filter(|name| !name.is_empty());
+    if name.is_some_and(|name| name.chars().count() > MAX_NAME_LENGTH) {
+        return Err(AppError::validation(
+            "name",
+            format!(
+                "Location name cannot be longer than {} characters",
+                MAX_NAME_LENGTH
+            ),
+        ));
+    }
+
+    match (kind.parent_kind(), parent_id) {
+        (None, Some(_)) => {
+            return Err(AppError::validation(
+                "parent_id",
+                "A warehouse cannot be inside another location",
+            ));
+        }
+        (Some(expected), None) => {
+            return Err(AppError::validation(
+                "parent_id",
+                format!("A {} must be inside a {}", kind.as_str(), expected.as_str()),
+            ));
+        }
+        (Some(expected), Some(parent_id)) => {
+            let parent = find_location(conn, _client_id, parent_id)?;
+            if parent.kind != expected {
+                return Err(AppError::validation(
+                    "parent_id",
+                    format!(
+                        "A {} must be inside a {}, not a {}",
+                        kind.as_str(),
+                        expected.as_str(),
+                        parent.kind.as_str()
+                    ),
+                ));
+            }
+        }
+        (None, None) => {}
+    }
+
+    let location = diesel::insert_into(locations::table)
+        .values(&NewLocation {
+            client_id: _client_id,
+            parent_id,
+            kind,
+            code,
+            name,
+        })
+        .returning(Location::as_returning())
+        .get_result(conn)
+        .map_err(AppError::from)?;
+
+    let all = client_locations(conn, _client_id)?;
+    let path = location_paths(&all)
+        .remove(&location.id)
+        .unwrap_or_else(|| location.code.clone());
+    Ok(LocationEntry { location, path })
+}
+
+/// Every location of `_client_id`, ordered by path so parents come before
+/// their children.
+pub fn list_locations(
+    conn: &mut DbConnection,
+    _client_id: Uuid,
+) -> Result<Vec<LocationEntry>, AppError> {
+    let all = client_locations(conn, _client_id)?;
+    let mut paths = location_paths(&all);
+
+    let mut entries: Vec<LocationEntry> = all
+        .into_iter()
+        .map(|location| LocationEntry {
+            path: paths.remove(&location.id).unwrap_or_default(),
+            location,
+        })
+        .collect();
+    entries.sort_by(|a, b| a.path.cmp(&b.path));
+    Ok(entries)
+}
+
+fn batch_bins(conn: &mut DbConnection, batch_id: Uuid) -> Result<Vec<BinStock>, AppError> {
+    bin_stock::table
+        .filter(bin_stock::batch_id.eq(batch_id))
+        .select(BinStock::as_select())
+        .load(conn)
+        .map_err(AppError::from)
+}
+
+fn placements(
+    conn: &mut DbConnection,
+    _client_id: Uuid,
+    batch: BatchDetail,
+) -> Result<BatchLocations, AppError> {
+    let on_hand_units = on_hand_units(conn, &[batch.id])?
+        .get(&batch.id)
+        .copied()
+        .unwrap_or(0);
+    let paths = location_paths(&client_locations(conn, _client_id)?);
+
+    let mut bins: Vec<BinPlacement> = batch_bins(conn, batch.id)?
+        .into_iter()
+        .map(|bin| BinPlacement {
+            location_id: bin.location_id,
+            path: paths.get(&bin.location_id).cloned().unwrap_or_default(),
+            stock: StockLevel::new(&batch, bin.units),
+        })
+        .collect();
+    bins.sort_by(|a, b| a.path.cmp(&b.path));
+
+    let placed: i64 = bins.iter().map(|bin| bin.stock.units).sum();
+    Ok(BatchLocations {
+        on_hand: StockLevel::new(&batch, on_hand_units),
+        unplaced: StockLevel::new(&batch, (on_hand_units - placed).max(0)),
+        excess_in_bins: (placed - on_hand_units).max(0),
+        bins,
+        batch,
+    })
+}
+
+/// Takes stock that just left a batch out of its bins, so they hold no more
+/// than the `on_hand` units left. Stock in no bin is used up first; after
+/// that the emptiest bins are cleared first. The caller must hold the lock
+/// on the batch row, as [`move_stock`] does.
+pub fn draw_down_bins(
+    conn: &mut DbConnection,
+    batch_id: Uuid,
+    on_hand: i64,
+) -> Result<(), AppError> {
+    let mut bins = batch_bins(conn, batch_id)?;
+    let mut excess = bins.iter().map(|bin| bin.units).sum::<i64>() - on_hand;
+    if excess <= 0 {
+        return Ok(());
+    }
+
+    bins.sort_by_key(|bin| (bin.units, bin.location_id));
+    let now = Utc::now().naive_utc();
+    for bin in bins {
+        if excess == 0 {
+            break;
+        }
+        let take = bin.units.min(excess);
+        let row = bin_stock::table.find((batch_id, bin.location_id));
+        if take == bin.units {
+            diesel::delete(row).execute(conn).map_err(AppError::from)?;
+        } else {
+            diesel::update(row)
+                .set((
+                    bin_stock::units.eq(bin_stock::units - take),
+                    bin_stock::updated_at.eq(now),
+                ))
+                .execute(conn)
+                .map_err(AppError::from)?;
+        }
+        excess -= take;
+    }
+    Ok(())
+}
+
+/// Answers "where is this batch": its stock per bin and what is in no bin.
+pub fn where_is_batch(
+    conn: &mut DbConnection,
+    _client_id: Uuid,
+    batch_detail_id: Uuid,
+) -> Result<BatchLocations, AppError> {
+    let batch = get_batch_detail(conn, _client_id, batch_detail_id)?;
+    placements(conn, _client_id, batch)
+}
+
+/// Checks that `location_id` belongs to `_client_id` and is a bin.
+fn require_bin(
+    conn: &mut DbConnection,
+    _client_id: Uuid,
+    location_id: Uuid,
+    field: &str,
+) -> Result<(), AppError> {
+    let location = find_location(conn, _client_id, location_id)?;
+    if location.kind != LocationKind::Bin {
+        return Err(AppError::validation(
+            field,
+            format!(
+                "Stock can only be kept in bins, not in a {}",
+                location.kind.as_str()
+            ),
+        ));
+    }
+    Ok(())
+}
+
+/// Moves `quantity` of a batch from one bin to another. Without
+/// `from_location_id` the stock is put away from what is in no bin yet;
+/// without `to_location_id` it is taken out of the bins. Only stock on
+/// hand can be placed, and a bin can't give more than it holds.
+pub fn move_stock(
+    conn: &mut DbConnection,
+    _client_id: Uuid,
+    batch_detail_id: Uuid,
+    from_location_id: Option<Uuid>,
+    to_location_id: Option<Uuid>,
+    quantity: i32,
+    unit: QuantityUnit,
+) -> Result<(StockTransfer, BatchLocations), AppError> {
+    if quantity <= 0 {
+        return Err(AppError::validation(
+            "quantity",
+            "Quantity must be greater than 0",
+        ));
+    }
+    if from_location_id.is_none() && to_location_id.is_none() {
+        return Err(AppError::validation(
+            "to_location_id",
+            "Choose a bin to move the stock from or to",
+        ));
+    }
+    if from_location_id == to_location_id {
+        return Err(AppError::validation(
+            "to_location_id",
+            "Stock is already in this bin",
+        ));
+    }
+
+    conn.transaction(|conn| {
+        get_batch_detail(conn, _client_id, batch_detail_id)?;
+        // Locking the batch serialises moves with each other and with
+        // stock postings, so the checks below hold until commit.
+        let batch = batch_details::table
+            .find(batch_detail_id)
+            .for_update()
+            .select(BatchDetail::as_select())
+            .get_result(conn)
+            .map_err(AppError::from)?;
+        if let Some(from) = from_location_id {
+            require_bin(conn, _client_id, from, "from_location_id")?;
+        }
+        if let Some(to) = to_location_id {
+            require_bin(conn, _client_id, to, "to_location_id")?;
+        }
+
+        let units = i64::from(quantity) * units_in(&batch, unit);
+        let available = match from_location_id {
+            Some(from) => bin_stock::table
+                .find((batch.id, from))
+                .select(bin_stock::units)
+                .first::<i64>(conn)
+                .optional()
+                .map_err(AppError::from)?
+                .unwrap_or(0),
+            None => {
+                let on_hand = on_hand_units(conn, &[batch.id])?
+                    .get(&batch.id)
+                    .copied()
+                    .unwrap_or(0);
+                let placed: i64 = batch_bins(conn, batch.id)?
+                    .iter()
+                    .map(|bin| bin.units)
+                    .sum();
+                on_hand - placed
+            }
+        };
+        if units > available {
+            let source = if from_location_id.is_some() {
+                "in this bin"
+            } else {
+                "outside the bins"
+            };
+            return Err(AppError::validation(
+                "quantity",
+                format!(
+                    "Only {} {} {}",
+                    StockLevel::new(&batch, available.max(0)).in_unit(unit),
+                    unit.as_str(),
+                    source
+                ),
+            ));
+        }
+
+        let now = Utc::now().naive_utc();
+        if let Some(from) = from_location_id {
+            if units == available {
+                diesel::delete(bin_stock::table.find((batch.id, from)))
+                    .execute(conn)
+                    .map_err(AppError::from)?;
+            } else {
+                diesel::update(bin_stock::table.find((batch.id, from)))
+                    .set((
+                        bin_stock::units.eq(bin_stock::units - units),
+                        bin_stock::updated_at.eq(now),
+                    ))
+                    .execute(conn)
+                    .map_err(AppError::from)?;
+            }
+        }
+        if let Some(to) = to_location_id {
+            diesel::insert_into(bin_stock::table)
+                .values((
+                    bin_stock::batch_id.eq(batch.id),
+                    bin_stock::location_id.eq(to),
+                    bin_stock::units.eq(units),
+                ))
+                .on_conflict((bin_stock::batch_id, bin_stock::location_id))
+                .do_update()
+                .set((
+                    bin_stock::units.eq(bin_stock::units + units),
+                    bin_stock::updated_at.eq(now),
+                ))
+                .execute(conn)
+                .map_err(AppError::from)?;
+        }
+
+        let transfer = diesel::insert_into(stock_transfers::table)
+            .values(&NewStockTransfer {
+                batch_id: batch.id,
+                from_location_id,
+                to_location_id,
+                quantity,
+                unit,
+                units,
+                moved_by: _client_id,
+            })
+            .returning(StockTransfer::as_returning())
+            .get_result(conn)
+            .map_err(AppError::from)?;
+
+        Ok((transfer, placements(conn, _client_id, batch)?))
+    })
+}
+
+/// Moves of one batch between bins, most recent first.
+pub fn batch_transfers(
+    conn: &mut DbConnection,
+    _client_id: Uuid,
+    batch_detail_id: Uuid,
+) -> Result<Vec<StockTransfer>, AppError> {
+    get_batch_detail(conn, _client_id, batch_detail_id)?;
+
+    stock_transfers::table
+        .filter(stock_transfers::batch_id.eq(batch_detail_id))
+        .select(StockTransfer::as_select())
+        .order(stock_transfers::moved_at.desc())
+        .load(conn)
+        .map_err(AppError::from)
+}
+
+/// The batches stored in `location_id` or any location inside it, by bin
+/// and then soonest expiry.
+pub fn location_stock(
+    conn: &mut DbConnection,
+    _client_id: Uuid,
+    location_id: Uuid,
+) -> Result<LocationStock, AppError> {
+    let all = client_locations(conn, _client_id)?;
+    let mut paths = location_paths(&all);
+    let Some(location) = all.iter().find(|l| l.id == location_id).cloned() else {
+        return Err(AppError::NotFound);
+    };
+
+    let mut subtree = HashSet::from([location_id]);
+    // Each pass adds the next level down; the hierarchy has four levels.
+    for _ in 0..3 {
+        for candidate in &all {
+            if candidate.parent_id.is_some_and(|p| subtree.contains(&p)) {
+                subtree.insert(candidate.id);
+            }
+        }
+    }
+
+    let rows = bin_stock::table
+        .inner_join(batch_details::table.inner_join(products::table))
+        .filter(bin_stock::location_id.eq_any(subtree.iter().copied().collect::<Vec<_>>()))
+        .select((
+            BinStock::as_select(),
+            BatchDetail::as_select(),
+            Product::as_select(),
+        ))
+        .load::<(BinStock, BatchDetail, Product)>(conn)
+        .map_err(AppError::from)?;
+
+    let mut batches: Vec<BinBatch> = rows
+        .into_iter()
+        .map(|(bin, batch, product)| BinBatch {
+            location_id: bin.location_id,
+            path: paths.get(&bin.location_id).cloned().unwrap_or_default(),
+            product_id: product.id,
+            product_name: product.product_name,
+            stock: StockLevel::new(&batch, bin.units),
+            batch,
+        })
+        .collect();
+    batches.sort_by(|a, b| {
+        a.path
+            .cmp(&b.path)
+            .then(a.batch.exp_date.cmp(&b.batch.exp_date))
+            .then_with(|| a.batch.batch_no.cmp(&b.batch.batch_no))
+    });
+
+    Ok(LocationStock {
+        location: LocationEntry {
+            path: paths.remove(&location_id).unwrap_or_default(),
+            location,
+        },
+        batches,
+    })
+}
@@ -1,61 +1,68 @@
-use app::ErrorResponse;
-use chrono::Utc;
+use app::{AppError, DbConnection};
+use chrono::{NaiveDateTime, TimeDelta, Utc};
 use diesel::{
-    BelongingToDsl, ExpressionMethods, GroupedBy, PgConnection, QueryDsl, RunQueryDsl,
-    SelectableHelper,
+    dsl::{exists, not},
+    pg::Pg,
+    BelongingToDsl, BoolExpressionMethods, Connection, ExpressionMethods, GroupedBy,
+    PgTextExpressionMethods, QueryDsl, RunQueryDsl, SelectableHelper,
 };
+use serde::{Deserialize, Serialize};
 use uuid::Uuid;
 
+use super::stock_service::remove_opening_receipts;
 use crate::models::{BatchDetail, NewProduct, Product, ProductWithBatches, UpdateProduct};
-use crate::schema::products;
 use crate::schema::products::dsl::*;
+use crate::schema::{batch_details, products};
 
 pub fn create_product(
-    conn: &mut PgConnection,
+    conn: &mut DbConnection,
     new_product: NewProduct,
-) -> Result<Product, ErrorResponse> {
+) -> Result<Product, AppError> {
     diesel::insert_into(products::table)
         .values(&new_product)
         .get_result::<Product>(conn)
-        .map_err(|e| ErrorResponse {
-            error: e.to_string(),
-        })
+        .map_err(AppError::from)
 }
 
-pub fn get_product(conn: &mut PgConnection, product_id: Uuid) -> Result<Product, ErrorResponse> {
+/// Loads a product only if it belongs to `_client_id`; other clients'
+/// products are reported as not found.
+pub fn get_product(
+    conn: &mut DbConnection,
+    _client_id: Uuid,
+    product_id: Uuid,
+) -> Result<Product, AppError> {
     products
         .find(product_id)
+        .filter(client_id.eq(_client_id))
         .get_result::<Product>(conn)
-        .map_err(|e| ErrorResponse {
-            error: e.to_string(),
-        })
+        .map_err(AppError::from)
 }
 
-pub fn get_all_products(conn: &mut PgConnection) -> Result<Vec<Product>, ErrorResponse> {
-    products.load::<Product>(conn).map_err(|e| ErrorResponse {
-        error: e.to_string(),
-    })
+pub fn get_all_products(
+    conn: &mut DbConnection,
+    _client_id: Uuid,
+) -> Result<Vec<Product>, AppError> {
+    products
+        .filter(client_id.eq(_client_id))
+        .load::<Product>(conn)
+        .map_err(AppError::from)
 }
 
 pub fn get_all_products_for_client(
-    conn: &mut PgConnection,
+    conn: &mut DbConnection,
     _client_id: Uuid,
-) -> Result<Vec<ProductWithBatches>, ErrorResponse> {
+) -> Result<Vec<ProductWithBatches>, AppError> {
     let products_for_client = products::table
         .filter(products::client_id.eq(_client_id))
         .select(Product::as_select())
         .order(updated_at.desc())
         .load(conn)
-        .map_err(|e| ErrorResponse {
-            error: e.to_string(),
-        })?;
+        .map_err(AppError::from)?;
 
     let all_batches = BatchDetail::belonging_to(&products_for_client)
         .select(BatchDetail::as_select())
         .load(conn)
-        .map_err(|e| ErrorResponse {
-            error: e.to_string(),
-        })?;
+        .map_err(AppError::from)?;
 
     let grouped_batches = all_batches
         .grouped_by(&products_for_client)
@@ -71,27 +78,304 @@ pub fn get_all_products_for_client(
 }
 
 pub fn update_product(
-    conn: &mut PgConnection,
+    conn: &mut DbConnection,
+    _client_id: Uuid,
     product_id: Uuid,
     product_data: UpdateProduct,
-) -> Result<Product, ErrorResponse> {
+) -> Result<Product, AppError> {
     let product_data = UpdateProduct {
         updated_at: Some(Utc::now().naive_utc()),
         ..product_data
     };
 
-    diesel::update(products.find(product_id))
+    let current = get_product(conn, _client_id, product_id)?;
+    let changes_totals = product_data
+        .total_quantity
+        .is_some_and(|quantity| quantity != current.total_quantity)
+        || product_data
+            .total_shipper_boxes
+            .is_some_and(|boxes| boxes != current.total_shipper_boxes);
+    if current.derived_totals && changes_totals {
+        return Err(AppError::validation(
+            "total_quantity",
+            "Totals for this product are derived from its batches and cannot be edited",
+        ));
+    }
+
+    diesel::update(products.find(current.id))
         .set(&product_data)
         .get_result::<Product>(conn)
-        .map_err(|e| ErrorResponse {
-            error: e.to_string(),
-        })
+        .map_err(AppError::from)
+}
+
+pub fn delete_product(
+    conn: &mut DbConnection,
+    _client_id: Uuid,
+    product_id: Uuid,
+) -> Result<usize, AppError> {
+    conn.transaction(|conn| {
+        // Locking the batches keeps movements from being posted against them
+        // while the product goes.
+        let batch_ids = batch_details::table
+            .inner_join(products::table)
+            .filter(batch_details::product_id.eq(product_id))
+            .filter(products::client_id.eq(_client_id))
+            .for_update()
+            .select(batch_details::id)
+            .load::<Uuid>(conn)
+            .map_err(AppError::from)?;
+
+        if !remove_opening_receipts(conn, &batch_ids)? {
+            return Err(AppError::Conflict(
+                "This product has batches with stock movements and can't be deleted".to_string(),
+            ));
+        }
+
+        diesel::delete(products.find(product_id).filter(client_id.eq(_client_id)))
+            .execute(conn)
+            .map_err(AppError::from)
+    })
+}
+
+/// Page size used when a query doesn't ask for one.
+const DEFAULT_PAGE_SIZE: i64 = 25;
+const MAX_PAGE_SIZE: i64 = 100;
+/// How far ahead `has_expiring_batches` looks when the query doesn't say.
+const DEFAULT_EXPIRING_WITHIN_DAYS: i64 = 90;
+const MAX_EXPIRING_WITHIN_DAYS: i64 = 3650;
+
+#[derive(Deserialize, Clone, Copy, Default)]
+#[serde(rename_all = "snake_case")]
+pub enum ProductSortField {
+    ProductName,
+    CreatedAt,
+    #[default]
+    UpdatedAt,
+    TotalQuantity,
+}
+
+#[derive(Deserialize, Clone, Copy, Default)]
+#[serde(rename_all = "snake_case")]
+pub enum SortDirection {
+    Asc,
+    #[default]
+    Desc,
+}
+
+/// Filters for [`query_products`]. Every filter is optional; date ranges
+/// include `from` and exclude `to`.
+#[derive(Deserialize, Default)]
+#[serde(default)]
+pub struct ProductFilter {
+    pub name_contains: Option<String>,
+    pub created_from: Option<NaiveDateTime>,
+    pub created_to: Option<NaiveDateTime>,
+    pub updated_from: Option<NaiveDateTime>,
+    pub updated_to: Option<NaiveDateTime>,
+    /// Keep only products that do (`true`) or don't (`false`) have a batch
+    /// expiring within `expiring_within_days`. Already expired batches
+    /// count as expiring.
+    pub has_expiring_batches: Option<bool>,
+    pub expiring_within_days: Option<i64>,
+}
+
+/// One page of a client's products. `page` is 1-based.
+#[derive(Deserialize, Default)]
+#[serde(default)]
+pub struct ProductQuery {
+    pub page: Option<i64>,
+    pub page_size: Option<i64>,
+    pub sort_by: ProductSortField,
+    pub direction: SortDirection,
+    pub filter: ProductFilter,
+}
+
+#[derive(Serialize)]
+pub struct ProductPage {
+    pub items: Vec<ProductWithBatches>,
+    pub total: i64,
+    pub page: i64,
+    pub page_size: i64,
+    pub total_pages: i64,
+}
+
+/// Escapes the `LIKE` wildcards in user input so it is matched literally.
+pub fn like_pattern(term: &str) -> String {
+    let escaped = term
+        .replace('\\', "\\\\")
+        .replace('%', "\\%")
+        .replace('_', "\\_");
+    format!("%{}%", escaped)
 }
 
-pub fn delete_product(conn: &mut PgConnection, product_id: Uuid) -> Result<usize, ErrorResponse> {
-    diesel::delete(products.find(product_id))
-        .execute(conn)
-        .map_err(|e| ErrorResponse {
-            error: e.to_string(),
+fn filtered_products<'a>(
+    _client_id: Uuid,
+    filter: &ProductFilter,
+) -> Result<products::BoxedQuery<'a, Pg>, AppError> {
+    let mut query = products::table
+        .filter(products::client_id.eq(_client_id))
+        .into_boxed();
+
+    if let Some(term) = filter.name_contains.as_deref().map(str::trim) {
+        if !term.is_empty() {
+            query = query.filter(products::product_name.ilike(like_pattern(term)));
+        }
+    }
+    if let Some(from) = filter.created_from {
+        query = query.filter(products::created_at.ge(from));
+    }
+    if let Some(to) = filter.created_to {
+        query = query.filter(products::created_at.lt(to));
+    }
+    if let Some(from) = filter.updated_from {
+        query = query.filter(products::updated_at.ge(from));
+    }
+    if let Some(to) = filter.updated_to {
+        query = query.filter(products::updated_at.lt(to));
+    }
+
+    if let Some(expiring) = filter.has_expiring_batches {
+        let days = filter
+            .expiring_within_days
+            .unwrap_or(DEFAULT_EXPIRING_WITHIN_DAYS);
+        if !(0..=MAX_EXPIRING_WITHIN_DAYS).contains(&days) {
+            return Err(AppError::validation(
+                "expiring_within_days",
+                format!(
+                    "Expiry window must be between 0 and {} days",
+                    MAX_EXPIRING_WITHIN_DAYS
+                ),
+            ));
+        }
+        let cutoff = Utc::now().date_naive() + TimeDelta::days(days);
+        let expiring_batch = exists(
+            batch_details::table.filter(
+                batch_details::product_id
+                    .eq(products::id)
+                    .and(batch_details::exp_date.le(cutoff)),
+            ),
+        );
+        query = if expiring {
+            query.filter(expiring_batch)
+        } else {
+            query.filter(not(expiring_batch))
+        };
+    }
+
+    Ok(query)
+}
+
+/// Loads one page of `_client_id`'s products, with their batches, together
+/// with the number of products matching the filters across all pages.
+/// Ties in the sort field are broken by id so pages never overlap.
+pub fn query_products(
+    conn: &mut DbConnection,
+    _client_id: Uuid,
+    query: &ProductQuery,
+) -> Result<ProductPage, AppError> {
+    let page = query.page.unwrap_or(1);
+    if page < 1 {
+        return Err(AppError::validation("page", "Page numbers start at 1"));
+    }
+    let page_size = query.page_size.unwrap_or(DEFAULT_PAGE_SIZE);
+    if !(1..=MAX_PAGE_SIZE).contains(&page_size) {
+        return Err(AppError::validation(
+            "page_size",
+            format!("Page size must be between 1 and {}", MAX_PAGE_SIZE),
+        ));
+    }
+
+    let total = filtered_products(_client_id, &query.filter)?
+        .count()
+        .get_result::<i64>(conn)
+        .map_err(AppError::from)?;
+
+    let mut rows = filtered_products(_client_id, &query.filter)?;
+    rows = match (query.sort_by, query.direction) {
+        (ProductSortField::ProductName, SortDirection::Asc) => {
+            rows.order_by(products::product_name.asc())
+        }
+        (ProductSortField::ProductName, SortDirection::Desc) => {
+            rows.order_by(products::product_name.desc())
+        }
+        (ProductSortField::CreatedAt, SortDirection::Asc) => {
+            rows.order_by(products::created_at.asc())
+        }
+        (ProductSortField::CreatedAt, SortDirection::Desc) => {
+            rows.order_by(products::created_at.desc())
+        }
+        (ProductSortField::UpdatedAt, SortDirection::Asc) => {
+            rows.order_by(products::updated_at.asc())
+        }
+        (ProductSortField::UpdatedAt, SortDirection::Desc) => {
+            rows.order_by(products::updated_at.desc())
+        }
+        (ProductSortField::TotalQuantity, SortDirection::Asc) => {
+            rows.order_by(products::total_quantity.asc())
+        }
+        (ProductSortField::TotalQuantity, SortDirection::Desc) => {
+            rows.order_by(products::total_quantity.desc())
+        }
+    };
+    let page_products = rows
+        .then_order_by(products::id.asc())
+        .limit(page_size)
+        .offset((page - 1).saturating_mul(page_size))
+        .select(Product::as_select())
+        .load(conn)
+        .map_err(AppError::from)?;
+
+    let page_batches = BatchDetail::belonging_to(&page_products)
+        .select(BatchDetail::as_select())
+        .order(batch_details::exp_date.asc())
+        .load(conn)
+        .map_err(AppError::from)?;
+
+    let items = page_batches
+        .grouped_by(&page_products)
+        .into_iter()
+        .zip(page_products)
+        .map(|(batches, product)| ProductWithBatches {
+            product,
+            batch_details: batches,
         })
+        .collect();
+
+    Ok(ProductPage {
+        items,
+        total,
+        page,
+        page_size,
+        total_pages: (total + page_size - 1) / page_size,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn expiring_within(days: i64) -> ProductFilter {
+        ProductFilter {
+            has_expiring_batches: Some(true),
+            expiring_within_days: Some(days),
+            ..ProductFilter::default()
+        }
+    }
+
+    #[test]
+    fn accepts_expiry_windows_up_to_ten_years() {
+        assert!(filtered_products(Uuid::nil(), &expiring_within(0)).is_ok());
+        assert!(filtered_products(Uuid::nil(), &expiring_within(3650)).is_ok());
+    }
+
+    #[test]
+    fn rejects_expiry_windows_out_of_range() {
+        for days in [-1, 3651, i64::MAX, i64::MIN] {
+            let err = filtered_products(Uuid::nil(), &expiring_within(days))
+                .err()
+                .expect("window should be rejected");
+            assert!(matches!(err, AppError::Validation { .. }));
+            assert_eq!(err.field(), Some("expiring_within_days"));
+        }
+    }
 }
@@ -0,0 +1,476 @@
+use app::{AppError, BatchInput, DbConnection, PackagingConfiguration, RowError};
+use chrono::{NaiveDate, Utc};
+use diesel::{
+    Connection, ExpressionMethods, OptionalExtension, QueryDsl, RunQueryDsl, SelectableHelper,
+};
+use serde::{Deserialize, Serialize};
+use std::collections::HashSet;
+use uuid::Uuid;
+
+use super::{
+    batch_details_service::{create_batch_detail, validate_batch_input},
+    reconciliation_service::sync_product_totals,
+    stock_service::record_movement,
+};
+use crate::models::{
+    BatchDetail, BatchStatus, GoodsReceipt, GoodsReceiptLine, MovementType, NewBatchDetail,
+    NewGoodsReceipt, NewGoodsReceiptLine, QuantityUnit, ReceiptStatus,
+};
+use crate::schema::{batch_details, goods_receipt_lines, goods_receipts, products};
+
+const MAX_SUPPLIER_LENGTH: usize = 255;
+const MAX_DELIVERY_NOTE_LENGTH: usize = 100;
+
+/// A receipt as entered on the receipt form: the delivery it came with and
+/// one line per batch delivered.
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ReceiptInput {
+    pub supplier: String,
+    pub delivery_note: String,
+    pub received_date: NaiveDate,
+    pub lines: Vec<ReceiptLineInput>,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ReceiptLineInput {
+    pub product_id: Uuid,
+    #[serde(flatten)]
+    pub batch: BatchInput,
+}
+
+#[derive(Serialize)]
+pub struct ReceiptWithLines {
+    pub receipt: GoodsReceipt,
+    pub lines: Vec<GoodsReceiptLine>,
+}
+
+/// Checks the header and every line of `input`. Line problems are reported
+/// together, by index, the same way batch creation reports rejected rows.
+fn validate_receipt(
+    conn: &mut DbConnection,
+    _client_id: Uuid,
+    input: &ReceiptInput,
+) -> Result<(), AppError> {
+    let supplier = input.supplier.trim();
+    if supplier.is_empty() {
+        return Err(AppError::validation("supplier", "Supplier is required"));
+    }
+    if supplier.chars().count() > MAX_SUPPLIER_LENGTH {
+        return Err(AppError::validation(
+            "supplier",
+            format!(
+                "Supplier cannot be longer than {} characters",
+                MAX_SUPPLIER_LENGTH
+            ),
+        ));
+    }
+    let delivery_note = input.delivery_note.trim();
+    if delivery_note.is_empty() {
+        return Err(AppError::validation(
+            "delivery_note",
+            "Delivery note is required",
+        ));
+    }
+    if delivery_note.chars().count() > MAX_DELIVERY_NOTE_LENGTH {
+        return Err(AppError::validation(
+            "delivery_note",
+            format!(
+                "Delivery note cannot be longer than {} characters",
+                MAX_DELIVERY_NOTE_LENGTH
+            ),
+        ));
+    }
+    if input.received_date > Utc::now().date_naive() {
+        return Err(AppError::validation(
+            "received_date",
+            "Received date cannot be in the future",
+        ));
+    }
+    if input.lines.is_empty() {
+        return Err(AppError::validation(
+            "lines",
+            "A receipt needs at least one line",
+        ));
+    }
+
+    let product_ids: Vec<Uuid> = input.lines.iter().map(|line| line.product_id).collect();
+    let own_products: HashSet<Uuid> = products::table
+        .filter(products::client_id.eq(_client_id))
+        .filter(products::id.eq_any(&product_ids))
+        .select(products::id)
+        .load::<Uuid>(conn)
+        .map_err(AppError::from)?
+        .into_iter()
+        .collect();
+
+    let mut rejected = Vec::new();
+    let mut seen = HashSet::new();
+    for (index, line) in input.lines.iter().enumerate() {
+        let mut reject = |reason: String| {
+            rejected.push(RowError {
+                index,
+                batch_no: line.batch.batch_no.clone(),
+                reason,
+            })
+        };
+        if !own_products.contains(&line.product_id) {
+            reject("Product not found".to_string());
+        }
+        for reason in validate_batch_input(&line.batch) {
+            reject(reason);
+        }
+        if !seen.insert((line.product_id, line.batch.batch_no.trim())) {
+            reject("Batch appears more than once on this receipt".to_string());
+        }
+    }
+
+    if rejected.is_empty() {
+        Ok(())
+    } else {
+        Err(AppError::BatchRejected { rows: rejected })
+    }
+}
+
+fn insert_lines(
+    conn: &mut DbConnection,
+    receipt_id: Uuid,
+    lines: &[ReceiptLineInput],
+) -> Result<Vec<GoodsReceiptLine>, AppError> {
+    let new_lines: Vec<NewGoodsReceiptLine> = lines
+        .iter()
+        .zip(1..)
+        .map(|(line, line_no)| NewGoodsReceiptLine {
+            receipt_id,
+            line_no,
+            product_id: line.product_id,
+            batch_no: line.batch.batch_no.trim(),
+            mfg_date: line.batch.mfg_date,
+            exp_date: line.batch.exp_date,
+            boxes: line.batch.boxes,
+            units_per_box: line.batch.units_per_box,
+            units_per_pack: line.batch.units_per_pack,
+            packs_per_box: line.batch.packs_per_box,
+            packages_configuration: line.batch.packages_configuration.trim(),
+            total_packs: line.batch.total_packs,
+        })
+        .collect();
+
+    diesel::insert_into(goods_receipt_lines::table)
+        .values(&new_lines)
+        .returning(GoodsReceiptLine::as_returning())
+        .get_results(conn)
+        .map_err(AppError::from)
+}
+
+fn receipt_lines(
+    conn: &mut DbConnection,
+    receipt_id: Uuid,
+) -> Result<Vec<GoodsReceiptLine>, AppError> {
+    goods_receipt_lines::table
+        .filter(goods_receipt_lines::receipt_id.eq(receipt_id))
+        .select(GoodsReceiptLine::as_select())
+        .order(goods_receipt_lines::line_no.asc())
+        .load(conn)
+        .map_err(AppError::from)
+}
+
+/// Loads a receipt of `_client_id` and locks it for the rest of the
+/// transaction.
+fn lock_receipt(
+    conn: &mut DbConnection,
+    _client_id: Uuid,
+    receipt_id: Uuid,
+) -> Result<GoodsReceipt, AppError> {
+    goods_receipts::table
+        .find(receipt_id)
+        .filter(goods_receipts::client_id.eq(_client_id))
+        .for_update()
+        .select(GoodsReceipt::as_select())
+        .get_result(conn)
+        .map_err(AppError::from)
+}
+
+fn require_draft(receipt: &GoodsReceipt, action: &str) -> Result<(), AppError> {
+    if receipt.status == ReceiptStatus::Draft {
+        return Ok(());
+    }
+    Err(AppError::Conflict(format!(
+        "Only draft receipts can be {}; this one is {}",
+        action,
+        receipt.status.as_str()
+    )))
+}
+
+/// Saves `input` as a draft receipt. Nothing reaches stock until the
+/// receipt is confirmed.
+pub fn create_goods_receipt(
+    conn: &mut DbConnection,
+    _client_id: Uuid,
+    input: &ReceiptInput,
+) -> Result<ReceiptWithLines, AppError> {
+    validate_receipt(conn, _client_id, input)?;
+
+    conn.transaction(|conn| {
+        let receipt = diesel::insert_into(goods_receipts::table)
+            .values(&NewGoodsReceipt {
+                client_id: _client_id,
+                supplier: input.supplier.trim(),
+                delivery_note: input.delivery_note.trim(),
+                received_date: input.received_date,
+            })
+            .returning(GoodsReceipt::as_returning())
+            .get_result(conn)
+            .map_err(AppError::from)?;
+        let lines = insert_lines(conn, receipt.id, &input.lines)?;
+
+        Ok(ReceiptWithLines { receipt, lines })
+    })
+}
+
+/// Replaces the header and lines of a draft receipt with `input`.
+pub fn update_goods_receipt(
+    conn: &mut DbConnection,
+    _client_id: Uuid,
+    receipt_id: Uuid,
+    input: &ReceiptInput,
+) -> Result<ReceiptWithLines, AppError> {
+    validate_receipt(conn, _client_id, input)?;
+
+    conn.transaction(|conn| {
+        let current = lock_receipt(conn, _client_id, receipt_id)?;
+        require_draft(&current, "edited")?;
+
+        let receipt = diesel::update(goods_receipts::table.find(receipt_id))
+            .set((
+                goods_receipts::supplier.eq(input.supplier.trim()),
+                goods_receipts::delivery_note.eq(input.delivery_note.trim()),
+                goods_receipts::received_date.eq(input.received_date),
+                goods_receipts::updated_at.eq(Utc::now().naive_utc()),
+            ))
+            .returning(GoodsReceipt::as_returning())
+            .get_result(conn)
+            .map_err(AppError::from)?;
+        diesel::delete(
+            goods_receipt_lines::table.filter(goods_receipt_lines::receipt_id.eq(receipt_id)),
+        )
+        .execute(conn)
+        .map_err(AppError::from)?;
+        let lines = insert_lines(conn, receipt_id, &input.lines)?;
+
+        Ok(ReceiptWithLines { receipt, lines })
+    })
+}
+
+/// Why `line` cannot be added to the existing `batch`, if it can't. A
+/// top-up must be the same batch: same dates and the same box layout.
+fn top_up_mismatch(batch: &BatchDetail, line: &GoodsReceiptLine) -> Option<String> {
+    if !BatchStatus::IN_STOCK.contains(&batch.status) {
+        return Some(format!(
+            "Batch is {} and cannot receive stock",
+            batch.status.as_str()
+        ));
+    }
+    if batch.mfg_date != line.mfg_date || batch.exp_date != line.exp_date {
+        return Some(format!(
+            "Batch exists with manufacturing date {} and expiry date {}",
+            batch.mfg_date, batch.exp_date
+        ));
+    }
+    let declared = batch
+        .packages_configuration
+        .parse::<PackagingConfiguration>()
+        .ok();
+    if declared.is_none() || declared != line.packages_configuration.parse().ok() {
+        return Some(format!(
+            "Package configuration {} does not match the batch's {}",
+            line.packages_configuration, batch.packages_configuration
+        ));
+    }
+    None
+}
+
+/// Books a draft receipt into stock. Each line creates its batch, or tops
+/// up the product's batch with the same number, and posts a receipt
+/// movement referencing the delivery note. If any line can't be booked the
+/// whole receipt is left as a draft and the lines are reported by index.
+pub fn confirm_goods_receipt(
+    conn: &mut DbConnection,
+    _client_id: Uuid,
+    receipt_id: Uuid,
+) -> Result<ReceiptWithLines, AppError> {
+    conn.transaction(|conn| {
+        let current = lock_receipt(conn, _client_id, receipt_id)?;
+        require_draft(&current, "confirmed")?;
+        let lines = receipt_lines(conn, receipt_id)?;
+        if lines.is_empty() {
+            return Err(AppError::validation(
+                "lines",
+                "A receipt needs at least one line",
+            ));
+        }
+
+        let mut rejected = Vec::new();
+        let mut touched_products = HashSet::new();
+        for line in &lines {
+            let index = usize::try_from(line.line_no - 1).unwrap_or_default();
+            let mut reject = |reason: String| {
+                rejected.push(RowError {
+                    index,
+                    batch_no: line.batch_no.clone(),
+                    reason,
+                })
+            };
+
+            let existing = batch_details::table
+                .filter(batch_details::product_id.eq(line.product_id))
+                .filter(batch_details::batch_no.eq(&line.batch_no))
+                .for_update()
+                .select(BatchDetail::as_select())
+                .first(conn)
+                .optional()
+                .map_err(AppError::from)?;
+
+            let batch = match existing {
+                Some(batch) => {
+                    if let Some(reason) = top_up_mismatch(&batch, line) {
+                        reject(reason);
+                        continue;
+                    }
+                    let (Some(boxes), Some(total_packs)) = (
+                        batch.boxes.checked_add(line.boxes),
+                        batch.total_packs.checked_add(line.total_packs),
+                    ) else {
+                        reject("Batch would hold more stock than can be recorded".to_string());
+                        continue;
+                    };
+                    diesel::update(batch_details::table.find(batch.id))
+                        .set((
+                            batch_details::boxes.eq(boxes),
+                            batch_details::total_packs.eq(total_packs),
+                            batch_details::updated_at.eq(Utc::now().naive_utc()),
+                        ))
+                        .returning(BatchDetail::as_returning())
+                        .get_result(conn)
+                        .map_err(AppError::from)?
+                }
+                None => create_batch_detail(
+                    conn,
+                    NewBatchDetail {
+                        product_id: line.product_id,
+                        batch_no: &line.batch_no,
+                        mfg_date: line.mfg_date,
+                        exp_date: line.exp_date,
+                        boxes: line.boxes,
+                        units_per_box: line.units_per_box,
+                        units_per_pack: line.units_per_pack,
+                        packs_per_box: line.packs_per_box,
+                        packages_configuration: &line.packages_configuration,
+                        total_packs: line.total_packs,
+                    },
+                )?,
+            };
+
+            record_movement(
+                conn,
+                &batch,
+                MovementType::Receipt,
+                line.total_packs,
+                QuantityUnit::Packs,
+                Some(&current.delivery_note),
+                _client_id,
+            )?;
+            diesel::update(goods_receipt_lines::table.find(line.id))
+                .set(goods_receipt_lines::batch_id.eq(batch.id))
+                .execute(conn)
+                .map_err(AppError::from)?;
+            touched_products.insert(batch.product_id);
+        }
+
+        if !rejected.is_empty() {
+            return Err(AppError::BatchRejected { rows: rejected });
+        }
+        for _product_id in touched_products {
+            sync_product_totals(conn, _product_id)?;
+        }
+
+        let now = Utc::now().naive_utc();
+        let receipt = diesel::update(goods_receipts::table.find(receipt_id))
+            .set((
+                goods_receipts::status.eq(ReceiptStatus::Confirmed),
+                goods_receipts::confirmed_at.eq(now),
+                goods_receipts::updated_at.eq(now),
+            ))
+            .returning(GoodsReceipt::as_returning())
+            .get_result(conn)
+            .map_err(AppError::from)?;
+        let lines = receipt_lines(conn, receipt_id)?;
+
+        Ok(ReceiptWithLines { receipt, lines })
+    })
+}
+
+/// Cancels a draft receipt. Confirmed receipts are already in stock and
+/// are corrected with stock adjustments instead.
+pub fn cancel_goods_receipt(
+    conn: &mut DbConnection,
+    _client_id: Uuid,
+    receipt_id: Uuid,
+) -> Result<GoodsReceipt, AppError> {
+    conn.transaction(|conn| {
+        let current = lock_receipt(conn, _client_id, receipt_id)?;
+        require_draft(&current, "cancelled")?;
+
+        let now = Utc::now().naive_utc();
+        diesel::update(goods_receipts::table.find(receipt_id))
+            .set((
+                goods_receipts::status.eq(ReceiptStatus::Cancelled),
+                goods_receipts::cancelled_at.eq(now),
+                goods_receipts::updated_at.eq(now),
+            ))
+            .returning(GoodsReceipt::as_returning())
+            .get_result(conn)
+            .map_err(AppError::from)
+    })
+}
+
+pub fn get_goods_receipt(
+    conn: &mut DbConnection,
+    _client_id: Uuid,
+    receipt_id: Uuid,
+) -> Result<ReceiptWithLines, AppError> {
+    let receipt = goods_receipts::table
+        .find(receipt_id)
+        .filter(goods_receipts::client_id.eq(_client_id))
+        .select(GoodsReceipt::as_select())
+        .get_result(conn)
+        .map_err(AppError::from)?;
+    let lines = receipt_lines(conn, receipt_id)?;
+
+    Ok(ReceiptWithLines { receipt, lines })
+}
+
+/// Receipts of `_client_id`, optionally only those in `status`, most
+/// recently received first.
+pub fn list_goods_receipts(
+    conn: &mut DbConnection,
+    _client_id: Uuid,
+    status: Option<ReceiptStatus>,
+) -> Result<Vec<GoodsReceipt>, AppError> {
+    let mut query = goods_receipts::table
+        .filter(goods_receipts::client_id.eq(_client_id))
+        .select(GoodsReceipt::as_select())
+        .into_boxed();
+    if let Some(status) = status {
+        query = query.filter(goods_receipts::status.eq(status));
+    }
+
+    query
+        .order((
+            goods_receipts::received_date.desc(),
+            goods_receipts::created_at.desc(),
+        ))
+        .load(conn)
+        .map_err(AppError::from)
+}
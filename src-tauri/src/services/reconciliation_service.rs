@@ -0,0 +1,171 @@
+use app::{AppError, DbConnection};
+use chrono::Utc;
+use diesel::{Connection, ExpressionMethods, QueryDsl, RunQueryDsl, SelectableHelper};
+use serde::Serialize;
+use std::collections::HashMap;
+use uuid::Uuid;
+
+use super::{product_service::get_product, stock_service::stock_levels};
+use crate::models::{BatchDetail, BatchStatus, Product, UpdateProduct};
+use crate::schema::{batch_details, products};
+
+/// Product totals as recorded on the product row compared with the stock on
+/// hand in its in-stock batches. `total_quantity` is compared with the
+/// batches' full packs and `total_shipper_boxes` with their full boxes.
+#[derive(Serialize)]
+pub struct ProductReconciliation {
+    pub product_id: Uuid,
+    pub product_name: String,
+    pub derived_totals: bool,
+    pub recorded_quantity: i32,
+    pub recorded_shipper_boxes: i32,
+    pub batch_total_packs: i64,
+    pub batch_boxes: i64,
+    pub quantity_difference: i64,
+    pub shipper_boxes_difference: i64,
+    pub in_sync: bool,
+}
+
+impl ProductReconciliation {
+    fn new(product: Product, batch_boxes: i64, batch_total_packs: i64) -> Self {
+        let quantity_difference = i64::from(product.total_quantity) - batch_total_packs;
+        let shipper_boxes_difference = i64::from(product.total_shipper_boxes) - batch_boxes;
+
+        ProductReconciliation {
+            product_id: product.id,
+            product_name: product.product_name,
+            derived_totals: product.derived_totals,
+            recorded_quantity: product.total_quantity,
+            recorded_shipper_boxes: product.total_shipper_boxes,
+            batch_total_packs,
+            batch_boxes,
+            quantity_difference,
+            shipper_boxes_difference,
+            in_sync: quantity_difference == 0 && shipper_boxes_difference == 0,
+        }
+    }
+}
+
+/// Sum of on-hand `(boxes, packs)` over the batches of `_product_id` that
+/// count as stock; recalled and expired batches are left out.
+pub fn batch_totals(conn: &mut DbConnection, _product_id: Uuid) -> Result<(i64, i64), AppError> {
+    let batches = batch_details::table
+        .filter(batch_details::product_id.eq(_product_id))
+        .filter(batch_details::status.eq_any(BatchStatus::IN_STOCK))
+        .select(BatchDetail::as_select())
+        .load(conn)
+        .map_err(AppError::from)?;
+
+    Ok(stock_levels(conn, batches)?
+        .iter()
+        .fold((0, 0), |(boxes, packs), (_, level)| {
+            (boxes + level.boxes, packs + level.packs)
+        }))
+}
+
+pub fn reconcile_product(
+    conn: &mut DbConnection,
+    _client_id: Uuid,
+    _product_id: Uuid,
+) -> Result<ProductReconciliation, AppError> {
+    let product = get_product(conn, _client_id, _product_id)?;
+    let (boxes, packs) = batch_totals(conn, _product_id)?;
+
+    Ok(ProductReconciliation::new(product, boxes, packs))
+}
+
+pub fn reconcile_client_products(
+    conn: &mut DbConnection,
+    _client_id: Uuid,
+) -> Result<Vec<ProductReconciliation>, AppError> {
+    let client_products = products::table
+        .filter(products::client_id.eq(_client_id))
+        .order(products::product_name.asc())
+        .load::<Product>(conn)
+        .map_err(AppError::from)?;
+
+    let product_ids: Vec<Uuid> = client_products.iter().map(|p| p.id).collect();
+    let batches = batch_details::table
+        .filter(batch_details::product_id.eq_any(&product_ids))
+        .filter(batch_details::status.eq_any(BatchStatus::IN_STOCK))
+        .select(BatchDetail::as_select())
+        .load(conn)
+        .map_err(AppError::from)?;
+
+    let mut totals: HashMap<Uuid, (i64, i64)> = HashMap::new();
+    for (batch, level) in stock_levels(conn, batches)? {
+        let (boxes, packs) = totals.entry(batch.product_id).or_default();
+        *boxes += level.boxes;
+        *packs += level.packs;
+    }
+
+    Ok(client_products
+        .into_iter()
+        .map(|product| {
+            let (boxes, packs) = totals.get(&product.id).copied().unwrap_or((0, 0));
+            ProductReconciliation::new(product, boxes, packs)
+        })
+        .collect())
+}
+
+fn to_total(value: i64, field: &str) -> Result<i32, AppError> {
+    i32::try_from(value)
+        .map_err(|_| AppError::validation(field, "Batch totals exceed the supported range"))
+}
+
+/// Overwrites the product totals with its batch totals when the product is
+/// in derived mode. Called after every batch insert, update, delete, status
+/// change and stock movement.
+pub fn sync_product_totals(conn: &mut DbConnection, _product_id: Uuid) -> Result<(), AppError> {
+    let derived = products::table
+        .find(_product_id)
+        .select(products::derived_totals)
+        .get_result::<bool>(conn)
+        .map_err(AppError::from)?;
+    if !derived {
+        return Ok(());
+    }
+
+    let (boxes, packs) = batch_totals(conn, _product_id)?;
+    diesel::update(products::table.find(_product_id))
+        .set((
+            products::total_quantity.eq(to_total(packs, "total_quantity")?),
+            products::total_shipper_boxes.eq(to_total(boxes, "total_shipper_boxes")?),
+        ))
+        .execute(conn)
+        .map_err(AppError::from)?;
+
+    Ok(())
+}
+
+/// Switches a product between hand-entered and batch-derived totals.
+/// Enabling derived mode immediately recomputes the totals.
+pub fn set_derived_totals(
+    conn: &mut DbConnection,
+    _client_id: Uuid,
+    _product_id: Uuid,
+    derived: bool,
+) -> Result<Product, AppError> {
+    conn.transaction(|conn| {
+        get_product(conn, _client_id, _product_id)?;
+
+        let product_data = UpdateProduct {
+            product_name: None,
+            total_quantity: None,
+            total_shipper_boxes: None,
+            updated_at: Some(Utc::now().naive_utc()),
+            derived_totals: Some(derived),
+        };
+        diesel::update(products::table.find(_product_id))
+            .set(&product_data)
+            .execute(conn)
+            .map_err(AppError::from)?;
+
+        sync_product_totals(conn, _product_id)?;
+
+        products::table
+            .find(_product_id)
+            .get_result::<Product>(conn)
+            .map_err(AppError::from)
+    })
+}
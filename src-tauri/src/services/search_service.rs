@@ -0,0 +1,267 @@
+use app::{AppError, DbConnection};
+use diesel::{
+    define_sql_function, infix_operator, pg::Pg, sql_types::Text, BoolExpressionMethods,
+    Connection, ExpressionMethods, IntoSql, PgTextExpressionMethods, QueryDsl, RunQueryDsl,
+    SelectableHelper,
+};
+use serde::Serialize;
+use std::collections::HashMap;
+use uuid::Uuid;
+
+use super::product_service::like_pattern;
+use crate::models::{BatchDetail, Product};
+use crate::schema::{batch_details, products};
+
+define_sql_function! {
+    /// `pg_trgm`: similarity between `needle` and the best matching run of
+    /// words in `haystack`, from 0 to 1.
+    fn word_similarity(needle: Text, haystack: Text) -> Float4;
+}
+
+// `pg_trgm`: true when `word_similarity(left, right)` reaches
+// `pg_trgm.word_similarity_threshold`. Unlike comparing the function's
+// result, this operator can use the trigram indexes.
+infix_operator!(WordSimilar, " <% ", backend: Pg);
+
+/// Fuzzy matches below this similarity are not reported. Also set as
+/// `pg_trgm.word_similarity_threshold` for the `<%` filters.
+const MIN_SIMILARITY: f32 = 0.4;
+/// Rows loaded per query before ranking, so a very broad term can't pull in
+/// a client's whole catalogue. Substring matches are loaded ahead of fuzzy
+/// ones, so the cap only ever drops the weakest candidates.
+const MAX_CANDIDATES: i64 = 500;
+const DEFAULT_LIMIT: usize = 20;
+const MAX_LIMIT: usize = 50;
+const MAX_TERM_LENGTH: usize = 100;
+
+/// The part of `field` that matched the search term, as character offsets.
+#[derive(Serialize)]
+pub struct Highlight {
+    pub field: &'static str,
+    pub start: usize,
+    pub end: usize,
+}
+
+#[derive(Serialize)]
+pub struct BatchHit {
+    pub batch: BatchDetail,
+    pub score: f32,
+    pub highlights: Vec<Highlight>,
+}
+
+/// A product matching the search, either by name or through its batches.
+/// `batches` holds only the batches that matched, best first.
+#[derive(Serialize)]
+pub struct ProductHit {
+    pub product: Product,
+    pub score: f32,
+    pub highlights: Vec<Highlight>,
+    pub batches: Vec<BatchHit>,
+}
+
+/// Case-insensitive position of `term` in `value`, in characters.
+fn find_match(value: &str, term: &str) -> Option<(usize, usize)> {
+    let fold = |c: char| c.to_lowercase().next().unwrap_or(c);
+    let value: Vec<char> = value.chars().map(fold).collect();
+    let term: Vec<char> = term.chars().map(fold).collect();
+    if term.is_empty() || term.len() > value.len() {
+        return None;
+    }
+
+    value
+        .windows(term.len())
+        .position(|window| window == term.as_slice())
+        .map(|start| (start, start + term.len()))
+}
+
+/// Scores how well `value` matches `term`: exact matches beat prefixes,
+/// prefixes beat word prefixes, which beat other substrings, which beat
+/// fuzzy matches. Returns `None` when it doesn't match at all.
+fn score_field(
+    field: &'static str,
+    value: &str,
+    term: &str,
+    similarity: f32,
+) -> Option<(f32, Option<Highlight>)> {
+    match find_match(value, term) {
+        Some((start, end)) => {
+            let score = if start == 0 && end == value.chars().count() {
+                1.0
+            } else if start == 0 {
+                0.9
+            } else if value
+                .chars()
+                .nth(start - 1)
+                .is_some_and(|c| !c.is_alphanumeric())
+            {
+                0.8
+            } else {
+                0.7
+            };
+            Some((score, Some(Highlight { field, start, end })))
+        }
+        None if similarity >= MIN_SIMILARITY => Some((similarity * 0.6, None)),
+        None => None,
+    }
+}
+
+/// Searches `_client_id`'s product names, batch numbers and packaging
+/// configurations for `term`, matching substrings and near misses, and
+/// returns up to `limit` products ranked by their best match.
+pub fn search(
+    conn: &mut DbConnection,
+    _client_id: Uuid,
+    term: &str,
+    limit: Option<usize>,
+) -> Result<Vec<ProductHit>, AppError> {
+    let term = term.trim();
+    if term.is_empty() {
+        return Err(AppError::validation("query", "Search term cannot be empty"));
+    }
+    if term.chars().count() > MAX_TERM_LENGTH {
+        return Err(AppError::validation(
+            "query",
+            format!(
+                "Search term cannot be longer than {} characters",
+                MAX_TERM_LENGTH
+            ),
+        ));
+    }
+    let limit = limit.unwrap_or(DEFAULT_LIMIT);
+    if !(1..=MAX_LIMIT).contains(&limit) {
+        return Err(AppError::validation(
+            "limit",
+            format!("Limit must be between 1 and {}", MAX_LIMIT),
+        ));
+    }
+    let pattern = like_pattern(term);
+
+    // `SET LOCAL` only lasts until the end of the transaction, so the
+    // threshold can't leak into other users of the pooled connection.
+    let (name_matches, batch_matches) = conn.transaction(|conn| {
+        diesel::sql_query(format!(
+            "SET LOCAL pg_trgm.word_similarity_threshold = {}",
+            MIN_SIMILARITY
+        ))
+        .execute(conn)
+        .map_err(AppError::from)?;
+
+        let name_substring = products::product_name.ilike(&pattern);
+        let name_similarity = word_similarity(term, products::product_name);
+        let name_matches = products::table
+            .filter(products::client_id.eq(_client_id))
+            .filter(name_substring.or(WordSimilar::new(
+                term.into_sql::<Text>(),
+                products::product_name,
+            )))
+            .select((Product::as_select(), name_similarity))
+            .order((name_substring.desc(), name_similarity.desc()))
+            .limit(MAX_CANDIDATES)
+            .load::<(Product, f32)>(conn)
+            .map_err(AppError::from)?;
+
+        let batch_substring = batch_details::batch_no
+            .ilike(&pattern)
+            .or(batch_details::packages_configuration.ilike(&pattern));
+        let batch_no_similarity = word_similarity(term, batch_details::batch_no);
+        let configuration_similarity = word_similarity(term, batch_details::packages_configuration);
+        let batch_matches = batch_details::table
+            .inner_join(products::table)
+            .filter(products::client_id.eq(_client_id))
+            .filter(
+                batch_substring
+                    .or(WordSimilar::new(
+                        term.into_sql::<Text>(),
+                        batch_details::batch_no,
+                    ))
+                    .or(WordSimilar::new(
+                        term.into_sql::<Text>(),
+                        batch_details::packages_configuration,
+                    )),
+            )
+            .select((
+                BatchDetail::as_select(),
+                Product::as_select(),
+                batch_no_similarity,
+                configuration_similarity,
+            ))
+            .order((
+                batch_substring.desc(),
+                batch_no_similarity.desc(),
+                configuration_similarity.desc(),
+            ))
+            .limit(MAX_CANDIDATES)
+            .load::<(BatchDetail, Product, f32, f32)>(conn)
+            .map_err(AppError::from)?;
+
+        Ok::<_, AppError>((name_matches, batch_matches))
+    })?;
+
+    let mut hits: HashMap<Uuid, ProductHit> = HashMap::new();
+    for (product, similarity) in name_matches {
+        if let Some((score, highlight)) =
+            score_field("product_name", &product.product_name, term, similarity)
+        {
+            hits.insert(
+                product.id,
+                ProductHit {
+                    product,
+                    score,
+                    highlights: highlight.into_iter().collect(),
+                    batches: Vec::new(),
+                },
+            );
+        }
+    }
+
+    for (batch, product, batch_no_similarity, configuration_similarity) in batch_matches {
+        let matches: Vec<(f32, Option<Highlight>)> = [
+            score_field("batch_no", &batch.batch_no, term, batch_no_similarity),
+            score_field(
+                "packages_configuration",
+                &batch.packages_configuration,
+                term,
+                configuration_similarity,
+            ),
+        ]
+        .into_iter()
+        .flatten()
+        .collect();
+        let Some(score) = matches.iter().map(|(score, _)| *score).reduce(f32::max) else {
+            continue;
+        };
+
+        let hit = hits.entry(product.id).or_insert_with(|| ProductHit {
+            product,
+            score: 0.0,
+            highlights: Vec::new(),
+            batches: Vec::new(),
+        });
+        hit.score = hit.score.max(score);
+        hit.batches.push(BatchHit {
+            batch,
+            score,
+            highlights: matches
+                .into_iter()
+                .filter_map(|(_, highlight)| highlight)
+                .collect(),
+        });
+    }
+
+    let mut hits: Vec<ProductHit> = hits.into_values().collect();
+    for hit in &mut hits {
+        hit.batches.sort_by(|a, b| {
+            b.score
+                .total_cmp(&a.score)
+                .then_with(|| a.batch.exp_date.cmp(&b.batch.exp_date))
+        });
+    }
+    hits.sort_by(|a, b| {
+        b.score
+            .total_cmp(&a.score)
+            .then_with(|| a.product.product_name.cmp(&b.product.product_name))
+    });
+    hits.truncate(limit);
+
+    Ok(hits)
+}
@@ -1,85 +1,300 @@
-use super::key_management_service::{get_app_dir, load_private_key};
-use ring::signature::{self, KeyPair, UnparsedPublicKey};
-use serde::{Deserialize, Serialize};
-use std::{
-    fs,
-    time::{Duration, SystemTime, UNIX_EPOCH},
+use super::key_management_service::{client_dir, load_private_key, KeyError, MasterKey};
+use crate::models::{AuthChallenge, NewAuthChallenge, NewSessionRecord, SessionRecord};
+use crate::schema::{auth_challenges, clients, sessions};
+use app::{AppError, DbConnection};
+use base64::{engine::general_purpose, Engine};
+use chrono::{TimeDelta, Utc};
+use diesel::{
+    BoolExpressionMethods, ExpressionMethods, OptionalExtension, QueryDsl, RunQueryDsl,
+    SelectableHelper,
+};
+use ring::{
+    digest,
+    rand::{SecureRandom, SystemRandom},
+    signature::{self, Ed25519KeyPair, KeyPair, UnparsedPublicKey},
 };
+use serde::{Deserialize, Serialize};
 use uuid::Uuid;
 
-#[derive(Serialize, Deserialize)]
+#[derive(Serialize, Deserialize, Clone)]
 pub struct Session {
     pub token: String,
     pub client_id: Uuid,
     pub expires_at: u64,
 }
 
-fn generate_session(client_id: Uuid) -> Session {
-    let token = Uuid::new_v4().to_string();
-    // 3 Hours
-    let expiration_time =
-        SystemTime::now().duration_since(UNIX_EPOCH).unwrap() + Duration::from_secs(10800);
-    let expires_at = expiration_time.as_secs();
+/// How long a session stays valid without being used. Every authorized
+/// command pushes the expiry out by this much again.
+const SESSION_IDLE_TIMEOUT: TimeDelta = TimeDelta::hours(3);
+/// Hard limit on a session's lifetime, however active it is.
+const SESSION_MAX_LIFETIME: TimeDelta = TimeDelta::hours(24);
+/// Minimum time between two expiry refreshes of the same session, so that
+/// bursts of commands don't each write to the sessions table.
+const SESSION_REFRESH_INTERVAL: TimeDelta = TimeDelta::minutes(1);
+
+/// Only a SHA-256 digest of each token is stored, so a database dump does
+/// not hand out usable sessions.
+fn hash_token(token: &str) -> String {
+    digest::digest(&digest::SHA256, token.as_bytes())
+        .as_ref()
+        .iter()
+        .map(|byte| format!("{:02x}", byte))
+        .collect()
+}
 
+fn to_session(token: &str, record: &SessionRecord) -> Session {
     Session {
-        token,
-        client_id,
-        expires_at,
+        token: token.to_string(),
+        client_id: record.client_id,
+        expires_at: record.expires_at.and_utc().timestamp().max(0) as u64,
     }
 }
 
-pub fn get_client_id_from_private_key() -> Result<Uuid, String> {
-    let app_data_dir = get_app_dir();
-    let entries = fs::read_dir(app_data_dir).map_err(|_| "Failed to read app data directory")?;
-    for entry in entries {
-        if let Ok(entry) = entry {
-            if entry.file_type().map(|ft| ft.is_dir()).unwrap_or(false) {
-                if let Ok(client_id) = Uuid::parse_str(&entry.file_name().to_string_lossy()) {
-                    return Ok(client_id);
-                }
-            }
-        }
+fn generate_session(conn: &mut DbConnection, client_id: Uuid) -> Result<Session, AppError> {
+    let token = Uuid::new_v4().to_string();
+    let now = Utc::now().naive_utc();
+
+    // Drop this client's dead sessions while we're here.
+    diesel::delete(
+        sessions::table
+            .filter(sessions::client_id.eq(client_id))
+            .filter(
+                sessions::expires_at
+                    .le(now)
+                    .or(sessions::revoked_at.is_not_null()),
+            ),
+    )
+    .execute(conn)
+    .map_err(AppError::from)?;
+
+    let record = diesel::insert_into(sessions::table)
+        .values(&NewSessionRecord {
+            client_id,
+            token_hash: &hash_token(&token),
+            expires_at: now + SESSION_IDLE_TIMEOUT,
+        })
+        .returning(SessionRecord::as_returning())
+        .get_result(conn)
+        .map_err(AppError::from)?;
+
+    Ok(to_session(&token, &record))
+}
+
+/// How long a sign-in challenge can be answered after it was issued.
+const CHALLENGE_TTL: TimeDelta = TimeDelta::seconds(60);
+/// Prefixed to every signed challenge so a sign-in signature can't be
+/// mistaken for a signature over anything else.
+const SIGN_IN_CONTEXT: &str = "product-tracker-sign-in";
+
+fn challenge_message(challenge: &AuthChallenge) -> String {
+    format!("{}:{}:{}", SIGN_IN_CONTEXT, challenge.id, challenge.nonce)
+}
+
+fn unauthorized(message: &str) -> AppError {
+    AppError::Unauthorized(message.to_string())
+}
+
+/// Issues a single-use random nonce that `_client_id` has to sign within
+/// [`CHALLENGE_TTL`] to get a session.
+pub fn issue_challenge(
+    conn: &mut DbConnection,
+    _client_id: Uuid,
+) -> Result<AuthChallenge, AppError> {
+    let now = Utc::now().naive_utc();
+
+    diesel::delete(
+        auth_challenges::table
+            .filter(auth_challenges::client_id.eq(_client_id))
+            .filter(
+                auth_challenges::expires_at
+                    .le(now)
+                    .or(auth_challenges::used_at.is_not_null()),
+            ),
+    )
+    .execute(conn)
+    .map_err(AppError::from)?;
+
+    let mut nonce = [0u8; 32];
+    SystemRandom::new()
+        .fill(&mut nonce)
+        .map_err(|_| AppError::Internal("Failed to generate sign-in challenge".to_string()))?;
+
+    diesel::insert_into(auth_challenges::table)
+        .values(&NewAuthChallenge {
+            client_id: _client_id,
+            nonce: &general_purpose::STANDARD.encode(nonce),
+            expires_at: now + CHALLENGE_TTL,
+        })
+        .returning(AuthChallenge::as_returning())
+        .get_result(conn)
+        .map_err(AppError::from)
+}
+
+/// Answers `challenge` with the client's private key.
+pub fn sign_challenge(key_pair: &Ed25519KeyPair, challenge: &AuthChallenge) -> Vec<u8> {
+    key_pair
+        .sign(challenge_message(challenge).as_bytes())
+        .as_ref()
+        .to_vec()
+}
+
+/// Checks `signature` against the public key registered for the client the
+/// challenge was issued to and opens a session on success.
+///
+/// The challenge is consumed before the signature is checked, so every
+/// challenge gets exactly one attempt and a replayed answer is rejected.
+pub fn verify_challenge(
+    conn: &mut DbConnection,
+    challenge_id: Uuid,
+    signature: &[u8],
+) -> Result<Session, AppError> {
+    let now = Utc::now().naive_utc();
+    let challenge = diesel::update(
+        auth_challenges::table
+            .find(challenge_id)
+            .filter(auth_challenges::used_at.is_null()),
+    )
+    .set(auth_challenges::used_at.eq(now))
+    .returning(AuthChallenge::as_returning())
+    .get_result(conn)
+    .optional()
+    .map_err(AppError::from)?
+    .ok_or_else(|| unauthorized("Sign-in challenge is unknown or was already used"))?;
+
+    if challenge.expires_at <= now {
+        return Err(unauthorized("Sign-in challenge expired, please try again"));
     }
-    Err("Client ID not found".to_string())
+
+    let registered_key = clients::table
+        .find(challenge.client_id)
+        .select(clients::public_key)
+        .get_result::<Option<String>>(conn)
+        .optional()
+        .map_err(AppError::from)?
+        .ok_or_else(|| unauthorized("Client is not registered"))?
+        .ok_or_else(|| unauthorized("Client has no registered public key"))?;
+    let registered_key = general_purpose::STANDARD
+        .decode(registered_key)
+        .map_err(|_| AppError::Internal("Registered public key is corrupt".to_string()))?;
+
+    UnparsedPublicKey::new(&signature::ED25519, registered_key)
+        .verify(challenge_message(&challenge).as_bytes(), signature)
+        .map_err(|_| unauthorized("Signature does not match the registered public key"))?;
+
+    generate_session(conn, challenge.client_id)
 }
 
-pub fn authenticate_client() -> Result<Session, String> {
-    let client_id = get_client_id_from_private_key()?;
+/// Records the public key of clients created before public keys were kept
+/// on the `clients` row. Keys that are already registered are never
+/// replaced.
+fn register_missing_public_key(
+    conn: &mut DbConnection,
+    client_id: Uuid,
+    key_pair: &Ed25519KeyPair,
+) -> Result<(), AppError> {
+    diesel::update(
+        clients::table
+            .find(client_id)
+            .filter(clients::public_key.is_null()),
+    )
+    .set(clients::public_key.eq(general_purpose::STANDARD.encode(key_pair.public_key())))
+    .execute(conn)
+    .map_err(AppError::from)?;
 
-    let private_key_path = get_app_dir()
-        .join(client_id.to_string())
-        .join("private_key");
-    let private_key_path_str = private_key_path
-        .to_str()
-        .ok_or("Invalid private key path")?;
+    Ok(())
+}
 
-    let private_key = load_private_key(private_key_path_str);
+/// Signs in `client_id` with its key stored on this machine: the server
+/// side issues a challenge, the local private key signs it and the
+/// signature is verified against the public key registered at
+/// `create_client`.
+pub fn authenticate_client(
+    conn: &mut DbConnection,
+    key: &MasterKey,
+    client_id: Uuid,
+) -> Result<Session, AppError> {
+    let private_key_path = client_dir(client_id)?.join("private_key");
+    let private_key = load_private_key(key, &private_key_path.to_string_lossy())?;
 
-    let key_pair = signature::Ed25519KeyPair::from_pkcs8(&private_key)
-        .map_err(|_| "Failed to create key pair")?;
+    let key_pair = Ed25519KeyPair::from_pkcs8(private_key.expose())
+        .map_err(|_| KeyError::Corrupt("decrypted key is not a valid Ed25519 key".to_string()))?;
+    register_missing_public_key(conn, client_id, &key_pair)?;
 
-    let timestamp = SystemTime::now()
-        .duration_since(UNIX_EPOCH)
-        .unwrap()
-        .as_secs()
-        .to_string();
-    let sig = key_pair.sign(timestamp.as_bytes());
+    let challenge = issue_challenge(conn, client_id)?;
+    let signature = sign_challenge(&key_pair, &challenge);
 
-    let peer_public_key =
-        UnparsedPublicKey::new(&signature::ED25519, key_pair.public_key().as_ref());
-    peer_public_key
-        .verify(timestamp.as_bytes(), sig.as_ref())
-        .map_err(|_| "Invalid signature")?;
+    verify_challenge(conn, challenge.id, &signature)
+}
+
+fn unrecognised() -> AppError {
+    AppError::Unauthorized("Session not recognised, please sign in again".to_string())
+}
+
+/// Guard run by every data command: resolves `token` to the session it was
+/// issued for, rejecting unknown, revoked and expired tokens. Valid sessions
+/// get their expiry slid forward, up to [`SESSION_MAX_LIFETIME`].
+pub fn require_session(conn: &mut DbConnection, token: &str) -> Result<Session, AppError> {
+    let record = sessions::table
+        .filter(sessions::token_hash.eq(hash_token(token)))
+        .select(SessionRecord::as_select())
+        .first(conn)
+        .optional()
+        .map_err(AppError::from)?
+        .ok_or_else(unrecognised)?;
+
+    if record.revoked_at.is_some() {
+        return Err(AppError::Unauthorized(
+            "Session was signed out, please sign in again".to_string(),
+        ));
+    }
+
+    let now = Utc::now().naive_utc();
+    if record.expires_at <= now {
+        return Err(AppError::Unauthorized(
+            "Session expired, please sign in again".to_string(),
+        ));
+    }
+
+    if now - record.last_seen_at < SESSION_REFRESH_INTERVAL {
+        return Ok(to_session(token, &record));
+    }
+
+    let expires_at = (now + SESSION_IDLE_TIMEOUT).min(record.created_at + SESSION_MAX_LIFETIME);
+    let record = diesel::update(sessions::table.find(record.id))
+        .set((
+            sessions::last_seen_at.eq(now),
+            sessions::expires_at.eq(expires_at),
+        ))
+        .returning(SessionRecord::as_returning())
+        .get_result(conn)
+        .map_err(AppError::from)?;
+
+    Ok(to_session(token, &record))
+}
 
-    let session = generate_session(client_id);
+/// Revokes the session behind `token`. Signing out twice is not an error.
+pub fn sign_out(conn: &mut DbConnection, token: &str) -> Result<(), AppError> {
+    diesel::update(
+        sessions::table
+            .filter(sessions::token_hash.eq(hash_token(token)))
+            .filter(sessions::revoked_at.is_null()),
+    )
+    .set(sessions::revoked_at.eq(Utc::now().naive_utc()))
+    .execute(conn)
+    .map_err(AppError::from)?;
 
-    Ok(session)
+    Ok(())
 }
 
-pub fn validate_session(session: &Session) -> bool {
-    let current_time = SystemTime::now()
-        .duration_since(UNIX_EPOCH)
-        .unwrap()
-        .as_secs();
-    session.expires_at > current_time
+/// Revokes every live session of `_client_id`, returning how many were
+/// revoked.
+pub fn revoke_all_sessions(conn: &mut DbConnection, _client_id: Uuid) -> Result<usize, AppError> {
+    diesel::update(
+        sessions::table
+            .filter(sessions::client_id.eq(_client_id))
+            .filter(sessions::revoked_at.is_null()),
+    )
+    .set(sessions::revoked_at.eq(Utc::now().naive_utc()))
+    .execute(conn)
+    .map_err(AppError::from)
 }
@@ -0,0 +1,528 @@
+use app::{AppError, DbConnection, RowError};
+use chrono::{NaiveDate, Utc};
+use diesel::{Connection, ExpressionMethods, QueryDsl, RunQueryDsl, SelectableHelper};
+use serde::{Deserialize, Serialize};
+use std::collections::{HashMap, HashSet};
+use uuid::Uuid;
+
+use super::{
+    location_service::draw_down_bins,
+    reconciliation_service::sync_product_totals,
+    stock_service::{on_hand_units, record_movement, units_in, PackedQuantity, StockLevel},
+};
+use crate::models::{
+    BatchDetail, BatchStatus, MovementType, NewShipment, NewShipmentLine, Product, QuantityUnit,
+    Shipment, ShipmentLine, ShipmentStatus,
+};
+use crate::schema::{batch_details, products, shipment_lines, shipments};
+
+const MAX_CONSIGNEE_LENGTH: usize = 255;
+const MAX_CARRIER_LENGTH: usize = 100;
+const MAX_REFERENCE_LENGTH: usize = 100;
+
+/// A shipment as entered on the dispatch form: who it goes to, how, and
+/// which batches it takes stock from.
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ShipmentInput {
+    pub consignee: String,
+    pub ship_date: NaiveDate,
+    pub carrier: Option<String>,
+    pub reference: String,
+    pub lines: Vec<ShipmentLineInput>,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ShipmentLineInput {
+    pub batch_id: Uuid,
+    pub quantity: i32,
+    pub unit: QuantityUnit,
+}
+
+#[derive(Serialize)]
+pub struct ShipmentWithLines {
+    pub shipment: Shipment,
+    pub lines: Vec<ShipmentLine>,
+}
+
+/// What a shipment holds of one batch. `units` is the whole quantity; the
+/// rest breaks it down the way it is packed.
+#[derive(Serialize)]
+pub struct PackedBatch {
+    pub batch_id: Uuid,
+    pub batch_no: String,
+    pub mfg_date: NaiveDate,
+    pub exp_date: NaiveDate,
+    pub boxes: i64,
+    pub loose_packs: i64,
+    pub loose_units: i64,
+    pub units: i64,
+}
+
+/// The batches of one product on a shipment, soonest expiry first, with
+/// their sums.
+#[derive(Serialize)]
+pub struct PackedProduct {
+    pub product_id: Uuid,
+    pub product_name: String,
+    pub batches: Vec<PackedBatch>,
+    pub boxes: i64,
+    pub loose_packs: i64,
+    pub loose_units: i64,
+    pub units: i64,
+}
+
+impl PackedProduct {
+    fn new(product: Product) -> Self {
+        PackedProduct {
+            product_id: product.id,
+            product_name: product.product_name,
+            batches: Vec::new(),
+            boxes: 0,
+            loose_packs: 0,
+            loose_units: 0,
+            units: 0,
+        }
+    }
+
+    fn add(&mut self, batch: PackedBatch) {
+        self.boxes += batch.boxes;
+        self.loose_packs += batch.loose_packs;
+        self.loose_units += batch.loose_units;
+        self.units += batch.units;
+        self.batches.push(batch);
+    }
+}
+
+#[derive(Serialize)]
+pub struct PackingList {
+    pub shipment: Shipment,
+    pub products: Vec<PackedProduct>,
+}
+
+fn check_length(field: &str, label: &str, value: &str, max: usize) -> Result<(), AppError> {
+    if value.chars().count() > max {
+        return Err(AppError::validation(
+            field,
+            format!("{} cannot be longer than {} characters", label, max),
+        ));
+    }
+    Ok(())
+}
+
+/// Checks the header and every line of `input`. Stock is only checked on
+/// dispatch, since it may change while the shipment is being prepared.
+fn validate_shipment(
+    conn: &mut DbConnection,
+    _client_id: Uuid,
+    input: &ShipmentInput,
+) -> Result<(), AppError> {
+    let consignee = input.consignee.trim();
+    if consignee.is_empty() {
+        return Err(AppError::validation("consignee", "Consignee is required"));
+    }
+    check_length("consignee", "Consignee", consignee, MAX_CONSIGNEE_LENGTH)?;
+    if let Some(carrier) = &input.carrier {
+        check_length("carrier", "Carrier", carrier.trim(), MAX_CARRIER_LENGTH)?;
+    }
+    let reference = input.reference.trim();
+    if reference.is_empty() {
+        return Err(AppError::validation("reference", "Reference is required"));
+    }
+    check_length("reference", "Reference", reference, MAX_REFERENCE_LENGTH)?;
+    if input.lines.is_empty() {
+        return Err(AppError::validation(
+            "lines",
+            "A shipment needs at least one line",
+        ));
+    }
+
+    let batch_ids: Vec<Uuid> = input.lines.iter().map(|line| line.batch_id).collect();
+    let own_batches: HashMap<Uuid, String> = batch_details::table
+        .inner_join(products::table)
+        .filter(products::client_id.eq(_client_id))
+        .filter(batch_details::id.eq_any(&batch_ids))
+        .select((batch_details::id, batch_details::batch_no))
+        .load::<(Uuid, String)>(conn)
+        .map_err(AppError::from)?
+        .into_iter()
+        .collect();
+
+    let mut rejected = Vec::new();
+    let mut seen = HashSet::new();
+    for (index, line) in input.lines.iter().enumerate() {
+        let batch_no = own_batches.get(&line.batch_id);
+        let mut reject = |reason: &str| {
+            rejected.push(RowError {
+                index,
+                batch_no: batch_no.cloned().unwrap_or_default(),
+                reason: reason.to_string(),
+            })
+        };
+        if batch_no.is_none() {
+            reject("Batch not found");
+        }
+        if line.quantity <= 0 {
+            reject("Quantity must be greater than 0");
+        }
+        if !seen.insert(line.batch_id) {
+            reject("Batch appears more than once on this shipment");
+        }
+    }
+
+    if rejected.is_empty() {
+        Ok(())
+    } else {
+        Err(AppError::BatchRejected { rows: rejected })
+    }
+}
+
+fn insert_lines(
+    conn: &mut DbConnection,
+    shipment_id: Uuid,
+    lines: &[ShipmentLineInput],
+) -> Result<Vec<ShipmentLine>, AppError> {
+    let new_lines: Vec<NewShipmentLine> = lines
+        .iter()
+        .zip(1..)
+        .map(|(line, line_no)| NewShipmentLine {
+            shipment_id,
+            line_no,
+            batch_id: line.batch_id,
+            quantity: line.quantity,
+            unit: line.unit,
+        })
+        .collect();
+
+    diesel::insert_into(shipment_lines::table)
+        .values(&new_lines)
+        .returning(ShipmentLine::as_returning())
+        .get_results(conn)
+        .map_err(AppError::from)
+}
+
+fn shipment_lines(
+    conn: &mut DbConnection,
+    shipment_id: Uuid,
+) -> Result<Vec<ShipmentLine>, AppError> {
+    shipment_lines::table
+        .filter(shipment_lines::shipment_id.eq(shipment_id))
+        .select(ShipmentLine::as_select())
+        .order(shipment_lines::line_no.asc())
+        .load(conn)
+        .map_err(AppError::from)
+}
+
+fn find_shipment(
+    conn: &mut DbConnection,
+    _client_id: Uuid,
+    shipment_id: Uuid,
+) -> Result<Shipment, AppError> {
+    shipments::table
+        .find(shipment_id)
+        .filter(shipments::client_id.eq(_client_id))
+        .select(Shipment::as_select())
+        .get_result(conn)
+        .map_err(AppError::from)
+}
+
+/// Loads a shipment of `_client_id` and locks it for the rest of the
+/// transaction.
+fn lock_shipment(
+    conn: &mut DbConnection,
+    _client_id: Uuid,
+    shipment_id: Uuid,
+) -> Result<Shipment, AppError> {
+    shipments::table
+        .find(shipment_id)
+        .filter(shipments::client_id.eq(_client_id))
+        .for_update()
+        .select(Shipment::as_select())
+        .get_result(conn)
+        .map_err(AppError::from)
+}
+
+fn require_draft(shipment: &Shipment, action: &str) -> Result<(), AppError> {
+    if shipment.status == ShipmentStatus::Draft {
+        return Ok(());
+    }
+    Err(AppError::Conflict(format!(
+        "Only draft shipments can be {}; this one is {}",
+        action,
+        shipment.status.as_str()
+    )))
+}
+
+fn trimmed_carrier(input: &ShipmentInput) -> Option<&str> {
+    input
+        .carrier
+        .as_deref()
+        .map(str::trim)
+        .filter(|carrier| !carrier.is_empty())
+}
+
+/// Saves `input` as a draft shipment. Nothing leaves stock until the
+/// shipment is dispatched.
+pub fn create_shipment(
+    conn: &mut DbConnection,
+    _client_id: Uuid,
+    input: &ShipmentInput,
+) -> Result<ShipmentWithLines, AppError> {
+    validate_shipment(conn, _client_id, input)?;
+
+    conn.transaction(|conn| {
+        let shipment = diesel::insert_into(shipments::table)
+            .values(&NewShipment {
+                client_id: _client_id,
+                consignee: input.consignee.trim(),
+                ship_date: input.ship_date,
+                carrier: trimmed_carrier(input),
+                reference: input.reference.trim(),
+            })
+            .returning(Shipment::as_returning())
+            .get_result(conn)
+            .map_err(AppError::from)?;
+        let lines = insert_lines(conn, shipment.id, &input.lines)?;
+
+        Ok(ShipmentWithLines { shipment, lines })
+    })
+}
+
+/// Replaces the header and lines of a draft shipment with `input`.
+pub fn update_shipment(
+    conn: &mut DbConnection,
+    _client_id: Uuid,
+    shipment_id: Uuid,
+    input: &ShipmentInput,
+) -> Result<ShipmentWithLines, AppError> {
+    validate_shipment(conn, _client_id, input)?;
+
+    conn.transaction(|conn| {
+        let current = lock_shipment(conn, _client_id, shipment_id)?;
+        require_draft(&current, "edited")?;
+
+        let shipment = diesel::update(shipments::table.find(shipment_id))
+            .set((
+                shipments::consignee.eq(input.consignee.trim()),
+                shipments::ship_date.eq(input.ship_date),
+                shipments::carrier.eq(trimmed_carrier(input)),
+                shipments::reference.eq(input.reference.trim()),
+                shipments::updated_at.eq(Utc::now().naive_utc()),
+            ))
+            .returning(Shipment::as_returning())
+            .get_result(conn)
+            .map_err(AppError::from)?;
+        diesel::delete(shipment_lines::table.filter(shipment_lines::shipment_id.eq(shipment_id)))
+            .execute(conn)
+            .map_err(AppError::from)?;
+        let lines = insert_lines(conn, shipment_id, &input.lines)?;
+
+        Ok(ShipmentWithLines { shipment, lines })
+    })
+}
+
+/// Takes a draft shipment out of stock, posting a dispatch movement per
+/// line that references the shipment. Only released batches can be
+/// shipped, and no batch can go below zero; if any line fails the
+/// shipment stays a draft and the lines are reported by index.
+pub fn dispatch_shipment(
+    conn: &mut DbConnection,
+    _client_id: Uuid,
+    shipment_id: Uuid,
+) -> Result<ShipmentWithLines, AppError> {
+    conn.transaction(|conn| {
+        let current = lock_shipment(conn, _client_id, shipment_id)?;
+        require_draft(&current, "dispatched")?;
+        let lines = shipment_lines(conn, shipment_id)?;
+        if lines.is_empty() {
+            return Err(AppError::validation(
+                "lines",
+                "A shipment needs at least one line",
+            ));
+        }
+
+        let mut rejected = Vec::new();
+        let mut touched_products = HashSet::new();
+        for line in &lines {
+            // Locking each batch serialises this with other postings
+            // against it, so the stock check below holds until commit.
+            let batch = batch_details::table
+                .find(line.batch_id)
+                .for_update()
+                .select(BatchDetail::as_select())
+                .get_result(conn)
+                .map_err(AppError::from)?;
+            let on_hand = on_hand_units(conn, &[batch.id])?
+                .get(&batch.id)
+                .copied()
+                .unwrap_or(0);
+
+            let units = i64::from(line.quantity) * units_in(&batch, line.unit);
+
+            let reason = if batch.status != BatchStatus::Released {
+                Some(format!("Batch is {}", batch.status.as_str()))
+            } else if units > on_hand {
+                Some(format!(
+                    "Only {} {} on hand",
+                    StockLevel::new(&batch, on_hand).in_unit(line.unit),
+                    line.unit.as_str()
+                ))
+            } else {
+                None
+            };
+            if let Some(reason) = reason {
+                rejected.push(RowError {
+                    index: usize::try_from(line.line_no - 1).unwrap_or_default(),
+                    batch_no: batch.batch_no,
+                    reason,
+                });
+                continue;
+            }
+
+            record_movement(
+                conn,
+                &batch,
+                MovementType::Dispatch,
+                -line.quantity,
+                line.unit,
+                Some(&current.reference),
+                _client_id,
+            )?;
+            draw_down_bins(conn, batch.id, on_hand - units)?;
+            touched_products.insert(batch.product_id);
+        }
+
+        if !rejected.is_empty() {
+            return Err(AppError::BatchRejected { rows: rejected });
+        }
+        for _product_id in touched_products {
+            sync_product_totals(conn, _product_id)?;
+        }
+
+        let now = Utc::now().naive_utc();
+        let shipment = diesel::update(shipments::table.find(shipment_id))
+            .set((
+                shipments::status.eq(ShipmentStatus::Dispatched),
+                shipments::dispatched_at.eq(now),
+                shipments::updated_at.eq(now),
+            ))
+            .returning(Shipment::as_returning())
+            .get_result(conn)
+            .map_err(AppError::from)?;
+
+        Ok(ShipmentWithLines { shipment, lines })
+    })
+}
+
+/// Cancels a draft shipment. Dispatched stock is already gone and is
+/// brought back with a stock adjustment instead.
+pub fn cancel_shipment(
+    conn: &mut DbConnection,
+    _client_id: Uuid,
+    shipment_id: Uuid,
+) -> Result<Shipment, AppError> {
+    conn.transaction(|conn| {
+        let current = lock_shipment(conn, _client_id, shipment_id)?;
+        require_draft(&current, "cancelled")?;
+
+        let now = Utc::now().naive_utc();
+        diesel::update(shipments::table.find(shipment_id))
+            .set((
+                shipments::status.eq(ShipmentStatus::Cancelled),
+                shipments::cancelled_at.eq(now),
+                shipments::updated_at.eq(now),
+            ))
+            .returning(Shipment::as_returning())
+            .get_result(conn)
+            .map_err(AppError::from)
+    })
+}
+
+pub fn get_shipment(
+    conn: &mut DbConnection,
+    _client_id: Uuid,
+    shipment_id: Uuid,
+) -> Result<ShipmentWithLines, AppError> {
+    let shipment = find_shipment(conn, _client_id, shipment_id)?;
+    let lines = shipment_lines(conn, shipment_id)?;
+
+    Ok(ShipmentWithLines { shipment, lines })
+}
+
+/// Shipments of `_client_id`, optionally only those in `status`, latest
+/// ship date first.
+pub fn list_shipments(
+    conn: &mut DbConnection,
+    _client_id: Uuid,
+    status: Option<ShipmentStatus>,
+) -> Result<Vec<Shipment>, AppError> {
+    let mut query = shipments::table
+        .filter(shipments::client_id.eq(_client_id))
+        .select(Shipment::as_select())
+        .into_boxed();
+    if let Some(status) = status {
+        query = query.filter(shipments::status.eq(status));
+    }
+
+    query
+        .order((shipments::ship_date.desc(), shipments::created_at.desc()))
+        .load(conn)
+        .map_err(AppError::from)
+}
+
+/// Summarises a shipment per product and batch, for printing with the
+/// goods. Drafts can be printed too, to check a shipment before dispatch.
+pub fn packing_list(
+    conn: &mut DbConnection,
+    _client_id: Uuid,
+    shipment_id: Uuid,
+) -> Result<PackingList, AppError> {
+    let shipment = find_shipment(conn, _client_id, shipment_id)?;
+    let lines = shipment_lines::table
+        .inner_join(batch_details::table.inner_join(products::table))
+        .filter(shipment_lines::shipment_id.eq(shipment_id))
+        .select((
+            ShipmentLine::as_select(),
+            BatchDetail::as_select(),
+            Product::as_select(),
+        ))
+        .order((
+            products::product_name.asc(),
+            products::id.asc(),
+            batch_details::exp_date.asc(),
+            batch_details::batch_no.asc(),
+        ))
+        .load::<(ShipmentLine, BatchDetail, Product)>(conn)
+        .map_err(AppError::from)?;
+
+    let mut packed_products: Vec<PackedProduct> = Vec::new();
+    for (line, batch, product) in lines {
+        let units = i64::from(line.quantity) * units_in(&batch, line.unit);
+        let packed = PackedQuantity::new(&batch, units);
+        let packed_batch = PackedBatch {
+            batch_id: batch.id,
+            batch_no: batch.batch_no,
+            mfg_date: batch.mfg_date,
+            exp_date: batch.exp_date,
+            boxes: packed.boxes,
+            loose_packs: packed.loose_packs,
+            loose_units: packed.loose_units,
+            units,
+        };
+
+        match packed_products.last_mut() {
+            Some(entry) if entry.product_id == product.id => entry.add(packed_batch),
+            _ => {
+                let mut entry = PackedProduct::new(product);
+                entry.add(packed_batch);
+                packed_products.push(entry);
+            }
+        }
+    }
+
+    Ok(PackingList {
+        shipment,
+        products: packed_products,
+    })
+}
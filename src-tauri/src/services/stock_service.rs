@@ -0,0 +1,276 @@
+use app::{AppError, DbConnection};
+use diesel::{
+    dsl::sql,
+    sql_types::{BigInt, Nullable},
+    Connection, ExpressionMethods, QueryDsl, RunQueryDsl, SelectableHelper,
+};
+use serde::Serialize;
+use std::collections::{HashMap, HashSet};
+use uuid::Uuid;
+
+use super::{
+    batch_details_service::get_batch_detail, location_service::draw_down_bins,
+    reconciliation_service::sync_product_totals,
+};
+use crate::models::{BatchDetail, MovementType, NewStockMovement, QuantityUnit, StockMovement};
+use crate::schema::{batch_details, stock_movements};
+
+const MAX_REFERENCE_LENGTH: usize = 100;
+
+/// Stock on hand in a batch, as the sum of its ledger. `packs` and `boxes`
+/// count full packs and full boxes only.
+#[derive(Serialize, Clone, Copy, Debug, Default, PartialEq)]
+pub struct StockLevel {
+    pub units: i64,
+    pub packs: i64,
+    pub boxes: i64,
+}
+
+impl StockLevel {
+    pub fn new(batch: &BatchDetail, units: i64) -> Self {
+        let packs = units / units_in(batch, QuantityUnit::Packs);
+        StockLevel {
+            units,
+            packs,
+            boxes: packs / i64::from(batch.packs_per_box.max(1)),
+        }
+    }
+
+    pub fn in_unit(&self, unit: QuantityUnit) -> i64 {
+        match unit {
+            QuantityUnit::Boxes => self.boxes,
+            QuantityUnit::Packs => self.packs,
+            QuantityUnit::Units => self.units,
+        }
+    }
+}
+
+/// A quantity of one batch as it is packed: full boxes, then packs outside
+/// a full box, then units from an opened pack.
+#[derive(Serialize, Clone, Copy, Debug, Default, PartialEq)]
+pub struct PackedQuantity {
+    pub boxes: i64,
+    pub loose_packs: i64,
+    pub loose_units: i64,
+}
+
+impl PackedQuantity {
+    pub fn new(batch: &BatchDetail, units: i64) -> Self {
+        let units_per_pack = units_in(batch, QuantityUnit::Packs);
+        let packs = units / units_per_pack;
+        let packs_per_box = i64::from(batch.packs_per_box.max(1));
+        PackedQuantity {
+            boxes: packs / packs_per_box,
+            loose_packs: packs % packs_per_box,
+            loose_units: units % units_per_pack,
+        }
+    }
+}
+
+/// How many units one `unit` of this batch holds.
+pub fn units_in(batch: &BatchDetail, unit: QuantityUnit) -> i64 {
+    let units_per_pack = i64::from(batch.units_per_pack.max(1));
+    match unit {
+        QuantityUnit::Units => 1,
+        QuantityUnit::Packs => units_per_pack,
+        QuantityUnit::Boxes => units_per_pack * i64::from(batch.packs_per_box.max(1)),
+    }
+}
+
+/// Units on hand per batch, summed from the ledger. Batches without
+/// movements are left out of the map.
+pub fn on_hand_units(
+    conn: &mut DbConnection,
+    batch_ids: &[Uuid],
+) -> Result<HashMap<Uuid, i64>, AppError> {
+    stock_movements::table
+        .filter(stock_movements::batch_id.eq_any(batch_ids))
+        .group_by(stock_movements::batch_id)
+        .select((
+            stock_movements::batch_id,
+            // SUM over BIGINT is NUMERIC in Postgres; stock fits in a BIGINT.
+            sql::<Nullable<BigInt>>("CAST(SUM(stock_movements.units_delta) AS BIGINT)"),
+        ))
+        .load::<(Uuid, Option<i64>)>(conn)
+        .map_err(AppError::from)
+        .map(|totals| {
+            totals
+                .into_iter()
+                .map(|(id, units)| (id, units.unwrap_or(0)))
+                .collect()
+        })
+}
+
+/// Pairs each batch with its stock level.
+pub fn stock_levels(
+    conn: &mut DbConnection,
+    batches: Vec<BatchDetail>,
+) -> Result<Vec<(BatchDetail, StockLevel)>, AppError> {
+    let ids: Vec<Uuid> = batches.iter().map(|batch| batch.id).collect();
+    let units = on_hand_units(conn, &ids)?;
+
+    Ok(batches
+        .into_iter()
+        .map(|batch| {
+            let level = StockLevel::new(&batch, units.get(&batch.id).copied().unwrap_or(0));
+            (batch, level)
+        })
+        .collect())
+}
+
+/// Appends a movement to `batch`'s ledger without any checks on the
+/// caller's side. `quantity` is signed the way the stock moves.
+pub fn record_movement(
+    conn: &mut DbConnection,
+    batch: &BatchDetail,
+    movement_type: MovementType,
+    quantity: i32,
+    unit: QuantityUnit,
+    reference: Option<&str>,
+    created_by: Uuid,
+) -> Result<StockMovement, AppError> {
+    diesel::insert_into(stock_movements::table)
+        .values(&NewStockMovement {
+            batch_id: batch.id,
+            movement_type,
+            quantity,
+            unit,
+            units_delta: i64::from(quantity) * units_in(batch, unit),
+            reference,
+            created_by,
+        })
+        .returning(StockMovement::as_returning())
+        .get_result(conn)
+        .map_err(AppError::from)
+}
+
+/// Removes the opening receipt of each batch about to be deleted, so a batch
+/// entered by mistake can go. Returns `false` and removes nothing when any
+/// of them has a movement past that receipt; their ledger has to stay.
+pub fn remove_opening_receipts(
+    conn: &mut DbConnection,
+    batch_ids: &[Uuid],
+) -> Result<bool, AppError> {
+    let movements = stock_movements::table
+        .filter(stock_movements::batch_id.eq_any(batch_ids))
+        .select((stock_movements::batch_id, stock_movements::movement_type))
+        .load::<(Uuid, MovementType)>(conn)
+        .map_err(AppError::from)?;
+
+    let mut seen = HashSet::new();
+    let opening_only = movements.iter().all(|(batch_id, movement_type)| {
+        *movement_type == MovementType::Receipt && seen.insert(*batch_id)
+    });
+    if !opening_only {
+        return Ok(false);
+    }
+
+    diesel::delete(stock_movements::table.filter(stock_movements::batch_id.eq_any(batch_ids)))
+        .execute(conn)
+        .map_err(AppError::from)?;
+    Ok(true)
+}
+
+/// Posts a movement against a batch of `_client_id`. Receipts, dispatches
+/// and write-offs take a positive `quantity` and move stock in or out as
+/// their type says; adjustments are signed. Stock can never go below zero,
+/// and stock going out is taken out of the batch's bins too.
+pub fn post_movement(
+    conn: &mut DbConnection,
+    _client_id: Uuid,
+    batch_detail_id: Uuid,
+    movement_type: MovementType,
+    quantity: i32,
+    unit: QuantityUnit,
+    reference: Option<&str>,
+) -> Result<(StockMovement, StockLevel), AppError> {
+    let signed_quantity = match movement_type {
+        MovementType::Adjustment if quantity != 0 => quantity,
+        MovementType::Adjustment => {
+            return Err(AppError::validation(
+                "quantity",
+                "An adjustment cannot be zero",
+            ));
+        }
+        _ if quantity <= 0 => {
+            return Err(AppError::validation(
+                "quantity",
+                "Quantity must be greater than 0",
+            ));
+        }
+        MovementType::Receipt => quantity,
+        MovementType::Dispatch | MovementType::WriteOff => -quantity,
+    };
+    let reference = reference.map(str::trim).filter(|r| !r.is_empty());
+    if reference.is_some_and(|r| r.chars().count() > MAX_REFERENCE_LENGTH) {
+        return Err(AppError::validation(
+            "reference",
+            format!(
+                "Reference cannot be longer than {} characters",
+                MAX_REFERENCE_LENGTH
+            ),
+        ));
+    }
+
+    conn.transaction(|conn| {
+        get_batch_detail(conn, _client_id, batch_detail_id)?;
+        // Locking the batch serialises postings, so two dispatches can't
+        // both pass the stock check below.
+        let batch = batch_details::table
+            .find(batch_detail_id)
+            .for_update()
+            .select(BatchDetail::as_select())
+            .get_result(conn)
+            .map_err(AppError::from)?;
+
+        let on_hand = on_hand_units(conn, &[batch.id])?
+            .get(&batch.id)
+            .copied()
+            .unwrap_or(0);
+        let after = on_hand + i64::from(signed_quantity) * units_in(&batch, unit);
+        if after < 0 {
+            return Err(AppError::validation(
+                "quantity",
+                format!(
+                    "Only {} {} on hand",
+                    StockLevel::new(&batch, on_hand).in_unit(unit),
+                    unit.as_str()
+                ),
+            ));
+        }
+
+        let movement = record_movement(
+            conn,
+            &batch,
+            movement_type,
+            signed_quantity,
+            unit,
+            reference,
+            _client_id,
+        )?;
+        if after < on_hand {
+            draw_down_bins(conn, batch.id, after)?;
+        }
+        sync_product_totals(conn, batch.product_id)?;
+        Ok((movement, StockLevel::new(&batch, after)))
+    })
+}
+
+/// The ledger of one batch, most recent first, with its current level.
+pub fn batch_movements(
+    conn: &mut DbConnection,
+    _client_id: Uuid,
+    batch_detail_id: Uuid,
+) -> Result<(Vec<StockMovement>, StockLevel), AppError> {
+    let batch = get_batch_detail(conn, _client_id, batch_detail_id)?;
+
+    let movements: Vec<StockMovement> = stock_movements::table
+        .filter(stock_movements::batch_id.eq(batch_detail_id))
+        .select(StockMovement::as_select())
+        .order(stock_movements::created_at.desc())
+        .load(conn)
+        .map_err(AppError::from)?;
+    let units = movements.iter().map(|movement| movement.units_delta).sum();
+
+    Ok((movements, StockLevel::new(&batch, units)))
+}
@@ -8,8 +8,20 @@ import {
   CardHeader,
   CardTitle,
 } from "@/components/ui/card";
+import { Input } from "@/components/ui/input";
+import {
+  Select,
+  SelectContent,
+  SelectItem,
+  SelectTrigger,
+  SelectValue,
+} from "@/components/ui/select";
 import { useToast } from "@/components/ui/use-toast";
-import clientOperationsMachine from "@/lib/machines/clientOperationsMachine";
+import { errorMessage } from "@/lib/utils";
+import clientOperationsMachine, {
+  LocalClient,
+  listClients,
+} from "@/lib/machines/clientOperationsMachine";
 import { useMachine } from "@xstate/react";
 import {
   KeyRound,
@@ -18,7 +30,7 @@ import {
   ShieldCheck,
 } from "lucide-react";
 import { useRouter } from "next/navigation";
-import { useEffect } from "react";
+import { useEffect, useState } from "react";
 import { MachineSnapshot, MetaObject, NonReducibleUnknown } from "xstate";
 
 export default function AuthClientOperation(
@@ -40,12 +52,25 @@ export default function AuthClientOperation(
 
   const router = useRouter();
 
+  const [clients, setClients] = useState<LocalClient[]>([]);
+  const [clientId, setClientId] = useState<string>("");
+  const [clientName, setClientName] = useState<string>("");
+
+  useEffect(() => {
+    listClients()
+      .then((localClients) => {
+        setClients(localClients);
+        if (localClients.length === 1) setClientId(localClients[0].id);
+      })
+      .catch(() => setClients([]));
+  }, [clientState.context.clientData]);
+
   const handleCreateClient = () => {
-    sendClient({ type: "CREATE" });
+    sendClient({ type: "CREATE", name: clientName });
   };
 
   const handleAuthenticateClient = () => {
-    sendClient({ type: "AUTHENTICATE" });
+    sendClient({ type: "AUTHENTICATE", clientId });
   };
 
   useEffect(() => {
@@ -53,7 +78,7 @@ export default function AuthClientOperation(
       toast({
         className: "bg-red-700",
         title: "Authentication Error",
-        description: clientState.context.error as unknown as string,
+        description: errorMessage(clientState.context.error),
       });
     }
 
@@ -108,6 +133,33 @@ export default function AuthClientOperation(
                 : null}
           </small>
         </div>
+        <div className="flex justify-center mt-4">
+          {state.matches("create_client") ? (
+            <Input
+              className="w-[400px]"
+              placeholder="Client name"
+              value={clientName}
+              onChange={(e) => setClientName(e.target.value)}
+            />
+          ) : state.matches("authenticate_client") ? (
+            <Select value={clientId} onValueChange={setClientId}>
+              <SelectTrigger className="w-[400px]">
+                <SelectValue placeholder="Select a client" />
+              </SelectTrigger>
+              <SelectContent>
+                {clients.map((client) => (
+                  <SelectItem
+                    key={client.id}
+                    value={client.id}
+                    disabled={!client.registered}
+                  >
+                    {client.name ?? client.id}
+                  </SelectItem>
+                ))}
+              </SelectContent>
+            </Select>
+          ) : null}
+        </div>
         <div className="flex justify-center mb-10">
           <Button
             onClick={() => {
@@ -120,7 +172,12 @@ export default function AuthClientOperation(
             variant="outline"
             size="lg"
             className="px-20 py-8"
-            disabled={!clientState.matches("idle")}
+            disabled={
+              clientState.matches("creating") ||
+              clientState.matches("authenticating") ||
+              (state.matches("create_client") && !clientName.trim()) ||
+              (state.matches("authenticate_client") && !clientId)
+            }
           >
             {state.matches("create_client") ? (
               <KeyRound className="mr-2" />
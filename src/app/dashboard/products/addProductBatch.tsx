@@ -24,7 +24,7 @@ import {
   PopoverTrigger,
 } from "@/components/ui/popover";
 import { toast } from "@/components/ui/use-toast";
-import { cn } from "@/lib/utils";
+import { cn, errorMessage } from "@/lib/utils";
 import { extendedBatchSchema, validateAndCalculateBatchConfig } from "@/util/validateAndCalculateBatchConfig";
 import { zodResolver } from "@hookform/resolvers/zod";
 import { format } from "date-fns";
@@ -149,7 +149,7 @@ export default function AddProductBatch() {
       toast({
         className: "bg-red-700",
         title: "Batch Creation Error",
-        description: productSnapshot.context.error as unknown as string,
+        description: errorMessage(productSnapshot.context.error),
       });
     }
   }, [productSnapshot.matches("success"), productSnapshot.matches("failure")]);
@@ -17,6 +17,7 @@ import { zodResolver } from "@hookform/resolvers/zod";
 import { Label } from "@/components/ui/label";
 import { Input } from "@/components/ui/input";
 import { useToast } from "@/components/ui/use-toast";
+import { errorMessage } from "@/lib/utils";
 import { ProductMachineContext } from "@/components/product-machine-provider";
 
 export type AddProductFormValues = {
@@ -135,7 +136,7 @@ export default function AddProductDialog() {
       toast({
         className: "bg-red-700",
         title: "Product Creation Error",
-        description: productSnapshot.context.error as unknown as string,
+        description: errorMessage(productSnapshot.context.error),
       });
     }
   }, [productSnapshot.matches("success"), productSnapshot.matches("failure")]);
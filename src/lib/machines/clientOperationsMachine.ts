@@ -3,9 +3,26 @@ import { invoke } from "@tauri-apps/api/core";
 import { StorageManager } from "../utils";
 
 type CreateClientResponse = {
-  client: { id: string; private_key_path: string };
+  client: {
+    id: string;
+    private_key_path: string;
+    public_key: string | null;
+    name: string;
+    created_at: string;
+  };
 };
 
+export type LocalClient = {
+  id: string;
+  name: string | null;
+  registered: boolean;
+};
+
+export async function listClients() {
+  const response = await invoke<{ clients: LocalClient[] }>("list_clients");
+  return response.clients;
+}
+
 export type AuthenticateClientResponse = {
   token: string;
   client_id: string;
@@ -14,13 +31,18 @@ export type AuthenticateClientResponse = {
 
 const storageManager = new StorageManager("store.bin");
 
-const createClientLogic = fromPromise(async () => {
-  const response = await invoke<CreateClientResponse>("create_client");
-  return response;
-});
+const createClientLogic = fromPromise<CreateClientResponse, { name: string }>(
+  async ({ input }) => {
+    const response = await invoke<CreateClientResponse>("create_client", input);
+    return response;
+  },
+);
 
-const authenticateClientLogic = fromPromise(async () => {
-  const response = await invoke<AuthenticateClientResponse>("sign_in");
+const authenticateClientLogic = fromPromise<
+  AuthenticateClientResponse,
+  { clientId: string }
+>(async ({ input }) => {
+  const response = await invoke<AuthenticateClientResponse>("sign_in", input);
   return response;
 });
 
@@ -31,6 +53,9 @@ const clientOperationsMachine = setup({
       authData: AuthenticateClientResponse | undefined;
       error: unknown;
     },
+    events: {} as
+      | { type: "CREATE"; name: string }
+      | { type: "AUTHENTICATE"; clientId: string },
   },
   actors: { createClientLogic, authenticateClientLogic },
 }).createMachine({
@@ -52,6 +77,9 @@ const clientOperationsMachine = setup({
       invoke: {
         id: "createClient",
         src: "createClientLogic",
+        input: ({ event }) => ({
+          name: event.type === "CREATE" ? event.name : "",
+        }),
         onDone: {
           target: "success",
           actions: assign({
@@ -68,6 +96,9 @@ const clientOperationsMachine = setup({
       invoke: {
         id: "authenticateClient",
         src: "authenticateClientLogic",
+        input: ({ event }) => ({
+          clientId: event.type === "AUTHENTICATE" ? event.clientId : "",
+        }),
         onDone: {
           target: "success",
           actions: [
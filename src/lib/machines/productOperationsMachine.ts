@@ -1,6 +1,6 @@
 import { AddProductFormValues } from "@/app/dashboard/products/addProductDialog";
 import { assign, fromPromise, setup } from "xstate";
-import { getClientId } from "../utils";
+import { getSessionToken } from "../utils";
 import { invoke } from "@tauri-apps/api/core";
 import { checkSession } from "./validateSessionMachine";
 import { AddProductBatchFormValues } from "@/app/dashboard/products/addProductBatch";
@@ -16,6 +16,7 @@ export type Product = {
   total_shipper_boxes: number;
   created_at: string;
   updated_at: string;
+  derived_totals: boolean;
 };
 
 type CreateProductResponse = {
@@ -29,6 +30,13 @@ export type FetchProductResponse = {
   }>;
 };
 
+export type BatchStatus =
+  | "quarantine"
+  | "released"
+  | "on_hold"
+  | "recalled"
+  | "expired";
+
 export type BatchDetails = {
   id: string;
   product_id: string;
@@ -42,6 +50,7 @@ export type BatchDetails = {
   total_packs: string;
   created_at: string;
   updated_at: string;
+  status: BatchStatus;
 };
 
 
@@ -66,9 +75,9 @@ const createProductLogic = fromPromise<
   AddProductFormValues
 >(async ({ input }): Promise<CreateProductResponse> => {
   checkSession();
-  const clientId = await getClientId();
+  const token = await getSessionToken();
   const response = await invoke<CreateProductResponse>("create_product", {
-    clientId: clientId,
+    token,
     ...input,
   });
 
@@ -77,9 +86,9 @@ const createProductLogic = fromPromise<
 
 const fetchClientProductsLogic = fromPromise(async () => {
   checkSession();
-  const clientId = await getClientId();
+  const token = await getSessionToken();
   const response = await invoke("get_all_products_for_client", {
-    clientId: clientId,
+    token,
   });
 
   return response;
@@ -90,9 +99,9 @@ const updateProductLogic = fromPromise<
   AddProductFormValues
 >(async ({ input }) => {
   checkSession();
-  const clientId = await getClientId();
+  const token = await getSessionToken();
   const response = await invoke<CreateProductResponse>("update_product", {
-    clientId: clientId,
+    token,
     productId: input.productId,
     ...input,
   });
@@ -123,7 +132,9 @@ const createProductBatchLogic = fromPromise<
     }));
 
 
+    const token = await getSessionToken();
     const response = await invoke<CreateBatchDetailsResponse>("create_batch_details", {
+      token,
       productId: input.productId,
       batch: batchPayload,
     });
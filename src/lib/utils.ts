@@ -40,6 +40,33 @@ export async function getClientId() {
   return session?.client_id;
 }
 
+export async function getSessionToken() {
+  const store = new StorageManager("store.bin");
+
+  const session =
+    await store.getItem<AuthenticateClientResponse>("client_session");
+
+  return session?.token;
+}
+
+export type AppError = {
+  kind: string;
+  code: string;
+  message: string;
+  field: string | null;
+  retryable: boolean;
+  recovery?: string;
+};
+
+export function errorMessage(error: unknown): string {
+  if (typeof error === "string") return error;
+  if (error && typeof error === "object" && "message" in error) {
+    const { message, recovery } = error as AppError;
+    return recovery ? `${message}. ${recovery}` : String(message);
+  }
+  return "Something went wrong";
+}
+
 export function extractValues(str: string, delimiter: string): string[] {
   return str.split(delimiter);
 }
@@ -18,10 +18,9 @@ export function validateAndCalculateBatchConfig(input: {
   unitsPerPack?: number;
 }): BatchCalculationResult {
   const boxes = input.boxes || 0;
-  const unitsPerPack = input.unitsPerPack || 0;
 
   const configParts = input.packagesConfiguration
-    .split('x')
+    .split(/[xX]/)
     .map(part => part.trim());
 
   const errors: string[] = [];
@@ -42,7 +41,7 @@ export function validateAndCalculateBatchConfig(input: {
   }
 
   const [layers, rowsPerLayer, packsPerRow, configUnitsPerPack] = configParts.map(part => {
-    const parsed = parseInt(part, 10);
+    const parsed = /^\d+$/.test(part) ? parseInt(part, 10) : NaN;
     if (isNaN(parsed) || parsed <= 0) {
       errors.push(`Invalid number in configuration: ${part}`);
       return 0;
@@ -60,11 +59,11 @@ export function validateAndCalculateBatchConfig(input: {
 
   const packsPerBox = layers * rowsPerLayer * packsPerRow;
 
-  if (configUnitsPerPack !== unitsPerPack && unitsPerPack !== 1) {
-    errors.push(`Units per pack mismatch: Configuration shows ${configUnitsPerPack}, but form has ${unitsPerPack}`);
+  if (input.unitsPerPack !== undefined && configUnitsPerPack !== input.unitsPerPack) {
+    errors.push(`Units per pack mismatch: Configuration shows ${configUnitsPerPack}, but form has ${input.unitsPerPack}`);
   }
 
-  const unitsPerBox = packsPerBox * unitsPerPack;
+  const unitsPerBox = packsPerBox * configUnitsPerPack;
 
   const totalPacks = packsPerBox * boxes;
   const totalUnits = unitsPerBox * boxes;
@@ -77,7 +76,7 @@ export function validateAndCalculateBatchConfig(input: {
   };
 
   return {
-    isValid: true,
+    isValid: errors.length === 0,
     calculatedValues,
     errors
   };